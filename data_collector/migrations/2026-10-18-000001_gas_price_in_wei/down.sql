@@ -0,0 +1,5 @@
+ALTER TABLE transactions
+    ALTER COLUMN effective_gas_price TYPE FLOAT8 USING effective_gas_price / 1e18;
+
+ALTER TABLE trades
+    ALTER COLUMN effective_gas_price TYPE FLOAT8 USING effective_gas_price / 1e18;
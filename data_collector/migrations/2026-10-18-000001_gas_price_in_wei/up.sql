@@ -0,0 +1,5 @@
+ALTER TABLE transactions
+    ALTER COLUMN effective_gas_price TYPE INT8 USING round(effective_gas_price * 1e18)::INT8;
+
+ALTER TABLE trades
+    ALTER COLUMN effective_gas_price TYPE INT8 USING round(effective_gas_price * 1e18)::INT8;
@@ -0,0 +1,19 @@
+ALTER TABLE sync_ticks
+    ALTER COLUMN reserve0 TYPE FLOAT8,
+    ALTER COLUMN reserve1 TYPE FLOAT8;
+
+ALTER TABLE swap_ticks
+    ALTER COLUMN amount0_in TYPE FLOAT8,
+    ALTER COLUMN amount0_out TYPE FLOAT8,
+    ALTER COLUMN amount1_in TYPE FLOAT8,
+    ALTER COLUMN amount1_out TYPE FLOAT8,
+    ALTER COLUMN base_amount TYPE FLOAT8,
+    ALTER COLUMN quote_amount TYPE FLOAT8;
+
+ALTER TABLE liquidity_ticks
+    ALTER COLUMN amount0 TYPE FLOAT8,
+    ALTER COLUMN amount1 TYPE FLOAT8;
+
+ALTER TABLE trades
+    ALTER COLUMN amount_in TYPE FLOAT8,
+    ALTER COLUMN amount_out TYPE FLOAT8;
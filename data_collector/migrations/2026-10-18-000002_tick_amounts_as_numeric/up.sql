@@ -0,0 +1,20 @@
+-- Amounts are stored as exact decimals, `amount / 10^decimals`, rows written before keep their f64 value.
+ALTER TABLE sync_ticks
+    ALTER COLUMN reserve0 TYPE NUMERIC,
+    ALTER COLUMN reserve1 TYPE NUMERIC;
+
+ALTER TABLE swap_ticks
+    ALTER COLUMN amount0_in TYPE NUMERIC,
+    ALTER COLUMN amount0_out TYPE NUMERIC,
+    ALTER COLUMN amount1_in TYPE NUMERIC,
+    ALTER COLUMN amount1_out TYPE NUMERIC,
+    ALTER COLUMN base_amount TYPE NUMERIC,
+    ALTER COLUMN quote_amount TYPE NUMERIC;
+
+ALTER TABLE liquidity_ticks
+    ALTER COLUMN amount0 TYPE NUMERIC,
+    ALTER COLUMN amount1 TYPE NUMERIC;
+
+ALTER TABLE trades
+    ALTER COLUMN amount_in TYPE NUMERIC,
+    ALTER COLUMN amount_out TYPE NUMERIC;
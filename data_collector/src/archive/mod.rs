@@ -0,0 +1,480 @@
+use crate::db::models::LogRecord;
+use arrow::array::{
+    Array, ArrayRef, AsArray, BinaryArray, Int32Array, Int64Array, RecordBatch, StringArray,
+};
+use arrow::datatypes::{DataType, Field, Int32Type, Int64Type, Schema};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+use web3::types::Log;
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+pub fn event_type_name(log_type: i32) -> &'static str {
+    match log_type {
+        0 => "pair_created",
+        1 => "sync",
+        2 => "swap",
+        3 => "mint",
+        4 => "burn",
+        5 => "transfer",
+        _ => "unknown",
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ManifestEntry {
+    pub kind: String,
+    pub event_type: String,
+    pub from_block: u64,
+    pub to_block: u64,
+    pub rows: usize,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn load(dir: &str) -> Self {
+        let path = Path::new(dir).join(MANIFEST_FILE);
+        if !path.exists() {
+            return Manifest::default();
+        }
+
+        let content = fs::read_to_string(&path).expect("Can't read archive manifest");
+        serde_json::from_str(&content).expect("Invalid archive manifest")
+    }
+
+    fn save(&self, dir: &str) {
+        let path = Path::new(dir).join(MANIFEST_FILE);
+        let tmp_path = Path::new(dir).join(format!("{}.tmp", MANIFEST_FILE));
+
+        let serialized = serde_json::to_string_pretty(self).expect("Failed to serialize manifest");
+        fs::write(&tmp_path, serialized).expect("Can't write archive manifest");
+        fs::rename(&tmp_path, &path).expect("Can't replace archive manifest");
+    }
+
+    fn add(&mut self, entry: ManifestEntry) {
+        self.files.retain(|x| x.path != entry.path);
+        self.files.push(entry);
+        self.files.sort_by(|a, b| {
+            (a.from_block, &a.kind, &a.event_type).cmp(&(b.from_block, &b.kind, &b.event_type))
+        });
+    }
+}
+
+pub struct ArchiveWriter {
+    dir: String,
+    manifest: Manifest,
+}
+
+impl ArchiveWriter {
+    pub fn new(dir: &str) -> Self {
+        fs::create_dir_all(dir).expect("Can't create archive directory");
+
+        ArchiveWriter {
+            dir: dir.to_owned(),
+            manifest: Manifest::load(dir),
+        }
+    }
+
+    pub fn write_raw(
+        &mut self,
+        from_block: u64,
+        to_block: u64,
+        logs: &[Log],
+        event_type: impl Fn(&Log) -> &'static str,
+    ) {
+        let mut partitions: BTreeMap<&'static str, Vec<&Log>> = BTreeMap::new();
+        for log in logs {
+            partitions.entry(event_type(log)).or_default().push(log);
+        }
+
+        for (event_type, logs) in partitions {
+            let batch = raw_logs_batch(&logs);
+            self.write_partition("raw", event_type, from_block, to_block, batch);
+        }
+
+        self.manifest.save(&self.dir);
+    }
+
+    pub fn write_decoded(&mut self, from_block: u64, to_block: u64, records: &[LogRecord]) {
+        let mut partitions: BTreeMap<&'static str, Vec<&LogRecord>> = BTreeMap::new();
+        for record in records {
+            partitions
+                .entry(event_type_name(record.log_type))
+                .or_default()
+                .push(record);
+        }
+
+        for (event_type, records) in partitions {
+            let batch = decoded_logs_batch(&records);
+            self.write_partition("decoded", event_type, from_block, to_block, batch);
+        }
+
+        self.manifest.save(&self.dir);
+    }
+
+    fn write_partition(
+        &mut self,
+        kind: &str,
+        event_type: &str,
+        from_block: u64,
+        to_block: u64,
+        batch: RecordBatch,
+    ) {
+        let relative_path = format!(
+            "{}/{}/{:010}-{:010}.parquet",
+            kind, event_type, from_block, to_block
+        );
+        let path = Path::new(&self.dir).join(&relative_path);
+        fs::create_dir_all(path.parent().unwrap()).expect("Can't create partition directory");
+
+        write_parquet(&path, batch.clone());
+
+        self.manifest.add(ManifestEntry {
+            kind: kind.to_owned(),
+            event_type: event_type.to_owned(),
+            from_block,
+            to_block,
+            rows: batch.num_rows(),
+            path: relative_path,
+        });
+    }
+}
+
+// Schema metadata is also stored as plain key-value metadata, for readers that ignore the Arrow schema.
+pub fn write_parquet(path: &Path, batch: RecordBatch) {
+    let file = File::create(path).expect("Can't create parquet file");
+    let metadata: Vec<KeyValue> = batch
+        .schema()
+        .metadata()
+        .iter()
+        .map(|(key, value)| KeyValue::new(key.clone(), value.clone()))
+        .collect();
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_key_value_metadata((!metadata.is_empty()).then_some(metadata))
+        .build();
+
+    let mut writer = ArrowWriter::try_new(file, batch.schema(), Some(props))
+        .expect("Can't create parquet writer");
+    writer.write(&batch).expect("Can't write parquet batch");
+    writer.close().expect("Can't close parquet file");
+}
+
+fn raw_logs_batch(logs: &[&Log]) -> RecordBatch {
+    let schema = Schema::new(vec![
+        Field::new("block_number", DataType::Int64, false),
+        Field::new("transaction_hash", DataType::Utf8, true),
+        Field::new("transaction_index", DataType::Int64, true),
+        Field::new("log_index", DataType::Int64, true),
+        Field::new("address", DataType::Utf8, false),
+        Field::new("topic0", DataType::Utf8, true),
+        Field::new("topic1", DataType::Utf8, true),
+        Field::new("topic2", DataType::Utf8, true),
+        Field::new("topic3", DataType::Utf8, true),
+        Field::new("data", DataType::Binary, false),
+    ]);
+
+    let topic = |i: usize| -> ArrayRef {
+        Arc::new(StringArray::from_iter(
+            logs.iter()
+                .map(|x| x.topics.get(i).map(|t| format!("{:?}", t))),
+        ))
+    };
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(
+            logs.iter()
+                .map(|x| x.block_number.unwrap_or_default().as_u64() as i64),
+        )),
+        Arc::new(StringArray::from_iter(
+            logs.iter()
+                .map(|x| x.transaction_hash.map(|t| format!("{:?}", t))),
+        )),
+        Arc::new(Int64Array::from_iter(
+            logs.iter()
+                .map(|x| x.transaction_index.map(|t| t.as_u64() as i64)),
+        )),
+        Arc::new(Int64Array::from_iter(
+            logs.iter().map(|x| x.log_index.map(|t| t.as_u64() as i64)),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            logs.iter().map(|x| format!("{:?}", x.address)),
+        )),
+        topic(0),
+        topic(1),
+        topic(2),
+        topic(3),
+        Arc::new(BinaryArray::from_iter_values(
+            logs.iter().map(|x| x.data.0.as_slice()),
+        )),
+    ];
+
+    RecordBatch::try_new(Arc::new(schema), columns).expect("Invalid raw logs batch")
+}
+
+fn decoded_logs_batch(records: &[&LogRecord]) -> RecordBatch {
+    let schema = Schema::new(vec![
+        Field::new("log_type", DataType::Int32, false),
+        Field::new("block_number", DataType::Int64, false),
+        Field::new("address", DataType::Utf8, false),
+        Field::new("data1", DataType::Utf8, true),
+        Field::new("data2", DataType::Utf8, true),
+        Field::new("data3", DataType::Utf8, true),
+        Field::new("data4", DataType::Utf8, true),
+        Field::new("data5", DataType::Utf8, true),
+        Field::new("transaction_hash", DataType::Utf8, true),
+        Field::new("log_index", DataType::Int64, true),
+        Field::new("transaction_index", DataType::Int64, true),
+    ]);
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int32Array::from_iter_values(
+            records.iter().map(|x| x.log_type),
+        )),
+        Arc::new(Int64Array::from_iter_values(
+            records.iter().map(|x| x.block_number),
+        )),
+        Arc::new(StringArray::from_iter_values(
+            records.iter().map(|x| x.address.clone()),
+        )),
+        Arc::new(StringArray::from_iter(
+            records.iter().map(|x| x.data1.clone()),
+        )),
+        Arc::new(StringArray::from_iter(
+            records.iter().map(|x| x.data2.clone()),
+        )),
+        Arc::new(StringArray::from_iter(
+            records.iter().map(|x| x.data3.clone()),
+        )),
+        Arc::new(StringArray::from_iter(
+            records.iter().map(|x| x.data4.clone()),
+        )),
+        Arc::new(StringArray::from_iter(
+            records.iter().map(|x| x.data5.clone()),
+        )),
+        Arc::new(StringArray::from_iter(
+            records.iter().map(|x| x.transaction_hash.clone()),
+        )),
+        Arc::new(Int64Array::from_iter(records.iter().map(|x| x.log_index))),
+        Arc::new(Int64Array::from_iter(
+            records.iter().map(|x| x.transaction_index),
+        )),
+    ];
+
+    RecordBatch::try_new(Arc::new(schema), columns).expect("Invalid decoded logs batch")
+}
+
+fn read_decoded_file(path: &Path) -> Vec<LogRecord> {
+    let file = File::open(path).expect("Can't open parquet file");
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file)
+        .expect("Invalid parquet file")
+        .build()
+        .expect("Can't read parquet file");
+
+    let mut records = Vec::new();
+    for batch in reader {
+        let batch = batch.expect("Can't read parquet batch");
+
+        let string = |name: &str| {
+            batch
+                .column_by_name(name)
+                .unwrap()
+                .as_string::<i32>()
+                .clone()
+        };
+        let log_type = batch
+            .column_by_name("log_type")
+            .unwrap()
+            .as_primitive::<Int32Type>()
+            .clone();
+        let block_number = batch
+            .column_by_name("block_number")
+            .unwrap()
+            .as_primitive::<Int64Type>()
+            .clone();
+        let log_index = batch
+            .column_by_name("log_index")
+            .unwrap()
+            .as_primitive::<Int64Type>()
+            .clone();
+        // Archives written before transaction indices were collected don't have the column.
+        let transaction_index = batch
+            .column_by_name("transaction_index")
+            .map(|x| x.as_primitive::<Int64Type>().clone());
+        let address = string("address");
+        let data = [
+            string("data1"),
+            string("data2"),
+            string("data3"),
+            string("data4"),
+            string("data5"),
+        ];
+        let transaction_hash = string("transaction_hash");
+
+        let optional = |array: &StringArray, i: usize| {
+            if array.is_null(i) {
+                None
+            } else {
+                Some(array.value(i).to_owned())
+            }
+        };
+
+        for i in 0..batch.num_rows() {
+            records.push(LogRecord {
+                id: 0,
+                log_type: log_type.value(i),
+                block_number: block_number.value(i),
+                address: address.value(i).to_owned(),
+                data1: optional(&data[0], i),
+                data2: optional(&data[1], i),
+                data3: optional(&data[2], i),
+                data4: optional(&data[3], i),
+                data5: optional(&data[4], i),
+                transaction_hash: optional(&transaction_hash, i),
+                log_index: if log_index.is_null(i) {
+                    None
+                } else {
+                    Some(log_index.value(i))
+                },
+                transaction_index: transaction_index
+                    .as_ref()
+                    .filter(|x| !x.is_null(i))
+                    .map(|x| x.value(i)),
+            });
+        }
+    }
+
+    records
+}
+
+// Yields decoded logs one archived block range at a time, all event types merged in block order.
+// Overlapping ranges (e.g. a re-collection with other boundaries) are read together and deduplicated.
+pub struct ArchiveReader {
+    dir: String,
+    ranges: Vec<((u64, u64), Vec<String>)>,
+    position: usize,
+}
+
+impl ArchiveReader {
+    pub fn with_range(dir: &str, from_block: Option<u64>, to_block: Option<u64>) -> Self {
+        let manifest = Manifest::load(dir);
+
+        let mut ranges: BTreeMap<(u64, u64), Vec<String>> = BTreeMap::new();
+        for entry in manifest.files {
+            if entry.kind != "decoded"
+                || from_block.is_some_and(|x| entry.to_block < x)
+                || to_block.is_some_and(|x| entry.from_block > x)
+            {
+                continue;
+            }
+
+            ranges
+                .entry((entry.from_block, entry.to_block))
+                .or_default()
+                .push(entry.path);
+        }
+
+        let mut merged: Vec<((u64, u64), Vec<String>)> = Vec::new();
+        for ((from, to), paths) in ranges {
+            match merged.last_mut() {
+                Some(((_, last_to), last_paths)) if from <= *last_to => {
+                    *last_to = (*last_to).max(to);
+                    last_paths.extend(paths);
+                }
+                _ => merged.push(((from, to), paths)),
+            }
+        }
+
+        ArchiveReader {
+            dir: dir.to_owned(),
+            ranges: merged,
+            position: 0,
+        }
+    }
+}
+
+impl Iterator for ArchiveReader {
+    type Item = Vec<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, paths) = self.ranges.get(self.position)?;
+        self.position += 1;
+
+        let mut records = Vec::new();
+        for path in paths {
+            records.extend(read_decoded_file(&Path::new(&self.dir).join(path)));
+        }
+
+        records.sort_by_key(|x| (x.block_number, x.transaction_index, x.log_index));
+        records.dedup_by(|a, b| {
+            a.log_index.is_some() && (a.block_number, a.log_index) == (b.block_number, b.log_index)
+        });
+
+        Some(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(log_type: i32, block_number: i64, log_index: i64) -> LogRecord {
+        LogRecord {
+            id: 0,
+            log_type,
+            block_number,
+            address: "0x01".to_owned(),
+            data1: Some(block_number.to_string()),
+            data2: None,
+            data3: None,
+            data4: None,
+            data5: None,
+            transaction_hash: None,
+            log_index: Some(log_index),
+            transaction_index: Some(0),
+        }
+    }
+
+    #[test]
+    fn overlapping_ranges_are_read_once() {
+        let dir = std::env::temp_dir().join("overlapping_archive");
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap();
+
+        // Syncs of blocks 5..=10 are archived by both collections, those of 11..=15 only by the second.
+        let mut writer = ArchiveWriter::new(dir);
+        let first: Vec<LogRecord> = (1..=10)
+            .flat_map(|x| [log(1, x, 0), log(2, x, 1)])
+            .collect();
+        writer.write_decoded(1, 10, &first);
+        let second: Vec<LogRecord> = (5..=15).map(|x| log(1, x, 0)).collect();
+        writer.write_decoded(5, 15, &second);
+        let third: Vec<LogRecord> = (16..=20).map(|x| log(1, x, 0)).collect();
+        writer.write_decoded(16, 20, &third);
+
+        let chunks: Vec<Vec<LogRecord>> = ArchiveReader::with_range(dir, None, None).collect();
+        assert_eq!(chunks.len(), 2);
+
+        let keys: Vec<(i64, i64)> = chunks
+            .iter()
+            .flatten()
+            .map(|x| (x.block_number, x.log_index.unwrap()))
+            .collect();
+        let mut expected: Vec<(i64, i64)> = (1..=10).flat_map(|x| [(x, 0), (x, 1)]).collect();
+        expected.extend((11..=20).map(|x| (x, 0)));
+        assert_eq!(keys, expected);
+    }
+}
@@ -0,0 +1,300 @@
+use crate::db::db::first_block_at;
+use crate::DatasetExportArgs;
+use chrono::NaiveDate;
+use csv::{Reader, StringRecord, Writer};
+use diesel::PgConnection;
+use serde::Serialize;
+use std::fs::File;
+
+const ID_COLUMNS: [&str; 4] = [
+    "open_block_number",
+    "close_block_number",
+    "token_symbol",
+    "token_address",
+];
+const LABEL_COLUMNS: [&str; 2] = ["target_price", "target_valid"];
+const LABEL_PREFIXES: [&str; 4] = ["return_", "norm_return_", "mfe_", "barrier_"];
+
+const LABEL_DEFINITION: &str = "target_price is the close of the next candle when it is the adjacent interval (target_valid). return_n, norm_return_n, mfe_n and barrier_n look at the n candle intervals after the candle's close: percent return, return over the candle's std_price_change window, maximum favourable excursion in percent, and the triple-barrier outcome (1 take-profit, -1 stop-loss, 0 neither).";
+
+#[derive(Serialize)]
+struct LabelSpec {
+    horizon_candles: u64,
+    horizon_blocks: u64,
+    definition: &'static str,
+}
+
+#[derive(Serialize)]
+struct SplitSpec {
+    name: &'static str,
+    path: String,
+    /// Boundaries before purging and embargo, inclusive
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+    rows: u64,
+    /// Rows dropped because their labels reach into the next split
+    purged: u64,
+    /// Rows dropped because their features reach back into the previous split
+    embargoed: u64,
+}
+
+#[derive(Serialize)]
+struct Metadata {
+    source: String,
+    candlestick_len: u64,
+    purge_blocks: u64,
+    embargo_blocks: u64,
+    features: Vec<String>,
+    labels: Vec<String>,
+    label: LabelSpec,
+    splits: Vec<SplitSpec>,
+}
+
+// A split boundary given as a block number or a UTC date, dates start at their first block.
+fn boundary(conn: &PgConnection, value: &str) -> u64 {
+    if let Ok(r) = value.parse() {
+        return r;
+    }
+
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .unwrap_or_else(|_| panic!("Split boundary {} is neither a block nor a date", value));
+    let timestamp = date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
+
+    first_block_at(conn, timestamp)
+        .unwrap_or_else(|| panic!("No collected block at or after {}", value)) as u64
+}
+
+fn is_label(column: &str) -> bool {
+    LABEL_COLUMNS.contains(&column) || LABEL_PREFIXES.iter().any(|x| column.starts_with(x))
+}
+
+// Longest label horizon of the header in candles, the next-candle target when there are no others.
+fn horizon_candles(header: &StringRecord) -> u64 {
+    header
+        .iter()
+        .filter_map(|x| x.strip_prefix("return_")?.parse().ok())
+        .fold(1, u64::max)
+}
+
+pub fn export(conn: &PgConnection, args: DatasetExportArgs) {
+    let val_start = boundary(conn, &args.val_start);
+    let test_start = boundary(conn, &args.test_start);
+
+    write_splits(&args, val_start, test_start);
+}
+
+// Splits the candles written by the raw CSV processor by open block: train before `val_start`,
+// validation before `test_start`, test from there on.
+// A row right before a boundary is purged when its labels reach past it, a row right after one
+// is embargoed while its rolling features still cover blocks before it.
+fn write_splits(args: &DatasetExportArgs, val_start: u64, test_start: u64) {
+    assert!(
+        val_start <= test_start,
+        "Validation split starts after the test split"
+    );
+    assert!(
+        val_start > 0,
+        "Validation split starts at block 0, there is no train split"
+    );
+
+    let mut rdr = Reader::from_path(&args.input_path).expect("can't read candles csv");
+    let header = rdr.headers().expect("can't read candles header").clone();
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|x| x == name)
+            .unwrap_or_else(|| panic!("Candles have no {} column", name))
+    };
+    let open_column = column("open_block_number");
+    let close_column = column("close_block_number");
+
+    let mut splits = [
+        ("train", None, Some(val_start)),
+        ("val", Some(val_start), Some(test_start)),
+        ("test", Some(test_start), None),
+    ]
+    .map(|(name, from_block, end_block)| SplitSpec {
+        name,
+        path: format!("{}/{}.csv", args.output_dir, name),
+        from_block,
+        to_block: end_block.map(|x: u64| x - 1),
+        rows: 0,
+        purged: 0,
+        embargoed: 0,
+    });
+    let mut writers: Vec<Writer<File>> = splits
+        .iter()
+        .map(|x| {
+            let mut wtr = Writer::from_path(&x.path).expect("can't create split csv");
+            wtr.write_record(&header).unwrap();
+            wtr
+        })
+        .collect();
+
+    let horizon_candles = horizon_candles(&header);
+    let mut candlestick_len = 0;
+
+    for result in rdr.records() {
+        let record = result.expect("can't read candle");
+        let open_block: u64 = record[open_column].parse().expect("Invalid open block");
+        let close_block: u64 = record[close_column].parse().expect("Invalid close block");
+        candlestick_len = close_block - open_block + 1;
+        let purge_blocks = horizon_candles * candlestick_len;
+
+        let index = if open_block < val_start {
+            0
+        } else if open_block < test_start {
+            1
+        } else {
+            2
+        };
+        let split = &mut splits[index];
+
+        if split
+            .to_block
+            .is_some_and(|x| close_block + purge_blocks > x)
+        {
+            split.purged += 1;
+        } else if split
+            .from_block
+            .is_some_and(|x| open_block < x + args.feature_window)
+        {
+            split.embargoed += 1;
+        } else {
+            writers[index].write_record(&record).unwrap();
+            split.rows += 1;
+        }
+    }
+
+    for mut wtr in writers {
+        wtr.flush().unwrap();
+    }
+
+    for split in &splits {
+        println!(
+            "[{}] {} rows, {} purged, {} embargoed",
+            split.name, split.rows, split.purged, split.embargoed
+        );
+    }
+
+    let metadata = Metadata {
+        source: args.input_path.clone(),
+        candlestick_len,
+        purge_blocks: horizon_candles * candlestick_len,
+        embargo_blocks: args.feature_window,
+        features: header
+            .iter()
+            .filter(|x| !ID_COLUMNS.contains(x) && !is_label(x))
+            .map(|x| x.to_owned())
+            .collect(),
+        labels: header
+            .iter()
+            .filter(|x| is_label(x))
+            .map(|x| x.to_owned())
+            .collect(),
+        label: LabelSpec {
+            horizon_candles,
+            horizon_blocks: horizon_candles * candlestick_len,
+            definition: LABEL_DEFINITION,
+        },
+        splits: splits.into(),
+    };
+
+    let file = File::create(format!("{}/metadata.json", args.output_dir))
+        .expect("can't create metadata json");
+    serde_json::to_writer_pretty(file, &metadata).expect("can't write metadata json");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn read_open_blocks(path: &str) -> Vec<u64> {
+        Reader::from_path(path)
+            .unwrap()
+            .records()
+            .map(|x| x.unwrap()[0].parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rows_at_the_boundaries_are_purged_and_embargoed() {
+        let dir = std::env::temp_dir().join("dataset_export_splits");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap().to_owned();
+
+        // 100-block candles with a 2-candle label horizon, so labels reach 200 blocks past the close.
+        let mut candles = String::from(
+            "open_block_number,close_block_number,token_symbol,token_address,volume,return_1,return_2\n",
+        );
+        for open_block in (0..3000).step_by(100) {
+            candles += &format!("{},{},TKA,0xaa,1,0,0\n", open_block, open_block + 99);
+        }
+        let input_path = format!("{}/candles.csv", dir);
+        fs::write(&input_path, candles).unwrap();
+
+        let args = DatasetExportArgs {
+            input_path,
+            output_dir: dir.clone(),
+            val_start: "1000".to_owned(),
+            test_start: "2000".to_owned(),
+            feature_window: 200,
+        };
+        write_splits(&args, 1000, 2000);
+
+        let blocks = |x| (x..).step_by(100);
+        assert_eq!(
+            read_open_blocks(&format!("{}/train.csv", dir)),
+            blocks(0).take(8).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            read_open_blocks(&format!("{}/val.csv", dir)),
+            blocks(1200).take(6).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            read_open_blocks(&format!("{}/test.csv", dir)),
+            blocks(2200).take(8).collect::<Vec<_>>()
+        );
+
+        let metadata: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(format!("{}/metadata.json", dir)).unwrap())
+                .unwrap();
+        assert_eq!(metadata["purge_blocks"], 200);
+        let counts: Vec<_> = metadata["splits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| {
+                (
+                    x["rows"].clone(),
+                    x["purged"].clone(),
+                    x["embargoed"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (8.into(), 2.into(), 0.into()),
+                (6.into(), 2.into(), 2.into()),
+                (8.into(), 0.into(), 2.into()),
+            ]
+        );
+        assert_eq!(metadata["splits"][0]["to_block"], 999);
+    }
+
+    #[test]
+    #[should_panic(expected = "there is no train split")]
+    fn validation_from_block_0_is_rejected() {
+        let args = DatasetExportArgs {
+            input_path: String::new(),
+            output_dir: String::new(),
+            val_start: "0".to_owned(),
+            test_start: "0".to_owned(),
+            feature_window: 0,
+        };
+        write_splits(&args, 0, 0);
+    }
+}
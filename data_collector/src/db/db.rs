@@ -1,7 +1,10 @@
-use super::models::BlockRecord;
+use super::models::{
+    BlockRecord, CandleRecord, PoolInfo, PoolState, SwapTick, SyncTick, TransactionRecord,
+};
 use super::schema::blocks::dsl::blocks;
 use crate::db::models::LogRecord;
 use crate::db::schema::logs::dsl::logs;
+use crate::db::schema::{candles, cex_data, pool_states, pools, swap_ticks, transactions};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use dotenv::dotenv;
@@ -37,3 +40,372 @@ pub fn insert_multiple_logs(conn: &PgConnection, new_logs: Vec<LogRecord>) {
         .execute(conn)
         .expect("Error inserting logs");
 }
+
+pub fn load_pools(conn: &PgConnection, tokens: &[String], factory: Option<&str>) -> Vec<PoolInfo> {
+    let mut query = pools::table.into_boxed();
+
+    if !tokens.is_empty() {
+        query = query.filter(
+            pools::token0
+                .eq_any(tokens.to_vec())
+                .or(pools::token1.eq_any(tokens.to_vec())),
+        );
+    }
+
+    if let Some(factory) = factory {
+        query = query.filter(pools::factory.eq(factory.to_owned()));
+    }
+
+    query.load::<PoolInfo>(conn).expect("Error loading pools")
+}
+
+pub fn load_pools_by_address(conn: &PgConnection, addresses: &[String]) -> Vec<PoolInfo> {
+    pools::table
+        .filter(pools::address.eq_any(addresses.to_vec()))
+        .load::<PoolInfo>(conn)
+        .expect("Error loading pools")
+}
+
+pub fn load_token_addresses(conn: &PgConnection) -> Vec<String> {
+    cex_data::table
+        .filter(cex_data::platform_slug.eq("ethereum"))
+        .select(cex_data::token_address)
+        .distinct()
+        .load::<String>(conn)
+        .expect("Error loading token addresses")
+}
+
+pub fn logs_block_range(conn: &PgConnection, log_type: i32) -> Option<(i64, i64)> {
+    use crate::db::schema::logs::dsl;
+
+    let from = dsl::logs
+        .filter(dsl::log_type.eq(log_type))
+        .select(diesel::dsl::min(dsl::block_number))
+        .first::<Option<i64>>(conn)
+        .expect("Error loading logs block range");
+    let to = dsl::logs
+        .filter(dsl::log_type.eq(log_type))
+        .select(diesel::dsl::max(dsl::block_number))
+        .first::<Option<i64>>(conn)
+        .expect("Error loading logs block range");
+
+    Some((from?, to?))
+}
+
+pub fn load_logs(
+    conn: &PgConnection,
+    log_type: i32,
+    from_block: i64,
+    to_block: i64,
+) -> Vec<LogRecord> {
+    use crate::db::schema::logs::dsl;
+
+    dsl::logs
+        .filter(dsl::log_type.eq(log_type))
+        .filter(dsl::block_number.between(from_block, to_block))
+        .order((
+            dsl::block_number.asc(),
+            dsl::transaction_index.asc(),
+            dsl::log_index.asc(),
+        ))
+        .load::<LogRecord>(conn)
+        .expect("Error loading logs")
+}
+
+pub fn insert_multiple_transactions(conn: &PgConnection, new_transactions: Vec<TransactionRecord>) {
+    diesel::insert_into(transactions::table)
+        .values(&new_transactions)
+        .execute(conn)
+        .expect("Error inserting transactions");
+}
+
+pub fn load_transactions(
+    conn: &PgConnection,
+    from_block: i64,
+    to_block: i64,
+) -> Vec<TransactionRecord> {
+    transactions::table
+        .filter(transactions::block_number.between(from_block, to_block))
+        .load::<TransactionRecord>(conn)
+        .expect("Error loading transactions")
+}
+
+// Latest state of the pool at or before the block, pool states only have rows for blocks it changed in.
+pub fn pool_state_at(conn: &PgConnection, address: &str, block_number: i64) -> Option<PoolState> {
+    pool_states::table
+        .filter(pool_states::address.eq(address))
+        .filter(pool_states::block_number.le(block_number))
+        .order(pool_states::block_number.desc())
+        .first::<PoolState>(conn)
+        .optional()
+        .expect("Error loading pool state")
+}
+
+// First collected block mined at or after the unix timestamp.
+pub fn first_block_at(conn: &PgConnection, unix_timestamp: i64) -> Option<i64> {
+    use crate::db::schema::blocks::dsl::*;
+
+    blocks
+        .select(block_number)
+        .filter(timestamp.ge(unix_timestamp))
+        .order(timestamp.asc())
+        .first::<i64>(conn)
+        .optional()
+        .expect("Error loading blocks")
+}
+
+// Timestamp of the latest collected block at or before the block.
+pub fn block_timestamp_at(conn: &PgConnection, number: i64) -> Option<i64> {
+    use crate::db::schema::blocks::dsl::*;
+
+    blocks
+        .select(timestamp)
+        .filter(block_number.le(number))
+        .order(block_number.desc())
+        .first::<i64>(conn)
+        .optional()
+        .expect("Error loading blocks")
+}
+
+pub fn swap_ticks_block_range(conn: &PgConnection) -> Option<(i64, i64)> {
+    let from = swap_ticks::table
+        .select(diesel::dsl::min(swap_ticks::block_number))
+        .first::<Option<i64>>(conn)
+        .expect("Error loading swap ticks block range");
+    let to = swap_ticks::table
+        .select(diesel::dsl::max(swap_ticks::block_number))
+        .first::<Option<i64>>(conn)
+        .expect("Error loading swap ticks block range");
+
+    Some((from?, to?))
+}
+
+// Swaps of pools trading one of `tokens`, or of every pool when it's empty.
+pub fn load_swap_ticks(
+    conn: &PgConnection,
+    from_block: i64,
+    to_block: i64,
+    tokens: &[String],
+) -> Vec<SwapTick> {
+    use crate::db::schema::swap_ticks::dsl::*;
+
+    let mut query = swap_ticks
+        .select((
+            token0_symbol,
+            token1_symbol,
+            token0_address,
+            token1_address,
+            block_number,
+            address,
+            sender,
+            amount0_in,
+            amount0_out,
+            amount1_in,
+            amount1_out,
+            token0_usd_price,
+            token1_usd_price,
+            transaction_hash,
+            origin,
+            log_index,
+            base_address,
+            side,
+            base_amount,
+            quote_amount,
+            execution_price,
+            notional_usd,
+            price_impact,
+        ))
+        .filter(block_number.between(from_block, to_block))
+        .order((block_number.asc(), log_index.asc(), id.asc()))
+        .into_boxed();
+
+    if !tokens.is_empty() {
+        query = query.filter(
+            token0_address
+                .eq_any(tokens.to_vec())
+                .or(token1_address.eq_any(tokens.to_vec())),
+        );
+    }
+
+    query
+        .load::<SwapTick>(conn)
+        .expect("Error loading swap ticks")
+}
+
+// Syncs of pools trading one of `tokens`, or of every pool when it's empty.
+pub fn load_sync_ticks(
+    conn: &PgConnection,
+    from_block: i64,
+    to_block: i64,
+    tokens: &[String],
+) -> Vec<SyncTick> {
+    use crate::db::schema::sync_ticks::dsl::*;
+
+    let mut query = sync_ticks
+        .select((
+            token0_symbol,
+            token1_symbol,
+            token0_address,
+            token1_address,
+            block_number,
+            address,
+            reserve0,
+            reserve1,
+            token0_usd_price,
+            token1_usd_price,
+            transaction_hash,
+            log_index,
+            cause,
+        ))
+        .filter(block_number.between(from_block, to_block))
+        .order((block_number.asc(), log_index.asc(), id.asc()))
+        .into_boxed();
+
+    if !tokens.is_empty() {
+        query = query.filter(
+            token0_address
+                .eq_any(tokens.to_vec())
+                .or(token1_address.eq_any(tokens.to_vec())),
+        );
+    }
+
+    query
+        .load::<SyncTick>(conn)
+        .expect("Error loading sync ticks")
+}
+
+// Replaces the candles of the length that open in the block range.
+pub fn replace_candles(
+    conn: &PgConnection,
+    candlestick_len: i64,
+    from_block: i64,
+    to_block: i64,
+    new_candles: Vec<CandleRecord>,
+) {
+    // Only the tokens written are replaced, a run over some tokens keeps the others' candles.
+    let mut token_addresses: Vec<&str> = new_candles
+        .iter()
+        .map(|x| x.token_address.as_str())
+        .collect();
+    token_addresses.sort();
+    token_addresses.dedup();
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        diesel::delete(
+            candles::table
+                .filter(candles::token_address.eq_any(&token_addresses))
+                .filter(candles::candlestick_len.eq(candlestick_len))
+                .filter(candles::open_block_number.between(from_block, to_block)),
+        )
+        .execute(conn)?;
+
+        for chunk in new_candles.chunks(1000) {
+            diesel::insert_into(candles::table)
+                .values(chunk)
+                .execute(conn)?;
+        }
+
+        Ok(())
+    })
+    .expect("Error replacing candles");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN_A: &str = "0x00000000000000000000000000000000000000aa";
+    const TOKEN_B: &str = "0x00000000000000000000000000000000000000bb";
+
+    fn candle(token_address: &str, open_block_number: i64, close_price: f64) -> CandleRecord {
+        CandleRecord {
+            token_address: token_address.to_owned(),
+            candlestick_len: 300,
+            open_block_number,
+            close_block_number: open_block_number + 299,
+            token_symbol: "TK".to_owned(),
+            open_price: 1.0,
+            close_price,
+            high_price: 1.0,
+            low_price: 1.0,
+            vwap: 1.0,
+            volume: 1.0,
+            buys_count: 1,
+            sells_count: 0,
+            buys_usd: 1.0,
+            sells_usd: 0.0,
+            buy_volume_share: 1.0,
+            realized_volatility: 0.0,
+            target_price: 1.0,
+            target_valid: true,
+        }
+    }
+
+    // Runs against the Postgres in DATABASE_URL, inside a transaction that is rolled back.
+    #[test]
+    #[ignore]
+    fn replacing_some_tokens_keeps_the_others() {
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let conn = establish_connection(&database_url);
+        conn.begin_test_transaction().unwrap();
+        diesel::sql_query(
+            "CREATE TEMP TABLE candles (
+                token_address VARCHAR NOT NULL,
+                candlestick_len BIGINT NOT NULL,
+                open_block_number BIGINT NOT NULL,
+                close_block_number BIGINT NOT NULL,
+                token_symbol VARCHAR NOT NULL,
+                open_price DOUBLE PRECISION NOT NULL,
+                close_price DOUBLE PRECISION NOT NULL,
+                high_price DOUBLE PRECISION NOT NULL,
+                low_price DOUBLE PRECISION NOT NULL,
+                vwap DOUBLE PRECISION NOT NULL,
+                volume DOUBLE PRECISION NOT NULL,
+                buys_count BIGINT NOT NULL,
+                sells_count BIGINT NOT NULL,
+                buys_usd DOUBLE PRECISION NOT NULL,
+                sells_usd DOUBLE PRECISION NOT NULL,
+                buy_volume_share DOUBLE PRECISION NOT NULL,
+                realized_volatility DOUBLE PRECISION NOT NULL,
+                target_price DOUBLE PRECISION NOT NULL,
+                target_valid BOOLEAN NOT NULL,
+                PRIMARY KEY (token_address, candlestick_len, open_block_number)
+            )",
+        )
+        .execute(&conn)
+        .unwrap();
+
+        replace_candles(
+            &conn,
+            300,
+            0,
+            300,
+            vec![
+                candle(TOKEN_A, 0, 1.0),
+                candle(TOKEN_A, 300, 1.0),
+                candle(TOKEN_B, 0, 1.0),
+                candle(TOKEN_B, 300, 1.0),
+            ],
+        );
+        // A rerun with --tokens A that only has A's first candle now.
+        replace_candles(&conn, 300, 0, 300, vec![candle(TOKEN_A, 0, 2.0)]);
+
+        let rows: Vec<(String, i64, f64)> = candles::table
+            .select((
+                candles::token_address,
+                candles::open_block_number,
+                candles::close_price,
+            ))
+            .order((candles::token_address, candles::open_block_number))
+            .load(&conn)
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (TOKEN_A.to_owned(), 0, 2.0),
+                (TOKEN_B.to_owned(), 0, 1.0),
+                (TOKEN_B.to_owned(), 300, 1.0),
+            ]
+        );
+    }
+}
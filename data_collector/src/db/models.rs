@@ -1,8 +1,13 @@
-use super::schema::{blocks, cex_data, liquidity_ticks, logs, pools, swap_ticks, sync_ticks};
+use super::schema::{
+    blocks, candles, cex_data, liquidity_ticks, logs, lp_positions, pool_states, pools,
+    processing_checkpoints, swap_ticks, sync_ticks, trades, transactions,
+};
+use crate::numeric::deserialize_decimal;
+use bigdecimal::BigDecimal;
 use serde::{Deserialize, Serialize};
-use web3::types::{Address, U256};
+use web3::types::{Address, H256, U256};
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 pub struct Token {
     pub symbol: String,
     pub address: Address,
@@ -18,10 +23,19 @@ pub struct SyncTick {
     pub token1_address: String,
     pub block_number: i64,
     pub address: String,
-    pub reserve0: f64,
-    pub reserve1: f64,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub reserve0: BigDecimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub reserve1: BigDecimal,
     pub token0_usd_price: f64,
     pub token1_usd_price: f64,
+    #[serde(default)]
+    pub transaction_hash: Option<String>,
+    #[serde(default)]
+    pub log_index: Option<i64>,
+    /// Kind of the Swap/Mint/Burn this Sync was emitted for, none for a bare `sync()`/`skim()`
+    #[serde(default)]
+    pub cause: Option<String>,
 }
 
 #[derive(Queryable, Insertable, Serialize, Deserialize)]
@@ -34,12 +48,57 @@ pub struct SwapTick {
     pub block_number: i64,
     pub address: String,
     pub sender: String,
-    pub amount0_in: f64,
-    pub amount0_out: f64,
-    pub amount1_in: f64,
-    pub amount1_out: f64,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub amount0_in: BigDecimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub amount0_out: BigDecimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub amount1_in: BigDecimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub amount1_out: BigDecimal,
     pub token0_usd_price: f64,
     pub token1_usd_price: f64,
+    #[serde(default)]
+    pub transaction_hash: Option<String>,
+    #[serde(default)]
+    pub origin: Option<String>,
+    #[serde(default)]
+    pub log_index: Option<i64>,
+    /// Base token of the pair, the other one is the quote (stablecoins, then WETH-like tokens)
+    pub base_address: String,
+    /// "buy" when the trader received the base token, "sell" otherwise
+    pub side: String,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub base_amount: BigDecimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub quote_amount: BigDecimal,
+    /// Quote per base paid by the trader
+    pub execution_price: f64,
+    pub notional_usd: f64,
+    /// Execution price relative to the spot price of the reserves before the swap, minus one
+    pub price_impact: f64,
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize)]
+#[table_name = "trades"]
+pub struct TradeRecord {
+    pub transaction_hash: String,
+    pub block_number: i64,
+    pub origin: Option<String>,
+    pub router: Option<String>,
+    pub token_in_symbol: String,
+    pub token_in_address: String,
+    pub token_out_symbol: String,
+    pub token_out_address: String,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub amount_in: BigDecimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub amount_out: BigDecimal,
+    pub hops: i32,
+    pub volume_usd: f64,
+    pub gas_used: Option<i64>,
+    /// Wei per gas
+    pub effective_gas_price: Option<i64>,
 }
 
 #[derive(Queryable, Insertable, Serialize, Deserialize)]
@@ -52,10 +111,67 @@ pub struct LiquidityTick {
     pub block_number: i64,
     pub address: String,
     pub sender: String,
-    pub amount0: f64,
-    pub amount1: f64,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub amount0: BigDecimal,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub amount1: BigDecimal,
     pub token0_usd_price: f64,
     pub token1_usd_price: f64,
+    #[serde(default)]
+    pub transaction_hash: Option<String>,
+    #[serde(default)]
+    pub log_index: Option<i64>,
+    /// LP token recipient of a Mint, LP token sender of a Burn; `sender` is usually the router
+    #[serde(default)]
+    pub provider: Option<String>,
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone)]
+#[table_name = "pool_states"]
+pub struct PoolState {
+    pub address: String,
+    pub block_number: i64,
+    pub token0_symbol: String,
+    pub token1_symbol: String,
+    pub token0_address: String,
+    pub token1_address: String,
+    /// Exact, in token units
+    pub reserve0: BigDecimal,
+    pub reserve1: BigDecimal,
+    /// token1 per token0
+    pub price0: f64,
+    /// token0 per token1
+    pub price1: f64,
+    pub tvl_usd: f64,
+    pub k: BigDecimal,
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone)]
+#[table_name = "lp_positions"]
+pub struct LpPosition {
+    pub block_number: i64,
+    pub address: String,
+    pub provider: String,
+    pub token0_symbol: String,
+    pub token1_symbol: String,
+    /// Exact, in LP token units
+    pub lp_balance: BigDecimal,
+    pub lp_supply: BigDecimal,
+    pub amount0: f64,
+    pub amount1: f64,
+    pub value_usd: f64,
+    /// Deposited amounts still attributed to the position, valued at current prices
+    pub hold_value_usd: f64,
+    pub fees_usd: f64,
+    /// Value without fees relative to holding, minus one
+    pub impermanent_loss: f64,
+}
+
+#[derive(Queryable, Insertable)]
+#[table_name = "processing_checkpoints"]
+pub struct Checkpoint {
+    pub name: String,
+    pub block_number: i64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -85,17 +201,90 @@ pub enum Event {
     Swap(SwapEvent),
     Mint(MintEvent),
     Burn(BurnEvent),
+    Transfer(TransferEvent),
 }
 
-pub fn parse_event(args: Vec<String>) -> Option<Event> {
-    match args[0].as_str() {
-        "0" => None,
-        "1" => Some(Event::Sync(SyncEvent::new(args))),
-        "2" => Some(Event::Swap(SwapEvent::new(args))),
-        "3" => Some(Event::Mint(MintEvent::new(args))),
-        "4" => Some(Event::Burn(BurnEvent::new(args))),
-        _ => panic!("Invalid args"),
+impl Event {
+    // Pair creations (and unknown types) are skipped. Transfers are kept for LP token accounting.
+    // A record of a known type that doesn't parse is a collection bug, not something to skip.
+    pub fn from_record(record: &LogRecord) -> Option<Event> {
+        let event = match record.log_type {
+            1 => SyncEvent::from_record(record).map(Event::Sync),
+            2 => SwapEvent::from_record(record).map(Event::Swap),
+            3 => MintEvent::from_record(record).map(Event::Mint),
+            4 => BurnEvent::from_record(record).map(Event::Burn),
+            5 => TransferEvent::from_record(record).map(Event::Transfer),
+            _ => return None,
+        };
+
+        Some(event.unwrap_or_else(|x| panic!("{}", record.malformed(&x))))
+    }
+
+    pub fn block_number(&self) -> u64 {
+        match self {
+            Event::Sync(event) => event.block_number,
+            Event::Swap(event) => event.block_number,
+            Event::Mint(event) => event.block_number,
+            Event::Burn(event) => event.block_number,
+            Event::Transfer(event) => event.block_number,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        match self {
+            Event::Sync(event) => event.address,
+            Event::Swap(event) => event.address,
+            Event::Mint(event) => event.address,
+            Event::Burn(event) => event.address,
+            Event::Transfer(event) => event.address,
+        }
+    }
+
+    pub fn transaction_hash(&self) -> Option<H256> {
+        match self {
+            Event::Sync(event) => event.transaction_hash,
+            Event::Swap(event) => event.transaction_hash,
+            Event::Mint(event) => event.transaction_hash,
+            Event::Burn(event) => event.transaction_hash,
+            Event::Transfer(event) => event.transaction_hash,
+        }
+    }
+
+    pub fn log_index(&self) -> Option<u64> {
+        match self {
+            Event::Sync(event) => event.log_index,
+            Event::Swap(event) => event.log_index,
+            Event::Mint(event) => event.log_index,
+            Event::Burn(event) => event.log_index,
+            Event::Transfer(event) => event.log_index,
+        }
     }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Sync(_) => "sync",
+            Event::Swap(_) => "swap",
+            Event::Mint(_) => "mint",
+            Event::Burn(_) => "burn",
+            Event::Transfer(_) => "transfer",
+        }
+    }
+}
+
+fn parse_optional<T: std::str::FromStr>(field: &Option<String>) -> Option<T> {
+    field.as_ref()?.parse().ok()
+}
+
+fn parse_field<T: std::str::FromStr>(field: Option<&str>, name: &str) -> Result<T, String> {
+    field
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| format!("invalid {} {:?}", name, field))
+}
+
+fn parse_amount(field: Option<&str>, name: &str) -> Result<U256, String> {
+    field
+        .and_then(|x| U256::from_dec_str(x).ok())
+        .ok_or_else(|| format!("invalid {} {:?}", name, field))
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -104,16 +293,20 @@ pub struct SyncEvent {
     pub address: Address,
     pub reserve0: U256,
     pub reserve1: U256,
+    pub transaction_hash: Option<H256>,
+    pub log_index: Option<u64>,
 }
 
 impl SyncEvent {
-    pub fn new(args: Vec<String>) -> Self {
-        Self {
-            block_number: args[1].parse().expect("block number is invalid"),
-            address: args[2].parse().expect("address is invalid"),
-            reserve0: U256::from_dec_str(&args[3]).expect("reserve0 is invalid"),
-            reserve1: U256::from_dec_str(&args[4]).expect("reserve1 is invalid"),
-        }
+    pub fn from_record(record: &LogRecord) -> Result<Self, String> {
+        Ok(Self {
+            block_number: record.block_number as u64,
+            address: parse_field(Some(&record.address), "address")?,
+            reserve0: parse_amount(record.data1.as_deref(), "reserve0")?,
+            reserve1: parse_amount(record.data2.as_deref(), "reserve1")?,
+            transaction_hash: parse_optional(&record.transaction_hash),
+            log_index: record.log_index.map(|x| x as u64),
+        })
     }
 }
 
@@ -126,19 +319,23 @@ pub struct SwapEvent {
     pub amount0_out: U256,
     pub amount1_in: U256,
     pub amount1_out: U256,
+    pub transaction_hash: Option<H256>,
+    pub log_index: Option<u64>,
 }
 
 impl SwapEvent {
-    pub fn new(args: Vec<String>) -> Self {
-        Self {
-            block_number: args[1].parse().expect("block number is invalid"),
-            address: args[2].parse().expect("address is invalid"),
-            sender: args[3].parse().expect("sender is invalid"),
-            amount0_in: U256::from_dec_str(&args[4]).expect("amount0_in is invalid"),
-            amount0_out: U256::from_dec_str(&args[5]).expect("amount0_out is invalid"),
-            amount1_in: U256::from_dec_str(&args[6]).expect("amount1_in is invalid"),
-            amount1_out: U256::from_dec_str(&args[7]).expect("amount1_out is invalid"),
-        }
+    pub fn from_record(record: &LogRecord) -> Result<Self, String> {
+        Ok(Self {
+            block_number: record.block_number as u64,
+            address: parse_field(Some(&record.address), "address")?,
+            sender: parse_field(record.data1.as_deref(), "sender")?,
+            amount0_in: parse_amount(record.data2.as_deref(), "amount0_in")?,
+            amount0_out: parse_amount(record.data4.as_deref(), "amount0_out")?,
+            amount1_in: parse_amount(record.data3.as_deref(), "amount1_in")?,
+            amount1_out: parse_amount(record.data5.as_deref(), "amount1_out")?,
+            transaction_hash: parse_optional(&record.transaction_hash),
+            log_index: record.log_index.map(|x| x as u64),
+        })
     }
 }
 
@@ -149,17 +346,21 @@ pub struct MintEvent {
     pub sender: Address,
     pub amount0: U256,
     pub amount1: U256,
+    pub transaction_hash: Option<H256>,
+    pub log_index: Option<u64>,
 }
 
 impl MintEvent {
-    pub fn new(args: Vec<String>) -> Self {
-        Self {
-            block_number: args[1].parse().expect("block number is invalid"),
-            address: args[2].parse().expect("address is invalid"),
-            sender: args[3].parse().expect("sender is invalid"),
-            amount0: U256::from_dec_str(&args[4]).expect("amount0 is invalid"),
-            amount1: U256::from_dec_str(&args[5]).expect("amount1 is invalid"),
-        }
+    pub fn from_record(record: &LogRecord) -> Result<Self, String> {
+        Ok(Self {
+            block_number: record.block_number as u64,
+            address: parse_field(Some(&record.address), "address")?,
+            sender: parse_field(record.data1.as_deref(), "sender")?,
+            amount0: parse_amount(record.data2.as_deref(), "amount0")?,
+            amount1: parse_amount(record.data3.as_deref(), "amount1")?,
+            transaction_hash: parse_optional(&record.transaction_hash),
+            log_index: record.log_index.map(|x| x as u64),
+        })
     }
 }
 
@@ -170,20 +371,69 @@ pub struct BurnEvent {
     pub sender: Address,
     pub amount0: U256,
     pub amount1: U256,
+    pub transaction_hash: Option<H256>,
+    pub log_index: Option<u64>,
 }
 
 impl BurnEvent {
-    pub fn new(args: Vec<String>) -> Self {
-        Self {
-            block_number: args[1].parse().expect("block number is invalid"),
-            address: args[2].parse().expect("address is invalid"),
-            sender: args[3].parse().expect("sender is invalid"),
-            amount0: U256::from_dec_str(&args[4]).expect("amount0 is invalid"),
-            amount1: U256::from_dec_str(&args[5]).expect("amount1 is invalid"),
-        }
+    pub fn from_record(record: &LogRecord) -> Result<Self, String> {
+        Ok(Self {
+            block_number: record.block_number as u64,
+            address: parse_field(Some(&record.address), "address")?,
+            sender: parse_field(record.data1.as_deref(), "sender")?,
+            amount0: parse_amount(record.data2.as_deref(), "amount0")?,
+            amount1: parse_amount(record.data3.as_deref(), "amount1")?,
+            transaction_hash: parse_optional(&record.transaction_hash),
+            log_index: record.log_index.map(|x| x as u64),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct TransferEvent {
+    pub block_number: u64,
+    pub address: Address,
+    pub from: Address,
+    pub to: Address,
+    pub value: U256,
+    pub transaction_hash: Option<H256>,
+    pub log_index: Option<u64>,
+}
+
+impl TransferEvent {
+    pub fn from_record(record: &LogRecord) -> Result<Self, String> {
+        Ok(Self {
+            block_number: record.block_number as u64,
+            address: parse_field(Some(&record.address), "address")?,
+            from: parse_field(record.data1.as_deref(), "from")?,
+            to: parse_field(record.data2.as_deref(), "to")?,
+            value: parse_amount(record.data3.as_deref(), "value")?,
+            transaction_hash: parse_optional(&record.transaction_hash),
+            log_index: record.log_index.map(|x| x as u64),
+        })
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct BalanceTick {
+    pub block_number: i64,
+    pub token_symbol: String,
+    pub token_address: String,
+    pub holder: String,
+    pub balance: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HolderStats {
+    pub open_block_number: u64,
+    pub close_block_number: u64,
+    pub token_symbol: String,
+    pub token_address: String,
+    pub holders_count: u64,
+    pub top_holders_share: f64,
+    pub transfers_count: u64,
+}
+
 #[derive(Queryable, Insertable, Serialize, Deserialize)]
 #[table_name = "blocks"]
 pub struct BlockRecord {
@@ -194,9 +444,10 @@ pub struct BlockRecord {
     pub gas_used: i64,
 }
 
-#[derive(Queryable, Insertable, Serialize, Deserialize)]
+#[derive(Queryable, QueryableByName, Insertable, Serialize, Deserialize)]
 #[table_name = "logs"]
 pub struct LogRecord {
+    #[serde(default)]
     pub id: i32,
     pub log_type: i32,
     pub block_number: i64,
@@ -206,6 +457,34 @@ pub struct LogRecord {
     pub data3: Option<String>,
     pub data4: Option<String>,
     pub data5: Option<String>,
+    pub transaction_hash: Option<String>,
+    #[serde(default)]
+    pub log_index: Option<i64>,
+    #[serde(default)]
+    pub transaction_index: Option<i64>,
+}
+
+impl LogRecord {
+    pub fn malformed(&self, reason: &str) -> String {
+        format!(
+            "Malformed log of type {} at block {}, log index {:?}: {}",
+            self.log_type, self.block_number, self.log_index, reason
+        )
+    }
+}
+
+#[derive(Queryable, Insertable, Serialize, Deserialize, Clone)]
+#[table_name = "transactions"]
+pub struct TransactionRecord {
+    pub id: i32,
+    pub transaction_hash: String,
+    pub block_number: i64,
+    pub transaction_index: i64,
+    pub from_address: String,
+    pub to_address: Option<String>,
+    pub gas_used: i64,
+    /// Wei per gas
+    pub effective_gas_price: i64,
 }
 
 #[derive(Queryable, Insertable, Serialize, Deserialize)]
@@ -224,4 +503,65 @@ pub struct PoolInfo {
     pub address: String,
     pub token0: String,
     pub token1: String,
+    pub factory: Option<String>,
+}
+
+// A token candle of the raw CSV processor, without the feature, context and label columns
+// its files have.
+#[derive(Queryable, Insertable, Serialize, Deserialize)]
+#[table_name = "candles"]
+pub struct CandleRecord {
+    pub token_address: String,
+    pub candlestick_len: i64,
+    pub open_block_number: i64,
+    pub close_block_number: i64,
+    pub token_symbol: String,
+    pub open_price: f64,
+    pub close_price: f64,
+    pub high_price: f64,
+    pub low_price: f64,
+    pub vwap: f64,
+    pub volume: f64,
+    pub buys_count: i64,
+    pub sells_count: i64,
+    pub buys_usd: f64,
+    pub sells_usd: f64,
+    pub buy_volume_share: f64,
+    pub realized_volatility: f64,
+    pub target_price: f64,
+    pub target_valid: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(log_type: i32, data1: &str) -> LogRecord {
+        LogRecord {
+            id: 0,
+            log_type,
+            block_number: 7,
+            address: "0x0000000000000000000000000000000000000001".to_owned(),
+            data1: Some(data1.to_owned()),
+            data2: Some("1".to_owned()),
+            data3: Some("2".to_owned()),
+            data4: Some("3".to_owned()),
+            data5: Some("4".to_owned()),
+            transaction_hash: None,
+            log_index: Some(3),
+            transaction_index: None,
+        }
+    }
+
+    #[test]
+    fn unknown_log_types_are_skipped() {
+        assert!(Event::from_record(&record(0, "")).is_none());
+        assert!(Event::from_record(&record(-1, "")).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "type 2 at block 7, log index Some(3): invalid sender")]
+    fn malformed_known_logs_panic() {
+        Event::from_record(&record(2, "not an address"));
+    }
 }
@@ -21,6 +21,9 @@ table! {
       data3 -> Nullable<Varchar>,
       data4 -> Nullable<Varchar>,
       data5 -> Nullable<Varchar>,
+      transaction_hash -> Nullable<Varchar>,
+      log_index -> Nullable<Int8>,
+      transaction_index -> Nullable<Int8>,
   }
 }
 
@@ -39,6 +42,7 @@ table! {
       address -> Varchar,
       token0 -> Varchar,
       token1 -> Varchar,
+      factory -> Nullable<Varchar>,
   }
 }
 
@@ -51,10 +55,46 @@ table! {
       token1_address -> Varchar,
       block_number -> Int8,
       address -> Varchar,
-      reserve0 -> Float8,
-      reserve1 -> Float8,
+      reserve0 -> Numeric,
+      reserve1 -> Numeric,
       token0_usd_price -> Float8,
       token1_usd_price -> Float8,
+      transaction_hash -> Nullable<Varchar>,
+      log_index -> Nullable<Int8>,
+      cause -> Nullable<Varchar>,
+  }
+}
+
+table! {
+  transactions (id) {
+      id -> Int4,
+      transaction_hash -> Varchar,
+      block_number -> Int8,
+      transaction_index -> Int8,
+      from_address -> Varchar,
+      to_address -> Nullable<Varchar>,
+      gas_used -> Int8,
+      effective_gas_price -> Int8,
+  }
+}
+
+table! {
+  trades (id) {
+      id -> Int4,
+      transaction_hash -> Varchar,
+      block_number -> Int8,
+      origin -> Nullable<Varchar>,
+      router -> Nullable<Varchar>,
+      token_in_symbol -> Varchar,
+      token_in_address -> Varchar,
+      token_out_symbol -> Varchar,
+      token_out_address -> Varchar,
+      amount_in -> Numeric,
+      amount_out -> Numeric,
+      hops -> Int4,
+      volume_usd -> Float8,
+      gas_used -> Nullable<Int8>,
+      effective_gas_price -> Nullable<Int8>,
   }
 }
 
@@ -68,12 +108,22 @@ table! {
       block_number -> Int8,
       address -> Varchar,
       sender -> Varchar,
-      amount0_in -> Float8,
-      amount0_out -> Float8,
-      amount1_in -> Float8,
-      amount1_out -> Float8,
+      amount0_in -> Numeric,
+      amount0_out -> Numeric,
+      amount1_in -> Numeric,
+      amount1_out -> Numeric,
       token0_usd_price -> Float8,
       token1_usd_price -> Float8,
+      transaction_hash -> Nullable<Varchar>,
+      origin -> Nullable<Varchar>,
+      log_index -> Nullable<Int8>,
+      base_address -> Varchar,
+      side -> Varchar,
+      base_amount -> Numeric,
+      quote_amount -> Numeric,
+      execution_price -> Float8,
+      notional_usd -> Float8,
+      price_impact -> Float8,
   }
 }
 
@@ -87,9 +137,78 @@ table! {
       block_number -> Int8,
       address -> Varchar,
       sender -> Varchar,
-      amount0 -> Float8,
-      amount1 -> Float8,
+      amount0 -> Numeric,
+      amount1 -> Numeric,
       token0_usd_price -> Float8,
       token1_usd_price -> Float8,
+      transaction_hash -> Nullable<Varchar>,
+      log_index -> Nullable<Int8>,
+      provider -> Nullable<Varchar>,
+  }
+}
+
+table! {
+  pool_states (address, block_number) {
+      address -> Varchar,
+      block_number -> Int8,
+      token0_symbol -> Varchar,
+      token1_symbol -> Varchar,
+      token0_address -> Varchar,
+      token1_address -> Varchar,
+      reserve0 -> Numeric,
+      reserve1 -> Numeric,
+      price0 -> Float8,
+      price1 -> Float8,
+      tvl_usd -> Float8,
+      k -> Numeric,
+  }
+}
+
+table! {
+  lp_positions (address, provider, block_number) {
+      block_number -> Int8,
+      address -> Varchar,
+      provider -> Varchar,
+      token0_symbol -> Varchar,
+      token1_symbol -> Varchar,
+      lp_balance -> Numeric,
+      lp_supply -> Numeric,
+      amount0 -> Float8,
+      amount1 -> Float8,
+      value_usd -> Float8,
+      hold_value_usd -> Float8,
+      fees_usd -> Float8,
+      impermanent_loss -> Float8,
+  }
+}
+
+table! {
+  processing_checkpoints (name) {
+      name -> Varchar,
+      block_number -> Int8,
+  }
+}
+
+table! {
+  candles (token_address, candlestick_len, open_block_number) {
+      token_address -> Varchar,
+      candlestick_len -> Int8,
+      open_block_number -> Int8,
+      close_block_number -> Int8,
+      token_symbol -> Varchar,
+      open_price -> Float8,
+      close_price -> Float8,
+      high_price -> Float8,
+      low_price -> Float8,
+      vwap -> Float8,
+      volume -> Float8,
+      buys_count -> Int8,
+      sells_count -> Int8,
+      buys_usd -> Float8,
+      sells_usd -> Float8,
+      buy_volume_share -> Float8,
+      realized_volatility -> Float8,
+      target_price -> Float8,
+      target_valid -> Bool,
   }
 }
@@ -1,15 +1,22 @@
-use crate::db::db::{establish_connection, insert_multiple_logs};
-use crate::db::models::LogRecord;
+use crate::archive::{event_type_name, ArchiveWriter};
+use crate::db::db::{
+    insert_multiple_logs, insert_multiple_transactions, load_pools, load_pools_by_address,
+    load_token_addresses,
+};
+use crate::db::models::{LogRecord, TransactionRecord};
 use async_recursion::async_recursion;
 use chrono::Utc;
 use diesel::PgConnection;
 use ethabi::{Contract, Event, RawLog, Token};
+use futures::future::join_all;
 use lazy_static::lazy_static;
+use std::collections::HashSet;
 use std::sync::Arc;
 use tokio::sync::Semaphore;
 use tokio::time::{sleep, Duration};
-use web3::types::{BlockNumber, Filter, FilterBuilder, Log, H256, U64};
-use web3::{transports::Http, Web3};
+use web3::transports::{Batch, Http};
+use web3::types::{Address, BlockNumber, Filter, FilterBuilder, Log, H256, U64};
+use web3::Web3;
 
 lazy_static! {
     static ref POOL_ABI: Contract = {
@@ -26,11 +33,19 @@ lazy_static! {
             ethabi::Contract::load(abi_content.as_bytes()).expect("Error parsing abi/factory.abi");
         contract
     };
+    static ref ERC20_ABI: Contract = {
+        let abi_content =
+            std::fs::read_to_string("abi/erc20.abi").expect("Unable to read abi/erc20.abi");
+        let contract =
+            ethabi::Contract::load(abi_content.as_bytes()).expect("Error parsing abi/erc20.abi");
+        contract
+    };
     static ref PAIR_CREATED_EVENT: Event = FACTORY_ABI.event("PairCreated").unwrap().clone();
     static ref SYNC_EVENT: Event = POOL_ABI.event("Sync").unwrap().clone();
     static ref SWAP_EVENT: Event = POOL_ABI.event("Swap").unwrap().clone();
     static ref MINT_EVENT: Event = POOL_ABI.event("Mint").unwrap().clone();
     static ref BURN_EVENT: Event = POOL_ABI.event("Burn").unwrap().clone();
+    static ref TRANSFER_EVENT: Event = ERC20_ABI.event("Transfer").unwrap().clone();
 }
 
 pub struct Opts {
@@ -38,24 +53,135 @@ pub struct Opts {
     pub to_block: u64,
     pub path: String,
     pub rpc: String,
+    pub pools: Vec<String>,
+    pub tokens: Vec<String>,
+    pub factory: Option<String>,
+    pub transfers: bool,
+    pub lp_transfers: bool,
+    pub receipts: bool,
+    pub archive_dir: Option<String>,
 }
 
+impl Opts {
+    fn is_scoped(&self) -> bool {
+        !self.pools.is_empty() || !self.tokens.is_empty() || self.factory.is_some()
+    }
+}
+
+const RECEIPTS_PER_BATCH: usize = 100;
+
+// Upper bound on addresses in a single eth_getLogs filter, most providers reject bigger ones.
+const ADDRESSES_PER_FILTER: usize = 1000;
+
 #[derive(Clone)]
 struct LocalFilter {
     topics: Vec<H256>,
+    addresses: Option<Vec<Address>>,
 }
 
 impl LocalFilter {
+    fn new(topics: Vec<H256>, addresses: Option<Vec<Address>>) -> Vec<LocalFilter> {
+        match addresses {
+            Some(addresses) => addresses
+                .chunks(ADDRESSES_PER_FILTER)
+                .map(|chunk| LocalFilter {
+                    topics: topics.clone(),
+                    addresses: Some(chunk.to_vec()),
+                })
+                .collect(),
+            None => vec![LocalFilter {
+                topics,
+                addresses: None,
+            }],
+        }
+    }
+
     fn get_filter(&self, from_block: u64, to_block: u64) -> Filter {
-        FilterBuilder::default()
+        let mut builder = FilterBuilder::default()
             .from_block(BlockNumber::Number(U64::from(from_block)))
             .to_block(BlockNumber::Number(U64::from(to_block)))
-            .topics(Some(self.topics.clone()), None, None, None)
-            .build()
+            .topics(Some(self.topics.clone()), None, None, None);
+
+        if let Some(addresses) = &self.addresses {
+            builder = builder.address(addresses.clone());
+        }
+
+        builder.build()
     }
 }
 
-fn convert_logs_to_records(logs: Vec<Log>) -> Vec<LogRecord> {
+fn parse_address(address: &str) -> Address {
+    address
+        .parse()
+        .unwrap_or_else(|_| panic!("Invalid address {}", address))
+}
+
+fn watchlist(conn: &PgConnection, opts: &Opts) -> Option<Vec<Address>> {
+    if !opts.is_scoped() {
+        return None;
+    }
+
+    let mut addresses: Vec<Address> = opts.pools.iter().map(|x| parse_address(x)).collect();
+
+    if !opts.tokens.is_empty() || opts.factory.is_some() {
+        let tokens: Vec<String> = opts
+            .tokens
+            .iter()
+            .map(|x| format!("{:?}", parse_address(x)))
+            .collect();
+        let factory = opts
+            .factory
+            .as_ref()
+            .map(|x| format!("{:?}", parse_address(x)));
+
+        for pool in load_pools(conn, &tokens, factory.as_deref()) {
+            addresses.push(parse_address(&pool.address));
+        }
+    }
+
+    addresses.sort();
+    addresses.dedup();
+
+    Some(addresses)
+}
+
+// `--factory`, or the factories of the watchlisted pools in the `pools` table.
+fn watchlist_factories(conn: &PgConnection, opts: &Opts, addresses: &[Address]) -> Vec<Address> {
+    if let Some(factory) = &opts.factory {
+        return vec![parse_address(factory)];
+    }
+
+    let addresses: Vec<String> = addresses.iter().map(|x| format!("{:?}", x)).collect();
+    let mut factories: Vec<Address> = load_pools_by_address(conn, &addresses)
+        .iter()
+        .filter_map(|x| x.factory.as_ref().map(|x| parse_address(x)))
+        .collect();
+    factories.sort();
+    factories.dedup();
+
+    factories
+}
+
+fn raw_event_type(log: &Log) -> &'static str {
+    let topic = match log.topics.first() {
+        Some(r) => *r,
+        None => return event_type_name(-1),
+    };
+
+    let log_type = match topic {
+        _ if topic == PAIR_CREATED_EVENT.signature() => 0,
+        _ if topic == SYNC_EVENT.signature() => 1,
+        _ if topic == SWAP_EVENT.signature() => 2,
+        _ if topic == MINT_EVENT.signature() => 3,
+        _ if topic == BURN_EVENT.signature() => 4,
+        _ if topic == TRANSFER_EVENT.signature() => 5,
+        _ => -1,
+    };
+
+    event_type_name(log_type)
+}
+
+fn convert_logs_to_records(logs: &[Log]) -> Vec<LogRecord> {
     let mut records = Vec::new();
 
     for log in logs {
@@ -88,6 +214,9 @@ fn convert_logs_to_records(logs: Vec<Log>) -> Vec<LogRecord> {
                         data3: Some(format!("{:?}", pool)),
                         data4: None,
                         data5: None,
+                        transaction_hash: log.transaction_hash.map(|x| format!("{:?}", x)),
+                        log_index: log.log_index.map(|x| x.as_u64() as i64),
+                        transaction_index: log.transaction_index.map(|x| x.as_u64() as i64),
                     });
                 }
             }
@@ -114,6 +243,9 @@ fn convert_logs_to_records(logs: Vec<Log>) -> Vec<LogRecord> {
                         data3: None,
                         data4: None,
                         data5: None,
+                        transaction_hash: log.transaction_hash.map(|x| format!("{:?}", x)),
+                        log_index: log.log_index.map(|x| x.as_u64() as i64),
+                        transaction_index: log.transaction_index.map(|x| x.as_u64() as i64),
                     });
                 }
             }
@@ -152,6 +284,9 @@ fn convert_logs_to_records(logs: Vec<Log>) -> Vec<LogRecord> {
                         data3: Some(format!("{:?}", amount1_in)),
                         data4: Some(format!("{:?}", amount0_out)),
                         data5: Some(format!("{:?}", amount1_out)),
+                        transaction_hash: log.transaction_hash.map(|x| format!("{:?}", x)),
+                        log_index: log.log_index.map(|x| x.as_u64() as i64),
+                        transaction_index: log.transaction_index.map(|x| x.as_u64() as i64),
                     });
                 }
             }
@@ -182,6 +317,9 @@ fn convert_logs_to_records(logs: Vec<Log>) -> Vec<LogRecord> {
                         data3: Some(format!("{:?}", amount1)),
                         data4: None,
                         data5: None,
+                        transaction_hash: log.transaction_hash.map(|x| format!("{:?}", x)),
+                        log_index: log.log_index.map(|x| x.as_u64() as i64),
+                        transaction_index: log.transaction_index.map(|x| x.as_u64() as i64),
                     });
                 }
             }
@@ -212,6 +350,42 @@ fn convert_logs_to_records(logs: Vec<Log>) -> Vec<LogRecord> {
                         data3: Some(format!("{:?}", amount1)),
                         data4: None,
                         data5: None,
+                        transaction_hash: log.transaction_hash.map(|x| format!("{:?}", x)),
+                        log_index: log.log_index.map(|x| x.as_u64() as i64),
+                        transaction_index: log.transaction_index.map(|x| x.as_u64() as i64),
+                    });
+                }
+            }
+            _ if topic == TRANSFER_EVENT.signature() => {
+                if let Ok(x) = TRANSFER_EVENT.parse_log(RawLog {
+                    topics: log.topics.clone(),
+                    data: log.data.0.clone(),
+                }) {
+                    let from = match x.params[0].value.clone() {
+                        Token::Address(x) => x,
+                        _ => continue,
+                    };
+                    let to = match x.params[1].value.clone() {
+                        Token::Address(x) => x,
+                        _ => continue,
+                    };
+                    let value = match x.params[2].value.clone() {
+                        Token::Uint(x) => x,
+                        _ => continue,
+                    };
+                    records.push(LogRecord {
+                        id: 0,
+                        log_type: 5,
+                        block_number: log.block_number.unwrap().as_u64() as i64,
+                        address: format!("{:?}", log.address),
+                        data1: Some(format!("{:?}", from)),
+                        data2: Some(format!("{:?}", to)),
+                        data3: Some(format!("{:?}", value)),
+                        data4: None,
+                        data5: None,
+                        transaction_hash: log.transaction_hash.map(|x| format!("{:?}", x)),
+                        log_index: log.log_index.map(|x| x.as_u64() as i64),
+                        transaction_index: log.transaction_index.map(|x| x.as_u64() as i64),
                     });
                 }
             }
@@ -229,7 +403,7 @@ async fn get_logs(
     to_block: u64,
     filter: LocalFilter,
     semaphore: Arc<Semaphore>,
-) -> Vec<LogRecord> {
+) -> Vec<Log> {
     if to_block - from_block > 1000 {
         let mid = (from_block + to_block) / 2;
 
@@ -263,7 +437,7 @@ async fn get_logs(
         drop(permit);
 
         match res {
-            Ok(x) => return convert_logs_to_records(x),
+            Ok(x) => return x,
             Err(x) => println!(
                 "Error doing from block {} to {}: {:?}",
                 from_block, to_block, x
@@ -298,20 +472,161 @@ async fn get_logs(
     }
 }
 
+// Receipts whose request failed inside the batch are retried until the node answers them.
+async fn get_receipts_batch(
+    http: Http,
+    mut hashes: Vec<H256>,
+    semaphore: Arc<Semaphore>,
+) -> Vec<TransactionRecord> {
+    let mut records = Vec::with_capacity(hashes.len());
+
+    while !hashes.is_empty() {
+        let permit = semaphore.acquire().await.unwrap();
+        let batch = Batch::new(http.clone());
+        let web3 = Web3::new(batch.clone());
+
+        let requests: Vec<_> = hashes
+            .iter()
+            .map(|hash| web3.eth().transaction_receipt(*hash))
+            .collect();
+        let submitted = batch.submit_batch().await;
+        let responses = join_all(requests).await;
+        drop(permit);
+
+        if let Err(x) = submitted {
+            println!("Error fetching {} receipts: {:?}", hashes.len(), x);
+            sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+
+        let mut failed = Vec::new();
+        let mut missing = 0;
+        for (hash, response) in hashes.iter().zip(responses) {
+            match response {
+                Ok(Some(receipt)) => records.push(TransactionRecord {
+                    id: 0,
+                    transaction_hash: format!("{:?}", receipt.transaction_hash),
+                    block_number: receipt.block_number.unwrap_or_default().as_u64() as i64,
+                    transaction_index: receipt.transaction_index.as_u64() as i64,
+                    from_address: format!("{:?}", receipt.from),
+                    to_address: receipt.to.map(|x| format!("{:?}", x)),
+                    gas_used: receipt.gas_used.unwrap_or_default().as_u64() as i64,
+                    effective_gas_price: receipt
+                        .effective_gas_price
+                        .map_or(0, |gas| gas.as_u64() as i64),
+                }),
+                Ok(None) => missing += 1,
+                Err(_) => failed.push(*hash),
+            }
+        }
+
+        if missing > 0 {
+            println!("No receipt for {} transactions, skipping them", missing);
+        }
+        if !failed.is_empty() {
+            println!("Error fetching {} receipts, retrying", failed.len());
+            sleep(Duration::from_secs(1)).await;
+        }
+
+        hashes = failed;
+    }
+
+    records
+}
+
+async fn get_receipts(http: Http, logs: &[LogRecord]) -> Vec<TransactionRecord> {
+    let mut hashes = HashSet::new();
+    for log in logs {
+        if log.log_type != 2 {
+            continue;
+        }
+
+        if let Some(hash) = &log.transaction_hash {
+            hashes.insert(hash.parse::<H256>().expect("Invalid transaction hash"));
+        }
+    }
+
+    let mut hashes: Vec<H256> = hashes.into_iter().collect();
+    hashes.sort();
+
+    let semaphore = Arc::new(Semaphore::new(20));
+    let batches = hashes
+        .chunks(RECEIPTS_PER_BATCH)
+        .map(|chunk| get_receipts_batch(http.clone(), chunk.to_vec(), semaphore.clone()));
+
+    join_all(batches).await.into_iter().flatten().collect()
+}
+
 pub async fn collect(conn: &PgConnection, opts: Opts) {
     let http = Http::new(&opts.rpc).expect("Can not create http");
-    let web3 = Web3::new(http);
-
-    let local_filter = LocalFilter {
-        topics: vec![
-            PAIR_CREATED_EVENT.signature(),
-            SYNC_EVENT.signature(),
-            SWAP_EVENT.signature(),
-            MINT_EVENT.signature(),
-            BURN_EVENT.signature(),
-        ],
+    let web3 = Web3::new(http.clone());
+
+    let addresses = watchlist(conn, &opts);
+    if let Some(addresses) = &addresses {
+        println!("Collection is scoped to {} pools", addresses.len());
+        if addresses.is_empty() {
+            return;
+        }
+    }
+
+    let pool_topics = vec![
+        SYNC_EVENT.signature(),
+        SWAP_EVENT.signature(),
+        MINT_EVENT.signature(),
+        BURN_EVENT.signature(),
+    ];
+    let mut local_filters = match &addresses {
+        Some(addresses) => {
+            let mut local_filters = LocalFilter::new(pool_topics, Some(addresses.clone()));
+
+            // PairCreated is emitted by the factory, not the pairs.
+            let factories = watchlist_factories(conn, &opts, addresses);
+            if factories.is_empty() {
+                println!("No factory known for the watchlist, PairCreated is not collected");
+            } else {
+                println!("Collecting PairCreated of {} factories", factories.len());
+                local_filters.extend(LocalFilter::new(
+                    vec![PAIR_CREATED_EVENT.signature()],
+                    Some(factories),
+                ));
+            }
+
+            local_filters
+        }
+        None => LocalFilter::new(
+            [vec![PAIR_CREATED_EVENT.signature()], pool_topics].concat(),
+            None,
+        ),
     };
 
+    if opts.transfers {
+        let tokens: Vec<Address> = load_token_addresses(conn)
+            .iter()
+            .map(|x| parse_address(x))
+            .collect();
+        println!("Collecting transfers of {} tokens", tokens.len());
+
+        local_filters.extend(LocalFilter::new(
+            vec![TRANSFER_EVENT.signature()],
+            Some(tokens),
+        ));
+    }
+
+    if opts.lp_transfers {
+        // LP token transfers are emitted by the pairs themselves.
+        let pools = addresses
+            .clone()
+            .expect("--lp-transfers needs --pools, --tokens or --factory");
+        println!("Collecting LP transfers of {} pools", pools.len());
+
+        local_filters.extend(LocalFilter::new(
+            vec![TRANSFER_EVENT.signature()],
+            Some(pools),
+        ));
+    }
+
+    let mut archive = opts.archive_dir.as_ref().map(|dir| ArchiveWriter::new(dir));
+
     let amount_block_one_iter = 50000;
     let iters = (opts.to_block - opts.from_block - 1) / amount_block_one_iter + 1;
 
@@ -328,14 +643,31 @@ pub async fn collect(conn: &PgConnection, opts: Opts) {
         }
         let semaphore = Arc::new(Semaphore::new(200));
 
-        let logs = get_logs(
-            web3.clone(),
-            from_block,
-            to_block,
-            local_filter.clone(),
-            semaphore,
-        )
-        .await;
+        let mut raw_logs = Vec::new();
+        for local_filter in &local_filters {
+            raw_logs.extend(
+                get_logs(
+                    web3.clone(),
+                    from_block,
+                    to_block,
+                    local_filter.clone(),
+                    semaphore.clone(),
+                )
+                .await,
+            );
+        }
+
+        let logs = convert_logs_to_records(&raw_logs);
+
+        if let Some(archive) = &mut archive {
+            archive.write_raw(from_block, to_block, &raw_logs, raw_event_type);
+            archive.write_decoded(from_block, to_block, &logs);
+        }
+
+        if opts.receipts {
+            let receipts = get_receipts(http.clone(), &logs).await;
+            insert_multiple_transactions(conn, receipts);
+        }
 
         insert_multiple_logs(conn, logs);
 
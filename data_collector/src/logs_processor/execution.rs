@@ -0,0 +1,95 @@
+use crate::db::models::{SwapEvent, Token};
+use crate::numeric;
+use bigdecimal::BigDecimal;
+use num_traits::Zero;
+use web3::types::U256;
+
+pub const BUY: &str = "buy";
+pub const SELL: &str = "sell";
+
+// A swap seen from the trader's side of a base/quote pair: "buy" means the trader received base.
+pub struct Execution {
+    pub side: &'static str,
+    pub base_amount: BigDecimal,
+    pub quote_amount: BigDecimal,
+    pub execution_price: f64,
+    pub price_impact: f64,
+}
+
+impl Execution {
+    // Prices are quote per base. `reserves` are the pool reserves right before the swap,
+    // the price impact is the execution price relative to their spot price.
+    // Everything is computed on the raw amounts, the prices are rounded to f64 once.
+    pub fn new(
+        event: &SwapEvent,
+        token0: &Token,
+        token1: &Token,
+        quote_is_token0: bool,
+        reserves: Option<(U256, U256)>,
+    ) -> Self {
+        let (base, quote) = if quote_is_token0 {
+            (token1, token0)
+        } else {
+            (token0, token1)
+        };
+        let (base_in, base_out, quote_in, quote_out) = if quote_is_token0 {
+            (
+                event.amount1_in,
+                event.amount1_out,
+                event.amount0_in,
+                event.amount0_out,
+            )
+        } else {
+            (
+                event.amount0_in,
+                event.amount0_out,
+                event.amount1_in,
+                event.amount1_out,
+            )
+        };
+
+        // Flash swaps can move a token both ways, only the net amounts count.
+        let bought = base_out > base_in;
+        let base_amount = abs_diff(base_out, base_in);
+        let quote_amount = abs_diff(quote_out, quote_in);
+
+        // Execution over spot price is quote_amount * base_reserve / (base_amount * quote_reserve),
+        // the decimals cancel out.
+        let price_impact = reserves.map_or(0.0, |(reserve0, reserve1)| {
+            let (base_reserve, quote_reserve) = if quote_is_token0 {
+                (reserve1, reserve0)
+            } else {
+                (reserve0, reserve1)
+            };
+
+            let numerator = numeric::to_biguint(quote_amount) * numeric::to_biguint(base_reserve);
+            let denominator = numeric::to_biguint(base_amount) * numeric::to_biguint(quote_reserve);
+            if numerator.is_zero() || denominator.is_zero() {
+                0.0
+            } else {
+                numeric::ratio(&numerator, 0, &denominator, 0) - 1.0
+            }
+        });
+
+        Execution {
+            side: if bought { BUY } else { SELL },
+            base_amount: numeric::to_decimal(base_amount, base.decimals),
+            quote_amount: numeric::to_decimal(quote_amount, quote.decimals),
+            execution_price: numeric::u256_ratio(
+                quote_amount,
+                quote.decimals,
+                base_amount,
+                base.decimals,
+            ),
+            price_impact,
+        }
+    }
+}
+
+fn abs_diff(a: U256, b: U256) -> U256 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
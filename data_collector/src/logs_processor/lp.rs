@@ -0,0 +1,300 @@
+use super::normalize;
+use super::price_agregator::PriceAgregator;
+use crate::db::models::{BurnEvent, LpPosition, MintEvent, Token, TransferEvent};
+use crate::numeric;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use web3::types::{Address, H256, U256};
+
+const LP_DECIMALS: u64 = 18;
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct Position {
+    balance: U256,
+    // Token amounts deposited by Mints, reduced pro rata when LP tokens leave the position.
+    deposited0: f64,
+    deposited1: f64,
+    // sqrt(k) per LP token when the position was entered, weighted by the LP tokens it got.
+    entry_growth: f64,
+}
+
+impl Position {
+    // Takes `value` LP tokens out of the position along with their share of the deposits.
+    fn split(&mut self, value: U256) -> Position {
+        let value = U256::min(value, self.balance);
+        let fraction = if self.balance.is_zero() {
+            0.0
+        } else {
+            normalize(value, LP_DECIMALS) / normalize(self.balance, LP_DECIMALS)
+        };
+
+        let part = Position {
+            balance: value,
+            deposited0: self.deposited0 * fraction,
+            deposited1: self.deposited1 * fraction,
+            entry_growth: self.entry_growth,
+        };
+
+        self.balance -= value;
+        self.deposited0 -= part.deposited0;
+        self.deposited1 -= part.deposited1;
+
+        part
+    }
+
+    fn merge(&mut self, part: Position) {
+        let balance = normalize(self.balance, LP_DECIMALS);
+        let part_balance = normalize(part.balance, LP_DECIMALS);
+        if balance + part_balance > 0.0 {
+            self.entry_growth = (self.entry_growth * balance + part.entry_growth * part_balance)
+                / (balance + part_balance);
+        }
+
+        self.balance += part.balance;
+        self.deposited0 += part.deposited0;
+        self.deposited1 += part.deposited1;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct PoolBook {
+    token0: Token,
+    token1: Token,
+    supply: U256,
+    positions: HashMap<Address, Position>,
+    // Recipient and amount of the last LP tokens minted, the Mint event that follows credits them.
+    last_mint: Option<(Option<H256>, Address, U256)>,
+    // The last address that sent LP tokens to the pair, a Burn that follows is theirs.
+    last_sent_to_pair: Option<(Option<H256>, Address)>,
+}
+
+// LP positions of every pool, built from the pair's own Transfer events.
+// Mint/Burn only name the router, the provider is the LP token recipient/sender of the same transaction.
+// Deposits are only known for liquidity added after collection started, older positions
+// report a zero hold value and no impermanent loss.
+// Serialisable, so it can be restored from a snapshot together with the price aggregator.
+#[derive(Serialize, Deserialize)]
+pub struct LpBook {
+    pools: HashMap<Address, PoolBook>,
+    changed: BTreeSet<(Address, Address)>,
+}
+
+impl LpBook {
+    pub fn new() -> Self {
+        LpBook {
+            pools: HashMap::new(),
+            changed: BTreeSet::new(),
+        }
+    }
+
+    fn pool(&mut self, address: Address, token0: &Token, token1: &Token) -> &mut PoolBook {
+        self.pools.entry(address).or_insert_with(|| PoolBook {
+            token0: token0.clone(),
+            token1: token1.clone(),
+            supply: U256::zero(),
+            positions: HashMap::new(),
+            last_mint: None,
+            last_sent_to_pair: None,
+        })
+    }
+
+    pub fn handle_transfer(&mut self, token0: &Token, token1: &Token, event: &TransferEvent) {
+        let pool = self.pool(event.address, token0, token1);
+
+        let part = if event.from == Address::zero() {
+            pool.supply = pool.supply.saturating_add(event.value);
+            pool.last_mint = Some((event.transaction_hash, event.to, event.value));
+            Position {
+                balance: event.value,
+                ..Default::default()
+            }
+        } else {
+            pool.positions
+                .entry(event.from)
+                .or_default()
+                .split(event.value)
+        };
+
+        if event.to == Address::zero() {
+            // The first mint locks MINIMUM_LIQUIDITY at the zero address, it stays in the supply.
+            if event.from != Address::zero() {
+                pool.supply = pool.supply.saturating_sub(part.balance);
+            }
+        } else {
+            if event.to == event.address {
+                pool.last_sent_to_pair = Some((event.transaction_hash, event.from));
+            }
+
+            pool.positions.entry(event.to).or_default().merge(part);
+        }
+
+        for holder in [event.from, event.to] {
+            if holder != Address::zero() && holder != event.address {
+                self.changed.insert((event.address, holder));
+            }
+        }
+    }
+
+    // `reserves` are the pool reserves right before the Mint, returns the provider.
+    pub fn handle_mint(
+        &mut self,
+        token0: &Token,
+        token1: &Token,
+        event: &MintEvent,
+        reserves: Option<(U256, U256)>,
+    ) -> Option<Address> {
+        let pool = self.pool(event.address, token0, token1);
+
+        let (provider, liquidity) = match pool.last_mint.take() {
+            Some((hash, provider, liquidity)) if hash == event.transaction_hash => {
+                (provider, liquidity)
+            }
+            _ => return None,
+        };
+
+        let amount0 = normalize(event.amount0, token0.decimals);
+        let amount1 = normalize(event.amount1, token1.decimals);
+        let (reserve0, reserve1) = reserves.map_or((0.0, 0.0), |(reserve0, reserve1)| {
+            (
+                normalize(reserve0, token0.decimals),
+                normalize(reserve1, token1.decimals),
+            )
+        });
+        let supply = normalize(pool.supply, LP_DECIMALS);
+
+        let position = pool.positions.entry(provider).or_default();
+        let minted = normalize(liquidity, LP_DECIMALS);
+        let balance = normalize(position.balance, LP_DECIMALS);
+        let growth = if supply > 0.0 {
+            ((reserve0 + amount0) * (reserve1 + amount1)).sqrt() / supply
+        } else {
+            0.0
+        };
+
+        // The minted tokens were merged with a zero entry growth by the Transfer, reweight them.
+        if balance > 0.0 {
+            position.entry_growth += growth * minted / balance;
+        }
+        position.deposited0 += amount0;
+        position.deposited1 += amount1;
+
+        Some(provider)
+    }
+
+    pub fn handle_burn(&mut self, event: &BurnEvent) -> Option<Address> {
+        let pool = self.pools.get_mut(&event.address)?;
+
+        match pool.last_sent_to_pair.take() {
+            Some((hash, provider)) if hash == event.transaction_hash => Some(provider),
+            _ => None,
+        }
+    }
+
+    // Valuations of the positions changed since the last call, with the prices as of `block_number`.
+    pub fn take_changed(
+        &mut self,
+        price_agregator: &PriceAgregator,
+        block_number: u64,
+    ) -> Vec<LpPosition> {
+        let changed = std::mem::take(&mut self.changed);
+
+        changed
+            .into_iter()
+            .filter_map(|(pool, provider)| {
+                self.valuation(price_agregator, &pool, &provider, block_number)
+            })
+            .collect()
+    }
+
+    // Valuations of every open position, and of the ones closed since the last call.
+    pub fn take_all(
+        &mut self,
+        price_agregator: &PriceAgregator,
+        block_number: u64,
+    ) -> Vec<LpPosition> {
+        let mut keys = std::mem::take(&mut self.changed);
+        for (pool, book) in &self.pools {
+            for (holder, position) in &book.positions {
+                if !position.balance.is_zero() && holder != pool && !holder.is_zero() {
+                    keys.insert((*pool, *holder));
+                }
+            }
+        }
+
+        keys.into_iter()
+            .filter_map(|(pool, provider)| {
+                self.valuation(price_agregator, &pool, &provider, block_number)
+            })
+            .collect()
+    }
+
+    fn valuation(
+        &self,
+        price_agregator: &PriceAgregator,
+        pool_address: &Address,
+        provider: &Address,
+        block_number: u64,
+    ) -> Option<LpPosition> {
+        let pool = self.pools.get(pool_address)?;
+        let position = pool.positions.get(provider).cloned().unwrap_or_default();
+
+        let (raw_reserve0, raw_reserve1) = price_agregator.pool_reserves(pool_address)?;
+        let reserve0 = normalize(raw_reserve0, pool.token0.decimals);
+        let reserve1 = normalize(raw_reserve1, pool.token1.decimals);
+        let price0 = price_agregator.token_usd_price(&pool.token0);
+        let price1 = price_agregator.token_usd_price(&pool.token1);
+
+        let supply = normalize(pool.supply, LP_DECIMALS);
+
+        // The position's share of the reserves, reserve * balance / supply on the raw amounts.
+        let balance = numeric::to_biguint(position.balance);
+        let raw_supply = numeric::to_biguint(pool.supply);
+        let amount0 = numeric::ratio(
+            &(numeric::to_biguint(raw_reserve0) * &balance),
+            pool.token0.decimals,
+            &raw_supply,
+            0,
+        );
+        let amount1 = numeric::ratio(
+            &(numeric::to_biguint(raw_reserve1) * &balance),
+            pool.token1.decimals,
+            &raw_supply,
+            0,
+        );
+        let value_usd = amount0 * price0 + amount1 * price1;
+        let hold_value_usd = position.deposited0 * price0 + position.deposited1 * price1;
+
+        // Fees grow sqrt(k) per LP token, what is left after taking them out is the price effect.
+        let growth = if supply > 0.0 {
+            (reserve0 * reserve1).sqrt() / supply
+        } else {
+            0.0
+        };
+        let fees_usd = if growth > 0.0 && position.entry_growth > 0.0 {
+            value_usd * (1.0 - position.entry_growth / growth)
+        } else {
+            0.0
+        };
+        let impermanent_loss = if hold_value_usd > 0.0 {
+            (value_usd - fees_usd) / hold_value_usd - 1.0
+        } else {
+            0.0
+        };
+
+        Some(LpPosition {
+            block_number: block_number as i64,
+            address: format!("{:?}", pool_address),
+            provider: format!("{:?}", provider),
+            token0_symbol: pool.token0.symbol.clone(),
+            token1_symbol: pool.token1.symbol.clone(),
+            lp_balance: numeric::to_decimal(position.balance, LP_DECIMALS),
+            lp_supply: numeric::to_decimal(pool.supply, LP_DECIMALS),
+            amount0,
+            amount1,
+            value_usd,
+            hold_value_usd,
+            fees_usd,
+            impermanent_loss,
+        })
+    }
+}
@@ -1,47 +1,67 @@
+mod execution;
+mod lp;
+mod pipeline;
 mod price_agregator;
+pub mod sink;
+mod snapshot;
+pub mod source;
+mod trades;
+mod writer;
+
+pub use execution::BUY;
 
 use crate::db::models::{
-    parse_event, CEXData, CEXRecord, Event, LiquidityTick, PoolInfo, SwapTick, SyncTick, Token,
+    CEXData, Event, LiquidityTick, PoolInfo, SwapTick, SyncEvent, SyncTick, Token,
+    TransactionRecord,
 };
-use crate::{utils, LogsProcessorArgs};
+use crate::{numeric, LogsProcessorArgs};
 use diesel::prelude::*;
 use diesel::PgConnection;
 use std::collections::HashMap;
-use std::io::{BufReader, Read};
-use std::path::Path;
-use std::{fs::File, io::BufRead};
+use std::sync::Arc;
 use web3::contract::Contract;
 use web3::contract::Options;
 use web3::transports::Http;
 use web3::types::Address;
+use web3::types::H256;
 use web3::types::U256;
 use web3::Web3;
 
 pub struct LogsProcessor {
     rpc: String,
+    db_url: String,
     cex_data: Vec<CEXData>,
     pools: HashMap<Address, PoolInfo>,
-    logs_path: String,
+    source: source::EventSourceKind,
+    logs_path: Option<String>,
+    archive_dir: Option<String>,
+    from_block: Option<u64>,
+    to_block: Option<u64>,
+    blocks_per_commit: u64,
+    sinks: Vec<sink::SinkKind>,
+    output_dir: String,
+    snapshot_dir: Option<String>,
 }
 
 impl LogsProcessor {
-    pub fn new(conn: &PgConnection, args: LogsProcessorArgs) -> Self {
+    pub fn new(conn: &PgConnection, db_url: &str, args: LogsProcessorArgs) -> Self {
         LogsProcessor {
             rpc: args.rpc,
-            cex_data: LogsProcessor::read_cex_data_db(conn),
+            db_url: db_url.to_owned(),
+            cex_data: read_cex_data_db(conn),
             pools: LogsProcessor::read_pools_db(conn),
+            source: args.source,
             logs_path: args.logs_path,
+            archive_dir: args.archive_dir,
+            from_block: args.from_block,
+            to_block: args.to_block,
+            blocks_per_commit: args.blocks_per_commit,
+            sinks: args.sinks,
+            output_dir: args.output_dir,
+            snapshot_dir: args.snapshot_dir,
         }
     }
 
-    fn read_cex_data_db(conn: &PgConnection) -> Vec<CEXData> {
-        use crate::db::schema::cex_data::dsl::*;
-        cex_data
-            .filter(platform_slug.eq("ethereum"))
-            .load::<CEXData>(conn)
-            .expect("Error loading CEX data from database")
-    }
-
     fn read_pools_db(conn: &PgConnection) -> HashMap<Address, PoolInfo> {
         use crate::db::schema::pools::dsl::*;
         let pool_infos = pools
@@ -57,27 +77,8 @@ impl LogsProcessor {
             .collect()
     }
 
-    pub async fn save_to_db(&self, conn: &PgConnection) {
-        let mut token_address_to_token = HashMap::new();
-        for cex_record in &self.cex_data {
-            if token_address_to_token.contains_key(&cex_record.token_address) {
-                continue;
-            }
-
-            if let Some(decimals) = self
-                .get_decimals(cex_record.token_address.parse().unwrap())
-                .await
-            {
-                token_address_to_token.insert(
-                    cex_record.token_address.clone(),
-                    Token {
-                        symbol: cex_record.symbol.clone(),
-                        address: cex_record.token_address.parse().unwrap(),
-                        decimals: decimals,
-                    },
-                );
-            }
-        }
+    pub async fn process(&self, conn: &PgConnection) {
+        let token_address_to_token = build_token_registry(&self.rpc, &self.cex_data).await;
 
         println!("[CEX data handled]");
 
@@ -129,159 +130,808 @@ impl LogsProcessor {
                 .unwrap(),
         ];
 
-        let mut price_agregator =
-            price_agregator::PriceAgregator::new(usd_token_addresses, decent_tokens);
-
-        let file = File::open(Path::new(&self.logs_path)).expect("invalid logs csv path");
-        let reader = BufReader::new(file);
-
-        for line in reader.lines() {
-            match line {
-                Ok(content) => {
-                    let args = content.split(',').map(|s| s.to_string()).collect();
-                    if let Some(event) = parse_event(args) {
-                        match event {
-                            Event::Sync(event) => {
-                                if let Some((token0, token1)) =
-                                    pool_address_to_tokens.get(&event.address)
-                                {
-                                    price_agregator.handle_sync(token0, token1, &event);
-
-                                    let record = SyncTick {
-                                        token0_symbol: token0.symbol.clone(),
-                                        token1_symbol: token1.symbol.clone(),
-                                        token0_address: format!("{:?}", token0.address),
-                                        token1_address: format!("{:?}", token1.address),
-                                        block_number: event.block_number as i64,
-                                        address: format!("{:?}", event.address),
-                                        reserve0: normalize(event.reserve0, token0.decimals),
-                                        reserve1: normalize(event.reserve1, token1.decimals),
-                                        token0_usd_price: price_agregator.token_usd_price(token0),
-                                        token1_usd_price: price_agregator.token_usd_price(token1),
-                                    };
-
-                                    diesel::insert_into(crate::db::schema::sync_ticks::table)
-                                        .values(&record)
-                                        .execute(conn)
-                                        .expect("Error saving sync log to database");
-                                }
-                            }
-
-                            Event::Swap(event) => {
-                                if let Some((token0, token1)) =
-                                    pool_address_to_tokens.get(&event.address)
-                                {
-                                    let record = SwapTick {
-                                        token0_symbol: token0.symbol.clone(),
-                                        token1_symbol: token1.symbol.clone(),
-                                        token0_address: format!("{:?}", token0.address),
-                                        token1_address: format!("{:?}", token1.address),
-                                        block_number: event.block_number as i64,
-                                        address: format!("{:?}", event.address),
-                                        sender: format!("{:?}", event.sender),
-                                        amount0_in: normalize(event.amount0_in, token0.decimals),
-                                        amount0_out: normalize(event.amount0_out, token0.decimals),
-                                        amount1_in: normalize(event.amount1_in, token1.decimals),
-                                        amount1_out: normalize(event.amount1_out, token1.decimals),
-                                        token0_usd_price: price_agregator.token_usd_price(token0),
-                                        token1_usd_price: price_agregator.token_usd_price(token1),
-                                    };
-
-                                    diesel::insert_into(crate::db::schema::swap_ticks::table)
-                                        .values(&record)
-                                        .execute(conn)
-                                        .expect("Error saving swap log to database");
-                                }
-                            }
-
-                            Event::Mint(event) => {
-                                if let Some((token0, token1)) =
-                                    pool_address_to_tokens.get(&event.address)
-                                {
-                                    let record = LiquidityTick {
-                                        token0_symbol: token0.symbol.clone(),
-                                        token1_symbol: token1.symbol.clone(),
-                                        token0_address: format!("{:?}", token0.address),
-                                        token1_address: format!("{:?}", token1.address),
-                                        block_number: event.block_number as i64,
-                                        address: format!("{:?}", event.address),
-                                        sender: format!("{:?}", event.sender),
-                                        amount0: normalize(event.amount0, token0.decimals),
-                                        amount1: normalize(event.amount1, token1.decimals),
-                                        token0_usd_price: price_agregator.token_usd_price(token0),
-                                        token1_usd_price: price_agregator.token_usd_price(token1),
-                                    };
-
-                                    diesel::insert_into(crate::db::schema::liquidity_ticks::table)
-                                        .values(&record)
-                                        .execute(conn)
-                                        .expect("Error saving mint log to database");
-                                }
-                            }
-
-                            Event::Burn(event) => {
-                                if let Some((token0, token1)) =
-                                    pool_address_to_tokens.get(&event.address)
-                                {
-                                    let record = LiquidityTick {
-                                        token0_symbol: token0.symbol.clone(),
-                                        token1_symbol: token1.symbol.clone(),
-                                        token0_address: format!("{:?}", token0.address),
-                                        token1_address: format!("{:?}", token1.address),
-                                        block_number: event.block_number as i64,
-                                        address: format!("{:?}", event.address),
-                                        sender: format!("{:?}", event.sender),
-                                        amount0: -normalize(event.amount0, token0.decimals),
-                                        amount1: -normalize(event.amount1, token1.decimals),
-                                        token0_usd_price: price_agregator.token_usd_price(token0),
-                                        token1_usd_price: price_agregator.token_usd_price(token1),
-                                    };
-
-                                    diesel::insert_into(crate::db::schema::liquidity_ticks::table)
-                                        .values(&record)
-                                        .execute(conn)
-                                        .expect("Error saving burn log to database");
-                                }
-                            }
-                        };
+        let state = State {
+            price_agregator: price_agregator::PriceAgregator::new(
+                usd_token_addresses,
+                decent_tokens,
+            ),
+            lp_book: lp::LpBook::new(),
+        };
+
+        // The checkpoint lives in the database, without the db sink the latest snapshot stands in for it.
+        let checkpoint = if self.sinks.contains(&sink::SinkKind::Db) {
+            sink::load_checkpoint(conn)
+        } else {
+            self.snapshot_dir
+                .as_deref()
+                .and_then(snapshot::latest_block)
+        };
+
+        if let Some(block_number) = checkpoint {
+            println!("Resuming after checkpoint at block {}", block_number);
+        }
+        let (state, from_block) = restore(
+            self.snapshot_dir.as_deref(),
+            checkpoint,
+            state,
+            self.from_block,
+        );
+
+        let metrics = Arc::new(pipeline::Metrics::new());
+        let writer = writer::TickWriter::new(
+            &self.sinks,
+            &self.output_dir,
+            &self.db_url,
+            self.blocks_per_commit,
+            checkpoint,
+            metrics.clone(),
+        );
+
+        // Decoding runs ahead on other threads, events are still handled one by one in log order.
+        let (source_kind, db_url, logs_path, archive_dir, to_block) = (
+            self.source,
+            self.db_url.clone(),
+            self.logs_path.clone(),
+            self.archive_dir.clone(),
+            self.to_block,
+        );
+        let events = pipeline::decode(
+            move || {
+                source::open(
+                    source_kind,
+                    &db_url,
+                    logs_path.as_deref(),
+                    archive_dir.as_deref(),
+                    source::BlockRange {
+                        from_block,
+                        to_block,
+                    },
+                )
+            },
+            metrics.clone(),
+        );
+
+        let mut transactions = trades::TransactionsCache::new();
+        handle_events(
+            events,
+            &pool_address_to_tokens,
+            state,
+            writer,
+            &metrics,
+            self.snapshot_dir.as_deref(),
+            |block_number, hash| transactions.get(conn, block_number, hash).cloned(),
+        );
+        metrics.report();
+
+        println!("[Events handled]");
+    }
+}
+
+// What a run carries from one block to the next, a snapshot restores it on resume.
+struct State {
+    price_agregator: price_agregator::PriceAgregator,
+    lp_book: lp::LpBook,
+}
+
+// Events up to the latest snapshot at or before the checkpoint are skipped, the ones after it
+// up to the checkpoint are replayed. Returns the state to start from and the first block to read.
+fn restore(
+    snapshot_dir: Option<&str>,
+    checkpoint: Option<u64>,
+    state: State,
+    from_block: Option<u64>,
+) -> (State, Option<u64>) {
+    let snapshot = match (snapshot_dir, checkpoint) {
+        (Some(dir), Some(block_number)) => snapshot::latest(dir, Some(block_number)),
+        _ => None,
+    };
+
+    match snapshot {
+        Some(snapshot) => {
+            println!("Restored snapshot at block {}", snapshot.block_number);
+
+            let state = State {
+                price_agregator: snapshot.price_agregator,
+                lp_book: snapshot.lp_book,
+            };
+            let from_block = u64::max(from_block.unwrap_or(0), snapshot.block_number + 1);
+            (state, Some(from_block))
+        }
+        None => (state, from_block),
+    }
+}
+
+// Prices the events in log order and hands their ticks to the writer, committing at the end.
+// `transaction` looks up the transaction a swap was sent in.
+fn handle_events(
+    events: impl Iterator<Item = Event>,
+    pool_address_to_tokens: &HashMap<&Address, (&Token, &Token)>,
+    state: State,
+    mut writer: writer::TickWriter,
+    metrics: &pipeline::Metrics,
+    snapshot_dir: Option<&str>,
+    mut transaction: impl FnMut(u64, &H256) -> Option<TransactionRecord>,
+) {
+    let State {
+        mut price_agregator,
+        mut lp_book,
+    } = state;
+    let mut trade: Option<trades::TradeBuilder> = None;
+    let mut current_block = None;
+    let mut pending_sync: Option<SyncEvent> = None;
+
+    for event in events {
+        if current_block != Some(event.block_number()) {
+            if let Some(sync) = pending_sync.take() {
+                apply_sync(
+                    &mut price_agregator,
+                    &mut writer,
+                    pool_address_to_tokens,
+                    &sync,
+                    None,
+                );
+            }
+
+            if let Some(t) = trade.take() {
+                save_trade(&mut writer, &t);
+            }
+
+            if let Some(block_number) = current_block {
+                save_pool_states(&mut price_agregator, &mut writer, block_number);
+
+                // Every open position is valued at the end of each commit range, so a resumed
+                // run writes the same rows as a full replay.
+                let positions = if writer.ends_range(event.block_number()) {
+                    lp_book.take_all(&price_agregator, block_number)
+                } else {
+                    lp_book.take_changed(&price_agregator, block_number)
+                };
+                for position in positions {
+                    writer.push_lp_position(position);
+                }
+            }
+
+            if let Some(block_number) = writer.start_block(event.block_number()) {
+                save_snapshot(
+                    snapshot_dir,
+                    &writer,
+                    block_number,
+                    &price_agregator,
+                    &lp_book,
+                );
+            }
+            current_block = Some(event.block_number());
+            metrics.add_block();
+        }
+
+        // A Sync carries the reserves after the Swap/Mint/Burn emitted right after it, so it is
+        // held back until that event is priced with the reserves from before it.
+        let paired_sync = match pending_sync.take() {
+            Some(sync) if is_paired(&sync, &event) => Some(sync),
+            Some(sync) => {
+                apply_sync(
+                    &mut price_agregator,
+                    &mut writer,
+                    pool_address_to_tokens,
+                    &sync,
+                    None,
+                );
+                None
+            }
+            None => None,
+        };
+        let kind = event.kind();
+
+        match event {
+            Event::Sync(event) => pending_sync = Some(event),
+
+            Event::Swap(event) => {
+                if let Some((token0, token1)) = pool_address_to_tokens.get(&event.address) {
+                    let transaction = event
+                        .transaction_hash
+                        .and_then(|hash| transaction(event.block_number, &hash));
+
+                    let quote_is_token0 = price_agregator.is_quote_token0(token0, token1);
+                    let execution = execution::Execution::new(
+                        &event,
+                        token0,
+                        token1,
+                        quote_is_token0,
+                        price_agregator.pool_reserves(&event.address),
+                    );
+
+                    let amount0_in = normalize(event.amount0_in, token0.decimals);
+                    let amount0_out = normalize(event.amount0_out, token0.decimals);
+                    let amount1_in = normalize(event.amount1_in, token1.decimals);
+                    let amount1_out = normalize(event.amount1_out, token1.decimals);
+                    let token0_usd_price = price_agregator.token_usd_price(token0);
+                    let token1_usd_price = price_agregator.token_usd_price(token1);
+
+                    let record = SwapTick {
+                        token0_symbol: token0.symbol.clone(),
+                        token1_symbol: token1.symbol.clone(),
+                        token0_address: format!("{:?}", token0.address),
+                        token1_address: format!("{:?}", token1.address),
+                        block_number: event.block_number as i64,
+                        address: format!("{:?}", event.address),
+                        sender: format!("{:?}", event.sender),
+                        amount0_in: numeric::to_decimal(event.amount0_in, token0.decimals),
+                        amount0_out: numeric::to_decimal(event.amount0_out, token0.decimals),
+                        amount1_in: numeric::to_decimal(event.amount1_in, token1.decimals),
+                        amount1_out: numeric::to_decimal(event.amount1_out, token1.decimals),
+                        token0_usd_price,
+                        token1_usd_price,
+                        transaction_hash: event.transaction_hash.map(|x| format!("{:?}", x)),
+                        origin: transaction.as_ref().map(|x| x.from_address.clone()),
+                        log_index: event.log_index.map(|x| x as i64),
+                        base_address: if quote_is_token0 {
+                            format!("{:?}", token1.address)
+                        } else {
+                            format!("{:?}", token0.address)
+                        },
+                        side: execution.side.to_owned(),
+                        base_amount: execution.base_amount,
+                        quote_amount: execution.quote_amount,
+                        execution_price: execution.execution_price,
+                        // Flash swaps can pay in both tokens, so the larger side is the notional.
+                        notional_usd: f64::max(
+                            amount0_in * token0_usd_price + amount1_in * token1_usd_price,
+                            amount0_out * token0_usd_price + amount1_out * token1_usd_price,
+                        ),
+                        price_impact: execution.price_impact,
+                    };
+
+                    let continues = match &mut trade {
+                        Some(t) if Some(t.transaction_hash()) == event.transaction_hash => {
+                            t.push(token0, token1, &record)
+                        }
+                        _ => false,
+                    };
+
+                    if !continues {
+                        if let Some(t) = trade.take() {
+                            save_trade(&mut writer, &t);
+                        }
+
+                        trade = trades::TradeBuilder::new(&event, transaction);
+                        if let Some(t) = &mut trade {
+                            t.push(token0, token1, &record);
+                        }
                     }
+
+                    writer.push_swap(record);
                 }
-                Err(e) => {
-                    panic!("Error reading line: {}", e);
+            }
+
+            Event::Mint(event) => {
+                if let Some((token0, token1)) = pool_address_to_tokens.get(&event.address) {
+                    let provider = lp_book.handle_mint(
+                        token0,
+                        token1,
+                        &event,
+                        price_agregator.pool_reserves(&event.address),
+                    );
+
+                    let record = LiquidityTick {
+                        token0_symbol: token0.symbol.clone(),
+                        token1_symbol: token1.symbol.clone(),
+                        token0_address: format!("{:?}", token0.address),
+                        token1_address: format!("{:?}", token1.address),
+                        block_number: event.block_number as i64,
+                        address: format!("{:?}", event.address),
+                        sender: format!("{:?}", event.sender),
+                        amount0: numeric::to_decimal(event.amount0, token0.decimals),
+                        amount1: numeric::to_decimal(event.amount1, token1.decimals),
+                        token0_usd_price: price_agregator.token_usd_price(token0),
+                        token1_usd_price: price_agregator.token_usd_price(token1),
+                        transaction_hash: event.transaction_hash.map(|x| format!("{:?}", x)),
+                        log_index: event.log_index.map(|x| x as i64),
+                        provider: provider.map(|x| format!("{:?}", x)),
+                    };
+
+                    writer.push_liquidity(record);
                 }
             }
-        }
 
-        println!("[Events handled]");
-    }
+            Event::Burn(event) => {
+                if let Some((token0, token1)) = pool_address_to_tokens.get(&event.address) {
+                    let provider = lp_book.handle_burn(&event);
+
+                    let record = LiquidityTick {
+                        token0_symbol: token0.symbol.clone(),
+                        token1_symbol: token1.symbol.clone(),
+                        token0_address: format!("{:?}", token0.address),
+                        token1_address: format!("{:?}", token1.address),
+                        block_number: event.block_number as i64,
+                        address: format!("{:?}", event.address),
+                        sender: format!("{:?}", event.sender),
+                        amount0: -numeric::to_decimal(event.amount0, token0.decimals),
+                        amount1: -numeric::to_decimal(event.amount1, token1.decimals),
+                        token0_usd_price: price_agregator.token_usd_price(token0),
+                        token1_usd_price: price_agregator.token_usd_price(token1),
+                        transaction_hash: event.transaction_hash.map(|x| format!("{:?}", x)),
+                        log_index: event.log_index.map(|x| x as i64),
+                        provider: provider.map(|x| format!("{:?}", x)),
+                    };
+
+                    writer.push_liquidity(record);
+                }
+            }
 
-    async fn get_decimals(&self, token_address: Address) -> Option<u64> {
-        let http = Http::new(&self.rpc).expect("Can't connect to RPC");
-        let web3 = Web3::new(http);
-
-        let abi = include_bytes!("../../abi/erc20.abi");
-        let contract = Contract::from_json(web3.eth(), token_address, abi)
-            .expect("Failed to create contract from ABI");
-
-        let decimals: U256 = match contract
-            .query("decimals", (), None, Options::default(), None)
-            .await
-        {
-            Ok(r) => r,
-            Err(_) => {
-                println!("can't get decimals for {:?}", token_address);
-                return None;
+            Event::Transfer(event) => {
+                if let Some((token0, token1)) = pool_address_to_tokens.get(&event.address) {
+                    lp_book.handle_transfer(token0, token1, &event);
+                }
             }
         };
 
-        Some(decimals.as_u64())
+        if let Some(sync) = paired_sync {
+            apply_sync(
+                &mut price_agregator,
+                &mut writer,
+                pool_address_to_tokens,
+                &sync,
+                Some(kind),
+            );
+        }
+    }
+
+    if let Some(sync) = pending_sync.take() {
+        apply_sync(
+            &mut price_agregator,
+            &mut writer,
+            pool_address_to_tokens,
+            &sync,
+            None,
+        );
+    }
+
+    if let Some(t) = trade.take() {
+        save_trade(&mut writer, &t);
+    }
+
+    if let Some(block_number) = current_block {
+        save_pool_states(&mut price_agregator, &mut writer, block_number);
+        for position in lp_book.take_changed(&price_agregator, block_number) {
+            writer.push_lp_position(position);
+        }
+    }
+
+    if let Some(block_number) = writer.commit() {
+        save_snapshot(
+            snapshot_dir,
+            &writer,
+            block_number,
+            &price_agregator,
+            &lp_book,
+        );
+    }
+    writer.finish();
+}
+
+// The state is serialised right away, but written only after the ticks up to `block_number`.
+fn save_snapshot(
+    snapshot_dir: Option<&str>,
+    writer: &writer::TickWriter,
+    block_number: u64,
+    price_agregator: &price_agregator::PriceAgregator,
+    lp_book: &lp::LpBook,
+) {
+    if let Some(dir) = snapshot_dir {
+        let dir = dir.to_owned();
+        let data = snapshot::serialize(block_number, price_agregator, lp_book);
+        writer.then(move || snapshot::write(&dir, block_number, &data));
+    }
+}
+
+fn is_paired(sync: &SyncEvent, event: &Event) -> bool {
+    let follows = match (sync.log_index, event.log_index()) {
+        (Some(a), Some(b)) => a + 1 == b,
+        _ => true,
+    };
+
+    !matches!(event, Event::Sync(_))
+        && follows
+        && sync.address == event.address()
+        && sync.transaction_hash == event.transaction_hash()
+}
+
+fn apply_sync(
+    price_agregator: &mut price_agregator::PriceAgregator,
+    writer: &mut writer::TickWriter,
+    pool_address_to_tokens: &HashMap<&Address, (&Token, &Token)>,
+    event: &SyncEvent,
+    cause: Option<&str>,
+) {
+    let (token0, token1) = match pool_address_to_tokens.get(&event.address) {
+        Some(r) => r,
+        None => return,
+    };
+
+    price_agregator.handle_sync(token0, token1, event);
+
+    writer.push_sync(SyncTick {
+        token0_symbol: token0.symbol.clone(),
+        token1_symbol: token1.symbol.clone(),
+        token0_address: format!("{:?}", token0.address),
+        token1_address: format!("{:?}", token1.address),
+        block_number: event.block_number as i64,
+        address: format!("{:?}", event.address),
+        reserve0: numeric::to_decimal(event.reserve0, token0.decimals),
+        reserve1: numeric::to_decimal(event.reserve1, token1.decimals),
+        token0_usd_price: price_agregator.token_usd_price(token0),
+        token1_usd_price: price_agregator.token_usd_price(token1),
+        transaction_hash: event.transaction_hash.map(|x| format!("{:?}", x)),
+        log_index: event.log_index.map(|x| x as i64),
+        cause: cause.map(|x| x.to_owned()),
+    });
+}
+
+// One row per pool synced in the block, with the USD prices as of the end of the block.
+fn save_pool_states(
+    price_agregator: &mut price_agregator::PriceAgregator,
+    writer: &mut writer::TickWriter,
+    block_number: u64,
+) {
+    for address in price_agregator.take_changed_pools() {
+        if let Some(state) = price_agregator.pool_state(&address, block_number) {
+            writer.push_pool_state(state);
+        }
     }
 }
 
+fn save_trade(writer: &mut writer::TickWriter, trade: &trades::TradeBuilder) {
+    if let Some(record) = trade.build() {
+        writer.push_trade(record);
+    }
+}
+
+pub fn read_cex_data_db(conn: &PgConnection) -> Vec<CEXData> {
+    use crate::db::schema::cex_data::dsl::*;
+    cex_data
+        .filter(platform_slug.eq("ethereum"))
+        .load::<CEXData>(conn)
+        .expect("Error loading CEX data from database")
+}
+
+pub async fn build_token_registry(rpc: &str, cex_data: &[CEXData]) -> HashMap<String, Token> {
+    let mut token_address_to_token = HashMap::new();
+    for cex_record in cex_data {
+        if token_address_to_token.contains_key(&cex_record.token_address) {
+            continue;
+        }
+
+        if let Some(decimals) = get_decimals(rpc, cex_record.token_address.parse().unwrap()).await {
+            token_address_to_token.insert(
+                cex_record.token_address.clone(),
+                Token {
+                    symbol: cex_record.symbol.clone(),
+                    address: cex_record.token_address.parse().unwrap(),
+                    decimals,
+                },
+            );
+        }
+    }
+
+    token_address_to_token
+}
+
+async fn get_decimals(rpc: &str, token_address: Address) -> Option<u64> {
+    let http = Http::new(rpc).expect("Can't connect to RPC");
+    let web3 = Web3::new(http);
+
+    let abi = include_bytes!("../../abi/erc20.abi");
+    let contract = Contract::from_json(web3.eth(), token_address, abi)
+        .expect("Failed to create contract from ABI");
+
+    let decimals: U256 = match contract
+        .query("decimals", (), None, Options::default(), None)
+        .await
+    {
+        Ok(r) => r,
+        Err(_) => {
+            println!("can't get decimals for {:?}", token_address);
+            return None;
+        }
+    };
+
+    Some(decimals.as_u64())
+}
+
 pub fn normalize(amount: U256, decimals: u64) -> f64 {
-    u256_to_f64(amount) / 10.0_f64.powf(decimals as f64)
+    numeric::to_f64(amount, decimals)
 }
 
 pub fn u256_to_f64(a: U256) -> f64 {
-    a.to_string().parse().unwrap()
+    numeric::to_f64(a, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::models::LogRecord;
+    use std::fs;
+
+    const TKA: &str = "0x00000000000000000000000000000000000000aa";
+    const WETH: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+    const USDT: &str = "0xdac17f958d2ee523a2206206994597c13d831ec7";
+    const TKA_WETH: &str = "0x00000000000000000000000000000000000a0001";
+    const WETH_USDT: &str = "0x00000000000000000000000000000000000a0002";
+    const PROVIDER: &str = "0x0000000000000000000000000000000000000b01";
+    const ROUTER: &str = "0x0000000000000000000000000000000000000b02";
+    const ZERO: &str = "0x0000000000000000000000000000000000000000";
+    const OUTPUTS: [&str; 6] = [
+        "sync_ticks",
+        "swap_ticks",
+        "liquidity_ticks",
+        "trades",
+        "pool_states",
+        "lp_positions",
+    ];
+
+    fn token(symbol: &str, address: &str, decimals: u64) -> Token {
+        Token {
+            symbol: symbol.to_owned(),
+            address: address.parse().unwrap(),
+            decimals,
+        }
+    }
+
+    fn new_state() -> State {
+        State {
+            price_agregator: price_agregator::PriceAgregator::new(
+                vec![USDT.parse().unwrap()],
+                vec![USDT.parse().unwrap(), WETH.parse().unwrap()],
+            ),
+            lp_book: lp::LpBook::new(),
+        }
+    }
+
+    // Logs of a block range, in log order.
+    struct Fixture {
+        records: Vec<LogRecord>,
+        block_number: i64,
+        log_index: i64,
+    }
+
+    impl Fixture {
+        fn push(&mut self, log_type: i32, address: &str, data: [&str; 5], transaction: u64) {
+            let field = |x: &str| (!x.is_empty()).then(|| x.to_owned());
+            let transaction_hash =
+                H256::from_low_u64_be(self.block_number as u64 * 10 + transaction);
+
+            self.records.push(LogRecord {
+                id: 0,
+                log_type,
+                block_number: self.block_number,
+                address: address.to_owned(),
+                data1: field(data[0]),
+                data2: field(data[1]),
+                data3: field(data[2]),
+                data4: field(data[3]),
+                data5: field(data[4]),
+                transaction_hash: Some(format!("{:?}", transaction_hash)),
+                log_index: Some(self.log_index),
+                transaction_index: Some(transaction as i64),
+            });
+            self.log_index += 1;
+        }
+    }
+
+    // WETH priced against USDT every block, TKA swapped against WETH in both directions,
+    // with liquidity added and partly removed every 50 blocks.
+    fn fixture(blocks: i64) -> Vec<LogRecord> {
+        let mut fixture = Fixture {
+            records: Vec::new(),
+            block_number: 0,
+            log_index: 0,
+        };
+        let (mut tka, mut weth) = (1_000_000u128 * 10u128.pow(18), 500u128 * 10u128.pow(18));
+
+        for block_number in 1..=blocks {
+            fixture.block_number = block_number;
+            fixture.log_index = 0;
+
+            let weth_reserve = 1000 * 10u128.pow(18) + block_number as u128 * 10u128.pow(15);
+            let usdt_reserve =
+                2_000_000 * 10u128.pow(6) + (block_number % 7) as u128 * 10u128.pow(9);
+            fixture.push(
+                1,
+                WETH_USDT,
+                [
+                    &weth_reserve.to_string(),
+                    &usdt_reserve.to_string(),
+                    "",
+                    "",
+                    "",
+                ],
+                0,
+            );
+
+            let amount_in = (1 + block_number as u128 % 13) * 10u128.pow(20);
+            let (amount0_in, amount1_out, amount1_in, amount0_out) = if block_number % 2 == 0 {
+                let out = (weth as f64 * amount_in as f64 / (tka + amount_in) as f64) as u128;
+                tka += amount_in;
+                weth -= out;
+                (amount_in, out, 0, 0)
+            } else {
+                let amount_in = amount_in / 2000;
+                let out = (tka as f64 * amount_in as f64 / (weth + amount_in) as f64) as u128;
+                weth += amount_in;
+                tka -= out;
+                (0, 0, amount_in, out)
+            };
+            fixture.push(
+                1,
+                TKA_WETH,
+                [&tka.to_string(), &weth.to_string(), "", "", ""],
+                1,
+            );
+            fixture.push(
+                2,
+                TKA_WETH,
+                [
+                    ROUTER,
+                    &amount0_in.to_string(),
+                    &amount1_in.to_string(),
+                    &amount0_out.to_string(),
+                    &amount1_out.to_string(),
+                ],
+                1,
+            );
+
+            let liquidity = 10u128.pow(20).to_string();
+            if block_number % 50 == 10 {
+                let (amount0, amount1) = (tka / 100, weth / 100);
+                tka += amount0;
+                weth += amount1;
+                fixture.push(5, TKA_WETH, [ZERO, PROVIDER, &liquidity, "", ""], 2);
+                fixture.push(
+                    1,
+                    TKA_WETH,
+                    [&tka.to_string(), &weth.to_string(), "", "", ""],
+                    2,
+                );
+                fixture.push(
+                    3,
+                    TKA_WETH,
+                    [ROUTER, &amount0.to_string(), &amount1.to_string(), "", ""],
+                    2,
+                );
+            }
+
+            if block_number % 50 == 35 {
+                let liquidity = 10u128.pow(19).to_string();
+                let (amount0, amount1) = (tka / 1000, weth / 1000);
+                tka -= amount0;
+                weth -= amount1;
+                fixture.push(5, TKA_WETH, [PROVIDER, TKA_WETH, &liquidity, "", ""], 2);
+                fixture.push(5, TKA_WETH, [TKA_WETH, ZERO, &liquidity, "", ""], 2);
+                fixture.push(
+                    1,
+                    TKA_WETH,
+                    [&tka.to_string(), &weth.to_string(), "", "", ""],
+                    2,
+                );
+                fixture.push(
+                    4,
+                    TKA_WETH,
+                    [ROUTER, &amount0.to_string(), &amount1.to_string(), "", ""],
+                    2,
+                );
+            }
+        }
+
+        fixture.records
+    }
+
+    fn output_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_owned()
+    }
+
+    // Runs the records through the price stage into CSV files, restoring from the snapshots the
+    // way a resumed run does when there is no db sink.
+    fn run(
+        records: Vec<LogRecord>,
+        dir: &str,
+        blocks_per_commit: u64,
+        decode: impl FnOnce(Vec<LogRecord>, Arc<pipeline::Metrics>) -> Box<dyn Iterator<Item = Event>>,
+    ) {
+        let tokens = [
+            token("TKA", TKA, 18),
+            token("WETH", WETH, 18),
+            token("USDT", USDT, 6),
+        ];
+        let pools: [Address; 2] = [TKA_WETH.parse().unwrap(), WETH_USDT.parse().unwrap()];
+        let pool_address_to_tokens: HashMap<&Address, (&Token, &Token)> = HashMap::from([
+            (&pools[0], (&tokens[0], &tokens[1])),
+            (&pools[1], (&tokens[1], &tokens[2])),
+        ]);
+
+        let snapshot_dir = format!("{}/snapshots", dir);
+        let checkpoint = snapshot::latest_block(&snapshot_dir);
+        let (state, from_block) = restore(Some(&snapshot_dir), checkpoint, new_state(), None);
+
+        let metrics = Arc::new(pipeline::Metrics::new());
+        let writer = writer::TickWriter::new(
+            &[sink::SinkKind::Csv],
+            dir,
+            "",
+            blocks_per_commit,
+            checkpoint,
+            metrics.clone(),
+        );
+        let records = records
+            .into_iter()
+            .filter(|x| x.block_number as u64 >= from_block.unwrap_or(0))
+            .collect();
+
+        handle_events(
+            decode(records, metrics.clone()),
+            &pool_address_to_tokens,
+            state,
+            writer,
+            &metrics,
+            Some(&snapshot_dir),
+            |_, _| None,
+        );
+    }
+
+    fn sequential(
+        records: Vec<LogRecord>,
+        _: Arc<pipeline::Metrics>,
+    ) -> Box<dyn Iterator<Item = Event>> {
+        Box::new(records.into_iter().filter_map(|x| Event::from_record(&x)))
+    }
+
+    fn assert_same_outputs(dir: &str, expected_dir: &str) {
+        for name in OUTPUTS {
+            let output = fs::read(format!("{}/{}.csv", dir, name)).unwrap();
+            let expected = fs::read(format!("{}/{}.csv", expected_dir, name)).unwrap();
+            assert!(output.len() > 100, "{} is empty", name);
+            assert!(output == expected, "{} differs", name);
+        }
+    }
+
+    #[test]
+    fn resumed_run_matches_an_uninterrupted_one() {
+        let expected_dir = output_dir("processor_uninterrupted");
+        run(fixture(500), &expected_dir, 100, sequential);
+
+        // Stopped mid-range, then resumed from the snapshot it left, or from the one before when
+        // that is lost and the files are cut back to it.
+        for drop_latest_snapshot in [false, true] {
+            let dir = output_dir("processor_resumed");
+            run(fixture(234), &dir, 100, sequential);
+
+            let snapshot_dir = format!("{}/snapshots", dir);
+            assert_eq!(snapshot::latest_block(&snapshot_dir), Some(234));
+            if drop_latest_snapshot {
+                fs::remove_file(format!("{}/{:010}.json", snapshot_dir, 234)).unwrap();
+            }
+            run(fixture(500), &dir, 100, sequential);
+
+            assert_same_outputs(&dir, &expected_dir);
+        }
+    }
+
+    #[test]
+    fn pipeline_matches_a_sequential_run() {
+        // Enough logs for several decoded batches.
+        let expected_dir = output_dir("processor_sequential");
+        run(fixture(4000), &expected_dir, 1000, sequential);
+
+        let dir = output_dir("processor_pipeline");
+        run(fixture(4000), &dir, 1000, |records, metrics| {
+            Box::new(pipeline::decode(
+                move || Box::new(records.into_iter()),
+                metrics,
+            ))
+        });
+
+        assert_same_outputs(&dir, &expected_dir);
+    }
 }
@@ -0,0 +1,152 @@
+use crate::db::models::{Event, LogRecord};
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::vec;
+
+const RECORDS_PER_BATCH: usize = 10000;
+// Decoded batches waiting for the price stage, bounds the memory the decoder can run ahead with.
+const BATCHES_BUFFERED: usize = 8;
+
+// Counters of the pipeline stages, shared between their threads.
+pub struct Metrics {
+    started: Instant,
+    records: AtomicU64,
+    events: AtomicU64,
+    blocks: AtomicU64,
+    decode_micros: AtomicU64,
+    wait_micros: AtomicU64,
+    write_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            started: Instant::now(),
+            records: AtomicU64::new(0),
+            events: AtomicU64::new(0),
+            blocks: AtomicU64::new(0),
+            decode_micros: AtomicU64::new(0),
+            wait_micros: AtomicU64::new(0),
+            write_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn add_block(&self) {
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_write_time(&self, duration: Duration) {
+        self.write_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    // Busy time of the price stage is whatever it did not spend waiting for decoded events.
+    pub fn report(&self) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let seconds = |x: &AtomicU64| x.load(Ordering::Relaxed) as f64 / 1e6;
+        let records = self.records.load(Ordering::Relaxed);
+        let events = self.events.load(Ordering::Relaxed);
+        let blocks = self.blocks.load(Ordering::Relaxed);
+
+        println!(
+            "Processed {} logs, {} events, {} blocks in {:.1}s: {:.0} logs/s, {:.0} events/s, {:.0} blocks/s; decode {:.1}s, price {:.1}s, write {:.1}s",
+            records,
+            events,
+            blocks,
+            elapsed,
+            records as f64 / elapsed,
+            events as f64 / elapsed,
+            blocks as f64 / elapsed,
+            seconds(&self.decode_micros),
+            elapsed - seconds(&self.wait_micros),
+            seconds(&self.write_micros)
+        );
+    }
+}
+
+// Events of the decoder thread in log order, one batch at a time.
+pub struct Events {
+    batches: Receiver<Vec<Event>>,
+    batch: vec::IntoIter<Event>,
+    decoder: Option<JoinHandle<()>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Iterator for Events {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.batch.next() {
+                return Some(event);
+            }
+
+            let started = Instant::now();
+            let batch = self.batches.recv();
+            self.metrics
+                .wait_micros
+                .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+
+            match batch {
+                Ok(batch) => self.batch = batch.into_iter(),
+                Err(_) => {
+                    // The channel only closes when the decoder is done, surface its panic if it had one.
+                    if let Some(decoder) = self.decoder.take() {
+                        decoder.join().expect("Log decoder panicked");
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+// Reads the records on a thread of its own and decodes every batch of them on the rayon pool.
+// Decoding keeps the order of the records, so the price stage sees the same sequence of events
+// as a sequential run.
+pub fn decode<F>(open: F, metrics: Arc<Metrics>) -> Events
+where
+    F: FnOnce() -> Box<dyn Iterator<Item = LogRecord>> + Send + 'static,
+{
+    let (sender, receiver) = sync_channel(BATCHES_BUFFERED);
+
+    let decoder_metrics = metrics.clone();
+    let decoder = thread::spawn(move || {
+        let mut records = open();
+
+        loop {
+            let batch: Vec<LogRecord> = records.by_ref().take(RECORDS_PER_BATCH).collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let started = Instant::now();
+            let events: Vec<Event> = batch.par_iter().filter_map(Event::from_record).collect();
+            decoder_metrics
+                .decode_micros
+                .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+            decoder_metrics
+                .records
+                .fetch_add(batch.len() as u64, Ordering::Relaxed);
+            decoder_metrics
+                .events
+                .fetch_add(events.len() as u64, Ordering::Relaxed);
+
+            // The price stage stopped reading, nothing left to decode for.
+            if sender.send(events).is_err() {
+                break;
+            }
+        }
+    });
+
+    Events {
+        batches: receiver,
+        batch: Vec::new().into_iter(),
+        decoder: Some(decoder),
+        metrics,
+    }
+}
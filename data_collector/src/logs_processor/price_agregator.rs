@@ -1,9 +1,11 @@
 use super::normalize;
-use crate::db::models::{SyncEvent, Token};
-use std::collections::{HashMap, HashSet};
+use crate::db::models::{PoolState, SyncEvent, Token};
+use crate::numeric;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use web3::types::{Address, U256};
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Serialize, Deserialize)]
 struct Pool {
     address: Address,
     token0: Token,
@@ -13,33 +15,33 @@ struct Pool {
 }
 
 impl Pool {
+    // token1 per token0, from the exact reserves so that even dust reserves keep their price.
     fn price0(&self) -> f64 {
-        let reserve0 = normalize(self.reserve0, self.token0.decimals);
-        let reserve1 = normalize(self.reserve1, self.token1.decimals);
-
-        if reserve0 < 1e-8 {
-            return 0.0;
-        }
-
-        reserve1 / reserve0
+        numeric::u256_ratio(
+            self.reserve1,
+            self.token1.decimals,
+            self.reserve0,
+            self.token0.decimals,
+        )
     }
 
     fn price1(&self) -> f64 {
-        let reserve0 = normalize(self.reserve0, self.token0.decimals);
-        let reserve1 = normalize(self.reserve1, self.token1.decimals);
-
-        if reserve1 < 1e-8 {
-            return 0.0;
-        }
-
-        reserve0 / reserve1
+        numeric::u256_ratio(
+            self.reserve0,
+            self.token0.decimals,
+            self.reserve1,
+            self.token1.decimals,
+        )
     }
 }
 
+// Serialisable, so a snapshot of it can stand in for replaying the history before a block.
+#[derive(Serialize, Deserialize)]
 pub struct PriceAgregator {
     usd_token_addresses: HashSet<Address>,
-    pools: Vec<Pool>,
-    token_to_biggest_pool: HashMap<Address, usize>,
+    pools: HashMap<Address, Pool>,
+    changed_pools: BTreeSet<Address>,
+    token_to_biggest_pool: HashMap<Address, Address>,
     tokens_prices: HashMap<Address, f64>,
     decent_token_addresses: HashSet<Address>,
 }
@@ -58,7 +60,8 @@ impl PriceAgregator {
 
         PriceAgregator {
             usd_token_addresses: usd_tokens_hashset,
-            pools: Vec::new(),
+            pools: HashMap::new(),
+            changed_pools: BTreeSet::new(),
             token_to_biggest_pool: HashMap::new(),
             tokens_prices: HashMap::new(),
             decent_token_addresses: decent_tokens_hashset,
@@ -66,30 +69,33 @@ impl PriceAgregator {
     }
 
     pub fn handle_sync(&mut self, token0: &Token, token1: &Token, event: &SyncEvent) {
-        self.pools.push(Pool {
-            address: event.address,
-            token0: token0.clone(),
-            token1: token1.clone(),
-            reserve0: event.reserve0,
-            reserve1: event.reserve1,
-        });
-
-        let idx = self.pools.len() - 1;
-
-        self.update_price(token0, idx);
-        self.update_price(token1, idx);
+        self.pools.insert(
+            event.address,
+            Pool {
+                address: event.address,
+                token0: token0.clone(),
+                token1: token1.clone(),
+                reserve0: event.reserve0,
+                reserve1: event.reserve1,
+            },
+        );
+
+        self.changed_pools.insert(event.address);
+
+        self.update_price(token0, &event.address);
+        self.update_price(token1, &event.address);
     }
 
-    fn update_price(&mut self, token: &Token, pool_idx: usize) {
-        let best_pool_idx = match self.find_best_pool(token, pool_idx) {
+    fn update_price(&mut self, token: &Token, pool_address: &Address) {
+        let best_pool_address = match self.find_best_pool(token, pool_address) {
             Some(r) => r,
             None => return,
         };
 
         self.token_to_biggest_pool
-            .insert(token.address, best_pool_idx);
+            .insert(token.address, best_pool_address);
 
-        let best_pool = &self.pools[best_pool_idx];
+        let best_pool = &self.pools[&best_pool_address];
         let usd_price = if best_pool.token0.address == token.address {
             best_pool.price0() * self.token_usd_price(&best_pool.token1)
         } else {
@@ -103,22 +109,21 @@ impl PriceAgregator {
         self.tokens_prices.insert(token.address, usd_price);
     }
 
-    fn find_best_pool(&mut self, token: &Token, pool_idx: usize) -> Option<usize> {
-        let pool = &self.pools[pool_idx];
+    fn find_best_pool(&mut self, token: &Token, pool_address: &Address) -> Option<Address> {
+        let pool = &self.pools[pool_address];
 
         match self.token_to_biggest_pool.get(&token.address) {
-            Some(biggest_pool_idx) => {
-                let biggest_pool_idx = *biggest_pool_idx;
-                let biggest_pool = &self.pools[biggest_pool_idx];
+            Some(biggest_pool_address) => {
+                let biggest_pool = &self.pools[biggest_pool_address];
                 if pool.address == biggest_pool.address {
-                    return Some(pool_idx);
+                    return Some(pool.address);
                 }
 
                 if self.decent_token_addresses.contains(&token.address)
                     && self.decent_token_addresses.contains(&pool.token0.address)
                         != self.decent_token_addresses.contains(&pool.token1.address)
                 {
-                    return Some(biggest_pool_idx);
+                    return Some(biggest_pool.address);
                 }
 
                 let reserve_biggest = if biggest_pool.token0.address == token.address {
@@ -134,9 +139,9 @@ impl PriceAgregator {
                 };
 
                 if reserve_biggest < reserve_current {
-                    Some(pool_idx)
+                    Some(pool.address)
                 } else {
-                    Some(biggest_pool_idx)
+                    Some(biggest_pool.address)
                 }
             }
             None => {
@@ -147,11 +152,63 @@ impl PriceAgregator {
                     return None;
                 }
 
-                Some(pool_idx)
+                Some(pool.address)
             }
         }
     }
 
+    pub fn pool_reserves(&self, address: &Address) -> Option<(U256, U256)> {
+        let pool = self.pools.get(address)?;
+        Some((pool.reserve0, pool.reserve1))
+    }
+
+    // Pools synced since the last call, in address order.
+    pub fn take_changed_pools(&mut self) -> Vec<Address> {
+        std::mem::take(&mut self.changed_pools)
+            .into_iter()
+            .collect()
+    }
+
+    pub fn pool_state(&self, address: &Address, block_number: u64) -> Option<PoolState> {
+        let pool = self.pools.get(address)?;
+        let reserve0 = numeric::to_decimal(pool.reserve0, pool.token0.decimals);
+        let reserve1 = numeric::to_decimal(pool.reserve1, pool.token1.decimals);
+        let tvl_usd = normalize(pool.reserve0, pool.token0.decimals)
+            * self.token_usd_price(&pool.token0)
+            + normalize(pool.reserve1, pool.token1.decimals) * self.token_usd_price(&pool.token1);
+        let k = &reserve0 * &reserve1;
+
+        Some(PoolState {
+            address: format!("{:?}", pool.address),
+            block_number: block_number as i64,
+            token0_symbol: pool.token0.symbol.clone(),
+            token1_symbol: pool.token1.symbol.clone(),
+            token0_address: format!("{:?}", pool.token0.address),
+            token1_address: format!("{:?}", pool.token1.address),
+            reserve0,
+            reserve1,
+            price0: pool.price0(),
+            price1: pool.price1(),
+            tvl_usd,
+            k,
+        })
+    }
+
+    // Stablecoins quote everything, then the other decent tokens; otherwise token1 is the quote.
+    pub fn is_quote_token0(&self, token0: &Token, token1: &Token) -> bool {
+        let rank = |token: &Token| {
+            if self.usd_token_addresses.contains(&token.address) {
+                0
+            } else if self.decent_token_addresses.contains(&token.address) {
+                1
+            } else {
+                2
+            }
+        };
+
+        rank(token0) < rank(token1)
+    }
+
     pub fn token_usd_price(&self, token: &Token) -> f64 {
         if self.usd_token_addresses.contains(&token.address) {
             return 1.0;
@@ -0,0 +1,678 @@
+use crate::archive::write_parquet;
+use crate::db::db::establish_connection;
+use crate::db::models::{
+    Checkpoint, LiquidityTick, LpPosition, PoolState, SwapTick, SyncTick, TradeRecord,
+};
+use crate::db::schema::{
+    liquidity_ticks, lp_positions, pool_states, processing_checkpoints, swap_ticks, sync_ticks,
+    trades,
+};
+use arrow::array::{ArrayRef, Float64Array, Int32Array, Int64Array, RecordBatch, StringArray};
+use bigdecimal::BigDecimal;
+use clap::ValueEnum;
+use csv::{ReaderBuilder, StringRecord, Writer, WriterBuilder};
+use diesel::prelude::*;
+use diesel::PgConnection;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::path::Path;
+use std::sync::Arc;
+
+pub const CHECKPOINT_NAME: &str = "logs_processor";
+const ROWS_PER_INSERT: usize = 1000;
+const TICK_KINDS: [&str; 6] = [
+    "sync_ticks",
+    "swap_ticks",
+    "liquidity_ticks",
+    "trades",
+    "pool_states",
+    "lp_positions",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SinkKind {
+    /// The tick, `trades`, `pool_states` and `lp_positions` tables
+    Db,
+    /// One CSV file per tick kind under the output dir
+    Csv,
+    /// One Parquet file per tick kind and committed block range under the output dir
+    Parquet,
+}
+
+#[derive(Default)]
+pub struct Ticks {
+    pub sync_ticks: Vec<SyncTick>,
+    pub swap_ticks: Vec<SwapTick>,
+    pub liquidity_ticks: Vec<LiquidityTick>,
+    pub trades: Vec<TradeRecord>,
+    pub pool_states: Vec<PoolState>,
+    pub lp_positions: Vec<LpPosition>,
+}
+
+// Sinks are written from the writer thread, file sinks of one commit concurrently.
+pub trait TickSink: Send {
+    fn write(&mut self, from_block: u64, to_block: u64, ticks: &Ticks);
+}
+
+// File sinks of a resumed run first drop what was written past the checkpoint,
+// the db transaction that would have moved it may have failed after they were flushed.
+pub fn open(
+    kind: SinkKind,
+    output_dir: &str,
+    db_url: &str,
+    checkpoint: Option<u64>,
+) -> Box<dyn TickSink> {
+    match kind {
+        SinkKind::Db => Box::new(DbSink::new(db_url)),
+        SinkKind::Csv => Box::new(CsvSink::new(output_dir, checkpoint)),
+        SinkKind::Parquet => Box::new(ParquetSink::new(output_dir, checkpoint)),
+    }
+}
+
+pub fn load_checkpoint(conn: &PgConnection) -> Option<u64> {
+    processing_checkpoints::table
+        .find(CHECKPOINT_NAME)
+        .first::<Checkpoint>(conn)
+        .optional()
+        .expect("Error loading checkpoint")
+        .map(|x| x.block_number as u64)
+}
+
+// Writes ticks and moves the checkpoint in one transaction, so the tables never run ahead of it.
+// Has its own connection, the processor keeps using the other one for transaction lookups.
+pub struct DbSink {
+    conn: PgConnection,
+}
+
+impl DbSink {
+    pub fn new(db_url: &str) -> Self {
+        DbSink {
+            conn: establish_connection(db_url),
+        }
+    }
+}
+
+impl TickSink for DbSink {
+    fn write(&mut self, _from_block: u64, to_block: u64, ticks: &Ticks) {
+        let conn = &self.conn;
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            for chunk in ticks.sync_ticks.chunks(ROWS_PER_INSERT) {
+                diesel::insert_into(sync_ticks::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+
+            for chunk in ticks.swap_ticks.chunks(ROWS_PER_INSERT) {
+                diesel::insert_into(swap_ticks::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+
+            for chunk in ticks.liquidity_ticks.chunks(ROWS_PER_INSERT) {
+                diesel::insert_into(liquidity_ticks::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+
+            for chunk in ticks.trades.chunks(ROWS_PER_INSERT) {
+                diesel::insert_into(trades::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+
+            for chunk in ticks.pool_states.chunks(ROWS_PER_INSERT) {
+                diesel::insert_into(pool_states::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+
+            for chunk in ticks.lp_positions.chunks(ROWS_PER_INSERT) {
+                diesel::insert_into(lp_positions::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+
+            let checkpoint = Checkpoint {
+                name: CHECKPOINT_NAME.to_owned(),
+                block_number: to_block as i64,
+            };
+            diesel::insert_into(processing_checkpoints::table)
+                .values(&checkpoint)
+                .on_conflict(processing_checkpoints::name)
+                .do_update()
+                .set(processing_checkpoints::block_number.eq(checkpoint.block_number))
+                .execute(conn)?;
+
+            Ok(())
+        })
+        .expect("Error committing ticks to database");
+    }
+}
+
+// Keeps one CSV writer per tick kind open for the whole run.
+// A resumed run cuts the existing files back to the checkpoint and appends to them.
+pub struct CsvSink {
+    sync_ticks: Writer<File>,
+    swap_ticks: Writer<File>,
+    liquidity_ticks: Writer<File>,
+    trades: Writer<File>,
+    pool_states: Writer<File>,
+    lp_positions: Writer<File>,
+}
+
+impl CsvSink {
+    pub fn new(output_dir: &str, checkpoint: Option<u64>) -> Self {
+        fs::create_dir_all(output_dir).expect("Can't create output directory");
+
+        CsvSink {
+            sync_ticks: csv_writer(output_dir, "sync_ticks", checkpoint),
+            swap_ticks: csv_writer(output_dir, "swap_ticks", checkpoint),
+            liquidity_ticks: csv_writer(output_dir, "liquidity_ticks", checkpoint),
+            trades: csv_writer(output_dir, "trades", checkpoint),
+            pool_states: csv_writer(output_dir, "pool_states", checkpoint),
+            lp_positions: csv_writer(output_dir, "lp_positions", checkpoint),
+        }
+    }
+}
+
+fn csv_writer(output_dir: &str, name: &str, checkpoint: Option<u64>) -> Writer<File> {
+    let path = Path::new(output_dir).join(format!("{}.csv", name));
+    let append = checkpoint.is_some() && path.exists();
+    if let (true, Some(block_number)) = (append, checkpoint) {
+        truncate_after(&path, block_number);
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(&path)
+        .expect("Can't open output csv");
+    let has_header = append && file.metadata().is_ok_and(|x| x.len() > 0);
+
+    WriterBuilder::new()
+        .has_headers(!has_header)
+        .from_writer(file)
+}
+
+// Rows are appended one commit after another, so everything from the first row
+// past `block_number` on was written by commits that never made it.
+// A row cut short by a crash counts as past it too.
+fn truncate_after(path: &Path, block_number: u64) {
+    if fs::metadata(path).map_or(true, |x| x.len() == 0) {
+        return;
+    }
+
+    let mut rdr = ReaderBuilder::new()
+        .flexible(true)
+        .from_path(path)
+        .expect("Can't read output csv");
+    let column = rdr
+        .headers()
+        .expect("Can't read output csv header")
+        .iter()
+        .position(|x| x == "block_number")
+        .expect("Output csv has no block_number column");
+
+    let mut record = StringRecord::new();
+    loop {
+        let end = rdr.position().byte();
+        let past = match rdr.read_record(&mut record) {
+            Ok(false) => return,
+            Ok(true) => record
+                .get(column)
+                .and_then(|x| x.parse::<u64>().ok())
+                .is_none_or(|x| x > block_number),
+            Err(_) => true,
+        };
+
+        if past {
+            OpenOptions::new()
+                .write(true)
+                .open(path)
+                .and_then(|x| x.set_len(end))
+                .expect("Can't truncate output csv");
+            return;
+        }
+    }
+}
+
+fn write_rows<T: Serialize>(writer: &mut Writer<File>, rows: &[T]) {
+    for row in rows {
+        writer.serialize(row).expect("Can't write csv row");
+    }
+
+    writer.flush().expect("Can't flush csv file");
+}
+
+impl TickSink for CsvSink {
+    fn write(&mut self, _from_block: u64, _to_block: u64, ticks: &Ticks) {
+        write_rows(&mut self.sync_ticks, &ticks.sync_ticks);
+        write_rows(&mut self.swap_ticks, &ticks.swap_ticks);
+        write_rows(&mut self.liquidity_ticks, &ticks.liquidity_ticks);
+        write_rows(&mut self.trades, &ticks.trades);
+        write_rows(&mut self.pool_states, &ticks.pool_states);
+        write_rows(&mut self.lp_positions, &ticks.lp_positions);
+    }
+}
+
+// Every commit becomes `{output_dir}/{kind}/{from_block}-{to_block}.parquet`,
+// so a resumed run adds files next to the ones already written, once the ones past the
+// checkpoint are removed. Commits end at the checkpoint, no file straddles it.
+pub struct ParquetSink {
+    dir: String,
+}
+
+impl ParquetSink {
+    pub fn new(output_dir: &str, checkpoint: Option<u64>) -> Self {
+        if let Some(block_number) = checkpoint {
+            for name in TICK_KINDS {
+                remove_after(&Path::new(output_dir).join(name), block_number);
+            }
+        }
+
+        ParquetSink {
+            dir: output_dir.to_owned(),
+        }
+    }
+
+    fn write_batch(&self, name: &str, from_block: u64, to_block: u64, batch: RecordBatch) {
+        if batch.num_rows() == 0 {
+            return;
+        }
+
+        let dir = Path::new(&self.dir).join(name);
+        fs::create_dir_all(&dir).expect("Can't create output directory");
+
+        let path = dir.join(format!("{:010}-{:010}.parquet", from_block, to_block));
+        write_parquet(&path, batch);
+    }
+}
+
+fn remove_after(dir: &Path, block_number: u64) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries {
+        let path = entry.expect("Can't list output directory").path();
+        let from_block = path
+            .file_stem()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.split('-').next())
+            .and_then(|x| x.parse::<u64>().ok());
+
+        if from_block.is_some_and(|x| x > block_number) {
+            fs::remove_file(&path).expect("Can't remove output parquet file");
+        }
+    }
+}
+
+impl TickSink for ParquetSink {
+    fn write(&mut self, from_block: u64, to_block: u64, ticks: &Ticks) {
+        self.write_batch(
+            "sync_ticks",
+            from_block,
+            to_block,
+            sync_ticks_batch(&ticks.sync_ticks),
+        );
+        self.write_batch(
+            "swap_ticks",
+            from_block,
+            to_block,
+            swap_ticks_batch(&ticks.swap_ticks),
+        );
+        self.write_batch(
+            "liquidity_ticks",
+            from_block,
+            to_block,
+            liquidity_ticks_batch(&ticks.liquidity_ticks),
+        );
+        self.write_batch("trades", from_block, to_block, trades_batch(&ticks.trades));
+        self.write_batch(
+            "pool_states",
+            from_block,
+            to_block,
+            pool_states_batch(&ticks.pool_states),
+        );
+        self.write_batch(
+            "lp_positions",
+            from_block,
+            to_block,
+            lp_positions_batch(&ticks.lp_positions),
+        );
+    }
+}
+
+pub fn strings<T>(rows: &[T], f: impl Fn(&T) -> &str) -> ArrayRef {
+    Arc::new(StringArray::from_iter_values(rows.iter().map(f)))
+}
+
+fn optional_strings<T>(rows: &[T], f: impl Fn(&T) -> Option<&str>) -> ArrayRef {
+    Arc::new(StringArray::from_iter(rows.iter().map(f)))
+}
+
+pub fn floats<T>(rows: &[T], f: impl Fn(&T) -> f64) -> ArrayRef {
+    Arc::new(Float64Array::from_iter_values(rows.iter().map(f)))
+}
+
+// Exact decimals are kept as strings, a product of reserves overflows even Decimal256.
+fn decimals<T>(rows: &[T], f: impl Fn(&T) -> &BigDecimal) -> ArrayRef {
+    Arc::new(StringArray::from_iter_values(
+        rows.iter().map(|x| f(x).to_string()),
+    ))
+}
+
+pub fn ints<T>(rows: &[T], f: impl Fn(&T) -> i64) -> ArrayRef {
+    Arc::new(Int64Array::from_iter_values(rows.iter().map(f)))
+}
+
+fn optional_ints<T>(rows: &[T], f: impl Fn(&T) -> Option<i64>) -> ArrayRef {
+    Arc::new(Int64Array::from_iter(rows.iter().map(f)))
+}
+
+fn batch(columns: Vec<(&str, ArrayRef)>) -> RecordBatch {
+    RecordBatch::try_from_iter(columns).expect("Invalid ticks batch")
+}
+
+fn sync_ticks_batch(rows: &[SyncTick]) -> RecordBatch {
+    batch(vec![
+        ("token0_symbol", strings(rows, |x| &x.token0_symbol)),
+        ("token1_symbol", strings(rows, |x| &x.token1_symbol)),
+        ("token0_address", strings(rows, |x| &x.token0_address)),
+        ("token1_address", strings(rows, |x| &x.token1_address)),
+        ("block_number", ints(rows, |x| x.block_number)),
+        ("address", strings(rows, |x| &x.address)),
+        ("reserve0", decimals(rows, |x| &x.reserve0)),
+        ("reserve1", decimals(rows, |x| &x.reserve1)),
+        ("token0_usd_price", floats(rows, |x| x.token0_usd_price)),
+        ("token1_usd_price", floats(rows, |x| x.token1_usd_price)),
+        (
+            "transaction_hash",
+            optional_strings(rows, |x| x.transaction_hash.as_deref()),
+        ),
+        ("log_index", optional_ints(rows, |x| x.log_index)),
+        ("cause", optional_strings(rows, |x| x.cause.as_deref())),
+    ])
+}
+
+fn swap_ticks_batch(rows: &[SwapTick]) -> RecordBatch {
+    batch(vec![
+        ("token0_symbol", strings(rows, |x| &x.token0_symbol)),
+        ("token1_symbol", strings(rows, |x| &x.token1_symbol)),
+        ("token0_address", strings(rows, |x| &x.token0_address)),
+        ("token1_address", strings(rows, |x| &x.token1_address)),
+        ("block_number", ints(rows, |x| x.block_number)),
+        ("address", strings(rows, |x| &x.address)),
+        ("sender", strings(rows, |x| &x.sender)),
+        ("amount0_in", decimals(rows, |x| &x.amount0_in)),
+        ("amount0_out", decimals(rows, |x| &x.amount0_out)),
+        ("amount1_in", decimals(rows, |x| &x.amount1_in)),
+        ("amount1_out", decimals(rows, |x| &x.amount1_out)),
+        ("token0_usd_price", floats(rows, |x| x.token0_usd_price)),
+        ("token1_usd_price", floats(rows, |x| x.token1_usd_price)),
+        (
+            "transaction_hash",
+            optional_strings(rows, |x| x.transaction_hash.as_deref()),
+        ),
+        ("origin", optional_strings(rows, |x| x.origin.as_deref())),
+        ("log_index", optional_ints(rows, |x| x.log_index)),
+        ("base_address", strings(rows, |x| &x.base_address)),
+        ("side", strings(rows, |x| &x.side)),
+        ("base_amount", decimals(rows, |x| &x.base_amount)),
+        ("quote_amount", decimals(rows, |x| &x.quote_amount)),
+        ("execution_price", floats(rows, |x| x.execution_price)),
+        ("notional_usd", floats(rows, |x| x.notional_usd)),
+        ("price_impact", floats(rows, |x| x.price_impact)),
+    ])
+}
+
+fn liquidity_ticks_batch(rows: &[LiquidityTick]) -> RecordBatch {
+    batch(vec![
+        ("token0_symbol", strings(rows, |x| &x.token0_symbol)),
+        ("token1_symbol", strings(rows, |x| &x.token1_symbol)),
+        ("token0_address", strings(rows, |x| &x.token0_address)),
+        ("token1_address", strings(rows, |x| &x.token1_address)),
+        ("block_number", ints(rows, |x| x.block_number)),
+        ("address", strings(rows, |x| &x.address)),
+        ("sender", strings(rows, |x| &x.sender)),
+        ("amount0", decimals(rows, |x| &x.amount0)),
+        ("amount1", decimals(rows, |x| &x.amount1)),
+        ("token0_usd_price", floats(rows, |x| x.token0_usd_price)),
+        ("token1_usd_price", floats(rows, |x| x.token1_usd_price)),
+        (
+            "transaction_hash",
+            optional_strings(rows, |x| x.transaction_hash.as_deref()),
+        ),
+        ("log_index", optional_ints(rows, |x| x.log_index)),
+        (
+            "provider",
+            optional_strings(rows, |x| x.provider.as_deref()),
+        ),
+    ])
+}
+
+fn trades_batch(rows: &[TradeRecord]) -> RecordBatch {
+    batch(vec![
+        ("transaction_hash", strings(rows, |x| &x.transaction_hash)),
+        ("block_number", ints(rows, |x| x.block_number)),
+        ("origin", optional_strings(rows, |x| x.origin.as_deref())),
+        ("router", optional_strings(rows, |x| x.router.as_deref())),
+        ("token_in_symbol", strings(rows, |x| &x.token_in_symbol)),
+        ("token_in_address", strings(rows, |x| &x.token_in_address)),
+        ("token_out_symbol", strings(rows, |x| &x.token_out_symbol)),
+        ("token_out_address", strings(rows, |x| &x.token_out_address)),
+        ("amount_in", decimals(rows, |x| &x.amount_in)),
+        ("amount_out", decimals(rows, |x| &x.amount_out)),
+        (
+            "hops",
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|x| x.hops))) as ArrayRef,
+        ),
+        ("volume_usd", floats(rows, |x| x.volume_usd)),
+        ("gas_used", optional_ints(rows, |x| x.gas_used)),
+        (
+            "effective_gas_price",
+            optional_ints(rows, |x| x.effective_gas_price),
+        ),
+    ])
+}
+
+fn pool_states_batch(rows: &[PoolState]) -> RecordBatch {
+    batch(vec![
+        ("address", strings(rows, |x| &x.address)),
+        ("block_number", ints(rows, |x| x.block_number)),
+        ("token0_symbol", strings(rows, |x| &x.token0_symbol)),
+        ("token1_symbol", strings(rows, |x| &x.token1_symbol)),
+        ("token0_address", strings(rows, |x| &x.token0_address)),
+        ("token1_address", strings(rows, |x| &x.token1_address)),
+        ("reserve0", decimals(rows, |x| &x.reserve0)),
+        ("reserve1", decimals(rows, |x| &x.reserve1)),
+        ("price0", floats(rows, |x| x.price0)),
+        ("price1", floats(rows, |x| x.price1)),
+        ("tvl_usd", floats(rows, |x| x.tvl_usd)),
+        ("k", decimals(rows, |x| &x.k)),
+    ])
+}
+
+fn lp_positions_batch(rows: &[LpPosition]) -> RecordBatch {
+    batch(vec![
+        ("block_number", ints(rows, |x| x.block_number)),
+        ("address", strings(rows, |x| &x.address)),
+        ("provider", strings(rows, |x| &x.provider)),
+        ("token0_symbol", strings(rows, |x| &x.token0_symbol)),
+        ("token1_symbol", strings(rows, |x| &x.token1_symbol)),
+        ("lp_balance", decimals(rows, |x| &x.lp_balance)),
+        ("lp_supply", decimals(rows, |x| &x.lp_supply)),
+        ("amount0", floats(rows, |x| x.amount0)),
+        ("amount1", floats(rows, |x| x.amount1)),
+        ("value_usd", floats(rows, |x| x.value_usd)),
+        ("hold_value_usd", floats(rows, |x| x.hold_value_usd)),
+        ("fees_usd", floats(rows, |x| x.fees_usd)),
+        ("impermanent_loss", floats(rows, |x| x.impermanent_loss)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::db::load_sync_ticks;
+    use crate::numeric;
+    use arrow::array::AsArray;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use web3::types::U256;
+
+    fn output_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_owned()
+    }
+
+    // 2^60 + 1 wei of an 18-decimal token, the nearest f64 is 2^60 wei.
+    fn large_reserve() -> BigDecimal {
+        numeric::to_decimal((U256::one() << 60) + 1, 18)
+    }
+
+    fn sync_ticks() -> Ticks {
+        Ticks {
+            sync_ticks: vec![SyncTick {
+                token0_symbol: "TKA".to_owned(),
+                token1_symbol: "WETH".to_owned(),
+                token0_address: "0x00000000000000000000000000000000000000aa".to_owned(),
+                token1_address: "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2".to_owned(),
+                block_number: 1,
+                address: "0x00000000000000000000000000000000000a0001".to_owned(),
+                reserve0: large_reserve(),
+                reserve1: -large_reserve(),
+                token0_usd_price: 1.0,
+                token1_usd_price: 2000.0,
+                transaction_hash: None,
+                log_index: Some(0),
+                cause: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn large_amounts_survive_the_file_sinks() {
+        let dir = output_dir("large_amounts_sink");
+        CsvSink::new(&dir, None).write(1, 1, &sync_ticks());
+        ParquetSink::new(&dir, None).write(1, 1, &sync_ticks());
+
+        let rows: Vec<SyncTick> = csv::Reader::from_path(Path::new(&dir).join("sync_ticks.csv"))
+            .unwrap()
+            .deserialize()
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(rows[0].reserve0, large_reserve());
+        assert_eq!(rows[0].reserve1, -large_reserve());
+
+        let file = File::open(Path::new(&dir).join("sync_ticks/0000000001-0000000001.parquet"));
+        let batch = ParquetRecordBatchReaderBuilder::try_new(file.unwrap())
+            .unwrap()
+            .build()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        let reserve0 = batch.column_by_name("reserve0").unwrap().as_string::<i32>();
+        assert_eq!(
+            reserve0.value(0).parse::<BigDecimal>().unwrap(),
+            large_reserve()
+        );
+    }
+
+    // Runs against the Postgres in DATABASE_URL, inside a transaction that is rolled back.
+    #[test]
+    #[ignore]
+    fn large_amounts_survive_the_db_sink() {
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let mut sink = DbSink::new(&database_url);
+        sink.conn.begin_test_transaction().unwrap();
+        diesel::sql_query(
+            "CREATE TEMP TABLE sync_ticks (
+                id SERIAL PRIMARY KEY,
+                token0_symbol VARCHAR NOT NULL,
+                token1_symbol VARCHAR NOT NULL,
+                token0_address VARCHAR NOT NULL,
+                token1_address VARCHAR NOT NULL,
+                block_number BIGINT NOT NULL,
+                address VARCHAR NOT NULL,
+                reserve0 NUMERIC NOT NULL,
+                reserve1 NUMERIC NOT NULL,
+                token0_usd_price DOUBLE PRECISION NOT NULL,
+                token1_usd_price DOUBLE PRECISION NOT NULL,
+                transaction_hash VARCHAR,
+                log_index BIGINT,
+                cause VARCHAR
+            )",
+        )
+        .execute(&sink.conn)
+        .unwrap();
+        diesel::sql_query(
+            "CREATE TEMP TABLE processing_checkpoints (
+                name VARCHAR PRIMARY KEY,
+                block_number BIGINT NOT NULL
+            )",
+        )
+        .execute(&sink.conn)
+        .unwrap();
+
+        sink.write(1, 1, &sync_ticks());
+
+        let rows = load_sync_ticks(&sink.conn, 1, 1, &[]);
+        assert_eq!(rows[0].reserve0, large_reserve());
+        assert_eq!(rows[0].reserve1, -large_reserve());
+        assert_eq!(load_checkpoint(&sink.conn), Some(1));
+    }
+
+    #[test]
+    fn resumed_csv_is_cut_back_to_the_checkpoint() {
+        let dir = output_dir("resumed_csv_sink");
+        let path = Path::new(&dir).join("trades.csv");
+        fs::write(
+            &path,
+            "block_number,address\n1,0x01\n2,0x01\n2,0x02\n3,0x01\n4,0x0",
+        )
+        .unwrap();
+
+        truncate_after(&path, 2);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "block_number,address\n1,0x01\n2,0x01\n2,0x02\n"
+        );
+
+        // The commit past the checkpoint is gone even if it only got half a row out.
+        fs::write(&path, "block_number,address\n1,0x01\n2,0x").unwrap();
+        truncate_after(&path, 1);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "block_number,address\n1,0x01\n"
+        );
+    }
+
+    #[test]
+    fn resumed_parquet_drops_files_past_the_checkpoint() {
+        let dir = output_dir("resumed_parquet_sink");
+        let swaps = Path::new(&dir).join("swap_ticks");
+        fs::create_dir_all(&swaps).unwrap();
+        for name in [
+            "0000000001-0000000002.parquet",
+            "0000000003-0000000004.parquet",
+        ] {
+            fs::write(swaps.join(name), "").unwrap();
+        }
+
+        ParquetSink::new(&dir, Some(2));
+
+        let mut names: Vec<_> = fs::read_dir(&swaps)
+            .unwrap()
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["0000000001-0000000002.parquet"]);
+    }
+}
@@ -0,0 +1,92 @@
+use super::lp::LpBook;
+use super::price_agregator::PriceAgregator;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+const SNAPSHOTS_KEPT: usize = 3;
+
+// Processor state as of the end of a committed block, one JSON file per block.
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    block_number: u64,
+    price_agregator: &'a PriceAgregator,
+    lp_book: &'a LpBook,
+}
+
+#[derive(Deserialize)]
+pub struct Snapshot {
+    pub block_number: u64,
+    pub price_agregator: PriceAgregator,
+    pub lp_book: LpBook,
+}
+
+fn path(dir: &str, block_number: u64) -> String {
+    format!("{}/{:010}.json", dir, block_number)
+}
+
+fn blocks(dir: &str) -> Vec<u64> {
+    let entries = match fs::read_dir(dir) {
+        Ok(r) => r,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut blocks: Vec<u64> = entries
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+
+            path.file_stem()?.to_str()?.parse().ok()
+        })
+        .collect();
+    blocks.sort();
+
+    blocks
+}
+
+pub fn serialize(block_number: u64, price_agregator: &PriceAgregator, lp_book: &LpBook) -> Vec<u8> {
+    let snapshot = SnapshotRef {
+        block_number,
+        price_agregator,
+        lp_book,
+    };
+
+    serde_json::to_vec(&snapshot).expect("Failed to serialize snapshot")
+}
+
+// Written to a temporary file first, so a crash never leaves a truncated snapshot behind.
+pub fn write(dir: &str, block_number: u64, data: &[u8]) {
+    fs::create_dir_all(dir).expect("Failed to create snapshot dir");
+
+    let tmp_path = format!("{}/{:010}.json.tmp", dir, block_number);
+    fs::write(&tmp_path, data).expect("Failed to write snapshot");
+    fs::rename(&tmp_path, path(dir, block_number)).expect("Failed to move snapshot file");
+
+    let blocks = blocks(dir);
+    for block_number in &blocks[..blocks.len().saturating_sub(SNAPSHOTS_KEPT)] {
+        fs::remove_file(path(dir, *block_number)).expect("Failed to remove old snapshot");
+    }
+
+    println!("Saved snapshot at block {}", block_number);
+}
+
+pub fn latest_block(dir: &str) -> Option<u64> {
+    blocks(dir).last().cloned()
+}
+
+// The latest snapshot at or before `max_block`.
+pub fn latest(dir: &str, max_block: Option<u64>) -> Option<Snapshot> {
+    let block_number = blocks(dir)
+        .into_iter()
+        .rev()
+        .find(|x| max_block.is_none_or(|max_block| *x <= max_block))?;
+
+    let file =
+        fs::File::open(Path::new(&path(dir, block_number))).expect("Failed to open snapshot");
+    let snapshot: Snapshot =
+        serde_json::from_reader(std::io::BufReader::new(file)).expect("Failed to parse snapshot");
+
+    Some(snapshot)
+}
@@ -0,0 +1,283 @@
+use crate::archive::ArchiveReader;
+use crate::db::db::establish_connection;
+use crate::db::models::LogRecord;
+use clap::ValueEnum;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::PgConnection;
+use std::collections::VecDeque;
+
+const ROWS_PER_FETCH: usize = 10000;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum EventSourceKind {
+    /// The `logs` table, streamed in block order
+    Db,
+    /// A Parquet archive written by the logs collector
+    Parquet,
+    /// A CSV export of the `logs` table with a header row
+    Csv,
+}
+
+pub struct BlockRange {
+    pub from_block: Option<u64>,
+    pub to_block: Option<u64>,
+}
+
+impl BlockRange {
+    pub fn contains(&self, block_number: u64) -> bool {
+        self.from_block.is_none_or(|x| block_number >= x)
+            && self.to_block.is_none_or(|x| block_number <= x)
+    }
+}
+
+pub fn open(
+    kind: EventSourceKind,
+    db_url: &str,
+    logs_path: Option<&str>,
+    archive_dir: Option<&str>,
+    range: BlockRange,
+) -> Box<dyn Iterator<Item = LogRecord>> {
+    let records: Box<dyn Iterator<Item = LogRecord>> = match kind {
+        EventSourceKind::Db => Box::new(DbSource::new(db_url, &range)),
+        EventSourceKind::Parquet => {
+            let archive_dir = archive_dir.expect("archive dir is required for the parquet source");
+            Box::new(
+                ArchiveReader::with_range(archive_dir, range.from_block, range.to_block)
+                    .flatten()
+                    .filter(move |x| range.contains(x.block_number as u64)),
+            )
+        }
+        EventSourceKind::Csv => {
+            let logs_path = logs_path.expect("logs path is required for the csv source");
+            let mut rdr = csv::Reader::from_path(logs_path).expect("invalid logs csv path");
+            let headers = rdr.headers().expect("invalid logs csv header").clone();
+            let logs_path = logs_path.to_owned();
+            let mut last_block = None;
+            Box::new(
+                rdr.into_records()
+                    .map(move |x| {
+                        let row = x.expect("invalid logs csv row");
+                        let record: LogRecord = row
+                            .deserialize(Some(&headers))
+                            .expect("invalid logs csv row");
+
+                        // Blocks are regrouped as they stream, the export has to be sorted by them.
+                        if let Some(last_block) = last_block.filter(|x| record.block_number < *x) {
+                            panic!(
+                                "{}:{}: logs are not sorted by block, {} comes after {}",
+                                logs_path,
+                                row.position().map_or(0, |x| x.line()),
+                                record.block_number,
+                                last_block
+                            );
+                        }
+                        last_block = Some(record.block_number);
+
+                        record
+                    })
+                    .filter(move |x| range.contains(x.block_number as u64)),
+            )
+        }
+    };
+
+    Box::new(CanonicalOrder::new(records))
+}
+
+// Sorts the logs of every block by (transaction index, log index).
+// Sources only have to be ordered by block, CSV exports usually aren't ordered within one.
+pub struct CanonicalOrder {
+    records: Box<dyn Iterator<Item = LogRecord>>,
+    peeked: Option<LogRecord>,
+    block: VecDeque<LogRecord>,
+    last_block: Option<i64>,
+}
+
+impl CanonicalOrder {
+    pub fn new(records: Box<dyn Iterator<Item = LogRecord>>) -> Self {
+        CanonicalOrder {
+            records,
+            peeked: None,
+            block: VecDeque::new(),
+            last_block: None,
+        }
+    }
+
+    fn read_block(&mut self) {
+        let first = match self.peeked.take().or_else(|| self.records.next()) {
+            Some(r) => r,
+            None => return,
+        };
+
+        if let Some(last_block) = self.last_block {
+            assert!(
+                first.block_number > last_block,
+                "logs are not sorted by block: {} after {}",
+                first.block_number,
+                last_block
+            );
+        }
+        self.last_block = Some(first.block_number);
+
+        let mut block = vec![first];
+        for record in self.records.by_ref() {
+            if record.block_number != block[0].block_number {
+                self.peeked = Some(record);
+                break;
+            }
+
+            block.push(record);
+        }
+
+        // Missing indices go last, as NULLs do in the ascending ORDER BY of the db source.
+        block.sort_by_key(|x| {
+            (
+                x.transaction_index.is_none(),
+                x.transaction_index,
+                x.log_index.is_none(),
+                x.log_index,
+            )
+        });
+        self.block.extend(block);
+    }
+}
+
+impl Iterator for CanonicalOrder {
+    type Item = LogRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.block.is_empty() {
+            self.read_block();
+        }
+
+        self.block.pop_front()
+    }
+}
+
+// Streams the `logs` table through a server-side cursor, so the table is never loaded at once.
+// The cursor lives in its own connection and transaction, apart from the one ticks are written to.
+pub struct DbSource {
+    conn: PgConnection,
+    buffer: VecDeque<LogRecord>,
+    finished: bool,
+}
+
+impl DbSource {
+    pub fn new(db_url: &str, range: &BlockRange) -> Self {
+        let conn = establish_connection(db_url);
+
+        conn.execute("BEGIN READ ONLY")
+            .expect("Error starting logs transaction");
+        conn.execute(&format!(
+            "DECLARE logs_cursor NO SCROLL CURSOR FOR \
+             SELECT * FROM logs WHERE block_number BETWEEN {} AND {} \
+             ORDER BY block_number, transaction_index, log_index",
+            range.from_block.unwrap_or(0),
+            range.to_block.map_or(i64::MAX, |x| x as i64),
+        ))
+        .expect("Error declaring logs cursor");
+
+        DbSource {
+            conn,
+            buffer: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    fn fetch(&mut self) {
+        let rows = sql_query(format!("FETCH {} FROM logs_cursor", ROWS_PER_FETCH))
+            .load::<LogRecord>(&self.conn)
+            .expect("Error fetching logs");
+
+        if rows.len() < ROWS_PER_FETCH {
+            self.finished = true;
+            self.conn
+                .execute("CLOSE logs_cursor")
+                .expect("Error closing logs cursor");
+            self.conn
+                .execute("COMMIT")
+                .expect("Error finishing logs transaction");
+        }
+
+        self.buffer.extend(rows);
+    }
+}
+
+impl Iterator for DbSource {
+    type Item = LogRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.is_empty() && !self.finished {
+            self.fetch();
+        }
+
+        self.buffer.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(block_number: i64, transaction_index: Option<i64>, log_index: Option<i64>) -> LogRecord {
+        LogRecord {
+            id: 0,
+            log_type: 0,
+            block_number,
+            address: String::new(),
+            data1: None,
+            data2: None,
+            data3: None,
+            data4: None,
+            data5: None,
+            transaction_hash: None,
+            log_index,
+            transaction_index,
+        }
+    }
+
+    #[test]
+    fn missing_indices_sort_last_within_a_block() {
+        let records = vec![
+            log(1, Some(0), None),
+            log(1, None, Some(0)),
+            log(1, Some(0), Some(1)),
+            log(1, Some(0), Some(0)),
+            log(2, Some(0), Some(0)),
+        ];
+
+        let order: Vec<_> = CanonicalOrder::new(Box::new(records.into_iter()))
+            .map(|x| (x.block_number, x.transaction_index, x.log_index))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (1, Some(0), Some(0)),
+                (1, Some(0), Some(1)),
+                (1, Some(0), None),
+                (1, None, Some(0)),
+                (2, Some(0), Some(0)),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "unsorted_logs.csv:4: logs are not sorted by block, 1 comes after 2")]
+    fn unsorted_csv_names_the_line() {
+        let path = std::env::temp_dir().join("unsorted_logs.csv");
+        std::fs::write(
+            &path,
+            "log_type,block_number,address,data1,data2,data3,data4,data5,transaction_hash\n\
+             0,1,0x01,,,,,,\n\
+             0,2,0x01,,,,,,\n\
+             0,1,0x01,,,,,,\n",
+        )
+        .unwrap();
+
+        let range = BlockRange {
+            from_block: None,
+            to_block: None,
+        };
+        open(EventSourceKind::Csv, "", path.to_str(), None, range).for_each(drop);
+    }
+}
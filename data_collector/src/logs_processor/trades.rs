@@ -0,0 +1,126 @@
+use crate::db::db::load_transactions;
+use crate::db::models::{SwapEvent, SwapTick, Token, TradeRecord, TransactionRecord};
+use bigdecimal::BigDecimal;
+use diesel::PgConnection;
+use num_traits::Zero;
+use std::collections::HashMap;
+use web3::types::H256;
+
+const BLOCKS_PER_WINDOW: u64 = 10000;
+
+pub struct TransactionsCache {
+    from_block: u64,
+    to_block: u64,
+    transactions: HashMap<String, TransactionRecord>,
+}
+
+impl TransactionsCache {
+    pub fn new() -> Self {
+        Self {
+            from_block: 1,
+            to_block: 0,
+            transactions: HashMap::new(),
+        }
+    }
+
+    pub fn get(
+        &mut self,
+        conn: &PgConnection,
+        block_number: u64,
+        transaction_hash: &H256,
+    ) -> Option<&TransactionRecord> {
+        if block_number < self.from_block || block_number > self.to_block {
+            self.from_block = block_number;
+            self.to_block = block_number + BLOCKS_PER_WINDOW - 1;
+            self.transactions =
+                load_transactions(conn, self.from_block as i64, self.to_block as i64)
+                    .into_iter()
+                    .map(|x| (x.transaction_hash.clone(), x))
+                    .collect();
+        }
+
+        self.transactions.get(&format!("{:?}", transaction_hash))
+    }
+}
+
+struct Hop {
+    token_in: Token,
+    token_out: Token,
+    amount_in: BigDecimal,
+    amount_out: BigDecimal,
+    volume_usd: f64,
+}
+
+pub struct TradeBuilder {
+    transaction_hash: H256,
+    block_number: u64,
+    transaction: Option<TransactionRecord>,
+    hops: Vec<Hop>,
+}
+
+impl TradeBuilder {
+    pub fn new(event: &SwapEvent, transaction: Option<TransactionRecord>) -> Option<Self> {
+        Some(Self {
+            transaction_hash: event.transaction_hash?,
+            block_number: event.block_number,
+            transaction,
+            hops: Vec::new(),
+        })
+    }
+
+    pub fn transaction_hash(&self) -> H256 {
+        self.transaction_hash
+    }
+
+    // Adds a hop if it continues the route, returns false when the swap starts a new route.
+    pub fn push(&mut self, token0: &Token, token1: &Token, swap: &SwapTick) -> bool {
+        let hop = if !swap.amount0_out.is_zero() {
+            Hop {
+                token_in: token1.clone(),
+                token_out: token0.clone(),
+                amount_in: swap.amount1_in.clone(),
+                amount_out: swap.amount0_out.clone(),
+                volume_usd: swap.notional_usd,
+            }
+        } else {
+            Hop {
+                token_in: token0.clone(),
+                token_out: token1.clone(),
+                amount_in: swap.amount0_in.clone(),
+                amount_out: swap.amount1_out.clone(),
+                volume_usd: swap.notional_usd,
+            }
+        };
+
+        if let Some(last) = self.hops.last() {
+            if last.token_out.address != hop.token_in.address {
+                return false;
+            }
+        }
+
+        self.hops.push(hop);
+        true
+    }
+
+    pub fn build(&self) -> Option<TradeRecord> {
+        let first = self.hops.first()?;
+        let last = self.hops.last()?;
+
+        Some(TradeRecord {
+            transaction_hash: format!("{:?}", self.transaction_hash),
+            block_number: self.block_number as i64,
+            origin: self.transaction.as_ref().map(|x| x.from_address.clone()),
+            router: self.transaction.as_ref().and_then(|x| x.to_address.clone()),
+            token_in_symbol: first.token_in.symbol.clone(),
+            token_in_address: format!("{:?}", first.token_in.address),
+            token_out_symbol: last.token_out.symbol.clone(),
+            token_out_address: format!("{:?}", last.token_out.address),
+            amount_in: first.amount_in.clone(),
+            amount_out: last.amount_out.clone(),
+            hops: self.hops.len() as i32,
+            volume_usd: f64::max(first.volume_usd, last.volume_usd),
+            gas_used: self.transaction.as_ref().map(|x| x.gas_used),
+            effective_gas_price: self.transaction.as_ref().map(|x| x.effective_gas_price),
+        })
+    }
+}
@@ -0,0 +1,217 @@
+use super::pipeline::Metrics;
+use super::sink::{self, SinkKind, Ticks};
+use crate::db::models::{LiquidityTick, LpPosition, PoolState, SwapTick, SyncTick, TradeRecord};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+// Commits waiting for the writer thread, the processor blocks once this many are queued.
+const COMMITS_BUFFERED: usize = 2;
+
+enum Job {
+    Commit {
+        from_block: u64,
+        to_block: u64,
+        ticks: Ticks,
+    },
+    Run(Box<dyn FnOnce() + Send>),
+}
+
+// Buffers ticks and hands them to the sinks once per block range.
+// Blocks up to the checkpoint are still fed through the processor to warm its state,
+// but their ticks are dropped.
+// Sinks are written on a thread of their own in commit order, file sinks of one commit in parallel.
+pub struct TickWriter {
+    jobs: Option<SyncSender<Job>>,
+    thread: Option<JoinHandle<()>>,
+    blocks_per_commit: u64,
+    checkpoint: Option<u64>,
+    first_block: Option<u64>,
+    last_block: Option<u64>,
+    ticks: Ticks,
+}
+
+impl TickWriter {
+    pub fn new(
+        kinds: &[SinkKind],
+        output_dir: &str,
+        db_url: &str,
+        blocks_per_commit: u64,
+        checkpoint: Option<u64>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let mut kinds = kinds.to_vec();
+        kinds.sort_by_key(|x| *x as u8);
+        kinds.dedup();
+
+        // The db sink moves the checkpoint, so it is written after the files and never gets ahead of them.
+        // The files get ahead of the checkpoint when the db commit fails, a resumed run cuts them back.
+        let mut file_sinks = Vec::new();
+        let mut db_sink = None;
+        for kind in kinds {
+            let sink = sink::open(kind, output_dir, db_url, checkpoint);
+            if kind == SinkKind::Db {
+                db_sink = Some(sink);
+            } else {
+                file_sinks.push(sink);
+            }
+        }
+
+        let (jobs, receiver) = sync_channel::<Job>(COMMITS_BUFFERED);
+        let thread = thread::spawn(move || {
+            for job in receiver {
+                let (from_block, to_block, ticks) = match job {
+                    Job::Commit {
+                        from_block,
+                        to_block,
+                        ticks,
+                    } => (from_block, to_block, ticks),
+                    Job::Run(f) => {
+                        f();
+                        continue;
+                    }
+                };
+
+                let started = Instant::now();
+                thread::scope(|scope| {
+                    for sink in &mut file_sinks {
+                        let ticks = &ticks;
+                        scope.spawn(move || sink.write(from_block, to_block, ticks));
+                    }
+                });
+                if let Some(sink) = &mut db_sink {
+                    sink.write(from_block, to_block, &ticks);
+                }
+                metrics.add_write_time(started.elapsed());
+
+                println!(
+                    "Committed blocks {} - {}: {} syncs, {} swaps, {} liquidity, {} trades, {} pool states, {} lp positions",
+                    from_block,
+                    to_block,
+                    ticks.sync_ticks.len(),
+                    ticks.swap_ticks.len(),
+                    ticks.liquidity_ticks.len(),
+                    ticks.trades.len(),
+                    ticks.pool_states.len(),
+                    ticks.lp_positions.len()
+                );
+                metrics.report();
+            }
+        });
+
+        TickWriter {
+            jobs: Some(jobs),
+            thread: Some(thread),
+            blocks_per_commit,
+            checkpoint,
+            first_block: None,
+            last_block: None,
+            ticks: Ticks::default(),
+        }
+    }
+
+    pub fn is_committed(&self, block_number: u64) -> bool {
+        self.checkpoint.is_some_and(|x| block_number <= x)
+    }
+
+    // Whether starting `block_number` commits the blocks before it.
+    pub fn ends_range(&self, block_number: u64) -> bool {
+        self.last_block.is_some_and(|x| {
+            x / self.blocks_per_commit != block_number / self.blocks_per_commit
+        })
+    }
+
+    // Must be called before the events of a new block are handled, returns the committed block.
+    pub fn start_block(&mut self, block_number: u64) -> Option<u64> {
+        let committed = if self.ends_range(block_number) {
+            self.commit()
+        } else {
+            None
+        };
+
+        if self.first_block.is_none() && !self.is_committed(block_number) {
+            self.first_block = Some(block_number);
+        }
+        self.last_block = Some(block_number);
+
+        committed
+    }
+
+    pub fn push_sync(&mut self, tick: SyncTick) {
+        if !self.is_committed(tick.block_number as u64) {
+            self.ticks.sync_ticks.push(tick);
+        }
+    }
+
+    pub fn push_swap(&mut self, tick: SwapTick) {
+        if !self.is_committed(tick.block_number as u64) {
+            self.ticks.swap_ticks.push(tick);
+        }
+    }
+
+    pub fn push_liquidity(&mut self, tick: LiquidityTick) {
+        if !self.is_committed(tick.block_number as u64) {
+            self.ticks.liquidity_ticks.push(tick);
+        }
+    }
+
+    pub fn push_trade(&mut self, trade: TradeRecord) {
+        if !self.is_committed(trade.block_number as u64) {
+            self.ticks.trades.push(trade);
+        }
+    }
+
+    pub fn push_pool_state(&mut self, state: PoolState) {
+        if !self.is_committed(state.block_number as u64) {
+            self.ticks.pool_states.push(state);
+        }
+    }
+
+    pub fn push_lp_position(&mut self, position: LpPosition) {
+        if !self.is_committed(position.block_number as u64) {
+            self.ticks.lp_positions.push(position);
+        }
+    }
+
+    fn send(&self, job: Job) {
+        self.jobs
+            .as_ref()
+            .expect("Tick writer is finished")
+            .send(job)
+            .unwrap_or_else(|_| panic!("Tick writer stopped"));
+    }
+
+    // Queues the ticks of the current range, returns its last block if there was anything to commit.
+    pub fn commit(&mut self) -> Option<u64> {
+        let (first_block, last_block) = match (self.first_block, self.last_block) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return None,
+        };
+
+        let job = Job::Commit {
+            from_block: first_block,
+            to_block: last_block,
+            ticks: std::mem::take(&mut self.ticks),
+        };
+        self.send(job);
+
+        self.checkpoint = Some(last_block);
+        self.first_block = None;
+
+        Some(last_block)
+    }
+
+    // Runs `f` on the writer thread once everything committed so far is written.
+    pub fn then(&self, f: impl FnOnce() + Send + 'static) {
+        self.send(Job::Run(Box::new(f)));
+    }
+
+    // Waits for the writer thread to write everything committed, uncommitted ticks are dropped.
+    pub fn finish(mut self) {
+        drop(self.jobs.take());
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("Tick writer panicked");
+        }
+    }
+}
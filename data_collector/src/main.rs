@@ -3,12 +3,17 @@ extern crate diesel;
 
 use clap::{Parser, Subcommand};
 
+mod archive;
 mod blocks_collector;
+mod dataset_export;
 mod db;
 mod logs_collector;
 mod logs_processor;
+mod numeric;
+mod pool_analytics;
 mod pools_collector;
 mod raw_csv_processor;
+mod transfers_processor;
 mod utils;
 
 use db::db::establish_connection;
@@ -28,9 +33,14 @@ enum Commands {
     RawCSVProcessor(RawCSVsProcessorArgs),
     PoolsCollector(PoolsCollectorArgs),
     BlocksCollector(BlocksCollectorArgs),
+    TransfersProcessor(TransfersProcessorArgs),
+    PoolState(PoolStateArgs),
+    PoolAnalytics(PoolAnalyticsArgs),
+    DatasetExport(DatasetExportArgs),
 }
 
 #[derive(Parser)]
+#[command(group(clap::ArgGroup::new("scope").multiple(true).args(["pools", "tokens", "factory"])))]
 struct LogsCollectorArgs {
     #[arg(short, long)]
     from_block: u64,
@@ -43,6 +53,35 @@ struct LogsCollectorArgs {
 
     #[arg(short, long)]
     rpc: String,
+
+    /// Only collect events emitted by these pool addresses
+    #[arg(long, value_delimiter = ',')]
+    pools: Vec<String>,
+
+    /// Only collect events of pools (from the `pools` table) that trade one of these tokens
+    #[arg(long, value_delimiter = ',')]
+    tokens: Vec<String>,
+
+    /// Only collect events of pools (from the `pools` table) created by this factory
+    #[arg(long)]
+    factory: Option<String>,
+
+    /// Also collect ERC-20 Transfer events of the tokens in the token registry
+    #[arg(long)]
+    transfers: bool,
+
+    /// Also collect Transfer events of the pools' LP tokens, used for LP position accounting.
+    /// Needs --pools, --tokens or --factory
+    #[arg(long, requires = "scope")]
+    lp_transfers: bool,
+
+    /// Also fetch receipts of swap transactions (origin, router, gas paid)
+    #[arg(long)]
+    receipts: bool,
+
+    /// Also archive raw and decoded logs as Parquet files under this directory
+    #[arg(long)]
+    archive_dir: Option<String>,
 }
 
 #[derive(Parser)]
@@ -53,8 +92,36 @@ struct LogsProcessorArgs {
     #[arg(short, long)]
     cex_data_path: String,
 
+    /// Where logs are read from
+    #[arg(long, value_enum, default_value_t = logs_processor::source::EventSourceKind::Db)]
+    source: logs_processor::source::EventSourceKind,
+
+    /// CSV export of the `logs` table, used by the csv source
     #[arg(short, long)]
-    logs_path: String,
+    logs_path: Option<String>,
+
+    /// Parquet archive written by the logs collector, used by the parquet source
+    #[arg(long)]
+    archive_dir: Option<String>,
+
+    #[arg(long)]
+    from_block: Option<u64>,
+
+    #[arg(long)]
+    to_block: Option<u64>,
+
+    /// Ticks are written in one transaction per this many blocks, together with the checkpoint
+    #[arg(long, default_value_t = 10000, value_parser = clap::value_parser!(u64).range(1..))]
+    blocks_per_commit: u64,
+
+    /// Where ticks are written; file sinks write under the output dir
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "db")]
+    sinks: Vec<logs_processor::sink::SinkKind>,
+
+    /// Processor state is saved here at every commit; a resumed run restores the latest
+    /// snapshot instead of replaying the logs before it
+    #[arg(long)]
+    snapshot_dir: Option<String>,
 
     #[arg(short, long)]
     pools_path: String,
@@ -65,14 +132,111 @@ struct LogsProcessorArgs {
 
 #[derive(Parser)]
 struct RawCSVsProcessorArgs {
+    /// Gaps of at most this many blocks between a token's candles are filled with empty
+    /// candles (no volume, last close carried); longer gaps stay open. 0 fills nothing
+    #[arg(long, default_value_t = 0)]
+    max_gap: u64,
+
+    /// Candle lengths in blocks; the shortest is built from the swaps and the others, which
+    /// must be multiples of it, are rolled up from it. Each length gets its own output
+    #[arg(short, long, value_delimiter = ',', required = true, value_parser = clap::value_parser!(u64).range(1..))]
+    candlestick_len: Vec<u64>,
+
+    /// Swap ticks CSV; without it the swaps and syncs are streamed from the swap_ticks and
+    /// sync_ticks tables in block order
     #[arg(short, long)]
-    blocks_window_len: u64,
+    swaps_path: Option<String>,
+
+    /// Sync ticks CSV for the swaps CSV; pool prices and liquidity come from their reserves when
+    /// given, the swaps' execution prices stand in otherwise
+    #[arg(long)]
+    syncs_path: Option<String>,
+
+    #[arg(long)]
+    from_block: Option<u64>,
+
+    #[arg(long)]
+    to_block: Option<u64>,
+
+    /// Only swaps of pools that trade one of these tokens
+    #[arg(long, value_delimiter = ',')]
+    tokens: Vec<String>,
+
+    /// Also write the token candles to the candles table, replacing the ones of the same length
+    /// in the block range
+    #[arg(long)]
+    save_candles: bool,
 
     #[arg(short, long)]
-    candlestick_len: u64,
+    output_dir: String,
+
+    /// Format of the candle files; Parquet and Arrow embed the candle length, feature spec and
+    /// block range in their schema metadata
+    #[arg(long, value_enum, default_value = "csv")]
+    format: raw_csv_processor::output::OutputFormat,
+
+    /// Write one file per token or per month under <output_dir>/tokens/ instead of a single file
+    #[arg(long, value_enum)]
+    partition: Option<raw_csv_processor::output::Partitioning>,
+
+    /// Holder stats produced by the transfers processor, joined onto candles
+    #[arg(long)]
+    holders_path: Option<String>,
+
+    /// std_price_change_* is floored to this before price changes are divided by it
+    #[arg(long, default_value_t = raw_csv_processor::features::DEFAULT_STD_FLOOR)]
+    std_floor: f64,
+
+    /// Feature bounds as column=lower:upper, either side may be empty
+    #[arg(long, value_delimiter = ',', default_value = raw_csv_processor::features::DEFAULT_CLIPS)]
+    clip: Vec<raw_csv_processor::features::Clip>,
+
+    /// Also z-score every feature over the token's candles of this many trailing blocks
+    #[arg(long)]
+    zscore_window: Option<u64>,
+
+    /// Reference assets as name=address; each adds <name>_* features of its latest candle,
+    /// joined onto every candle next to the market breadth of its interval
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "eth=0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2,btc=0x2260fac5e5542a773aa44fbcfedf7c193bc2c599"
+    )]
+    anchors: Vec<raw_csv_processor::context::Anchor>,
+
+    /// Label horizons in candles; every horizon adds return, normalised return, MFE
+    /// and (with both barriers) triple-barrier columns
+    #[arg(long, value_delimiter = ',', default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+    label_horizons: Vec<u64>,
+
+    /// std_price_change_* window that normalised returns are divided by
+    #[arg(long, value_enum, default_value = "6h")]
+    label_std_window: raw_csv_processor::labels::StdWindow,
+
+    /// Triple-barrier take-profit, percent above the entry close
+    #[arg(long)]
+    take_profit: Option<f64>,
+
+    /// Triple-barrier stop-loss, percent below the entry close
+    #[arg(long)]
+    stop_loss: Option<f64>,
+}
 
+#[derive(Parser)]
+struct TransfersProcessorArgs {
     #[arg(short, long)]
-    swaps_path: String,
+    rpc: String,
+
+    #[arg(short, long)]
+    candlestick_len: u64,
+
+    /// Addresses to keep a per-block balance ledger for
+    #[arg(short, long, value_delimiter = ',')]
+    watched: Vec<String>,
+
+    /// Number of largest holders used for the concentration share
+    #[arg(long, default_value_t = 10)]
+    top_n: usize,
 
     #[arg(short, long)]
     output_dir: String,
@@ -108,6 +272,55 @@ struct BinanceCollectorArgs {
     output_filepath: String,
 }
 
+#[derive(Parser)]
+struct PoolStateArgs {
+    /// Pool address
+    #[arg(short, long)]
+    pool: String,
+
+    #[arg(short, long)]
+    block_number: i64,
+}
+
+#[derive(Parser)]
+struct PoolAnalyticsArgs {
+    /// Interval length in blocks, a day by default
+    #[arg(short, long, default_value_t = 7200, value_parser = clap::value_parser!(u64).range(1..))]
+    interval_len: u64,
+
+    #[arg(long)]
+    from_block: Option<u64>,
+
+    #[arg(long)]
+    to_block: Option<u64>,
+
+    #[arg(short, long)]
+    output_dir: String,
+}
+
+#[derive(Parser)]
+struct DatasetExportArgs {
+    /// Candles written by the raw CSV processor
+    #[arg(short, long)]
+    input_path: String,
+
+    #[arg(short, long)]
+    output_dir: String,
+
+    /// First block of the validation split, or a UTC date as YYYY-MM-DD
+    #[arg(long)]
+    val_start: String,
+
+    /// First block of the test split, or a UTC date as YYYY-MM-DD
+    #[arg(long)]
+    test_start: String,
+
+    /// Longest window the features look back over in blocks, rows starting this close after a
+    /// split boundary are embargoed; a week (volume_week) by default
+    #[arg(long, default_value_t = 300 * 24 * 7)]
+    feature_window: u64,
+}
+
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
@@ -120,15 +333,21 @@ async fn main() {
                 to_block: args.to_block,
                 path: args.path,
                 rpc: args.rpc,
+                pools: args.pools,
+                tokens: args.tokens,
+                factory: args.factory,
+                transfers: args.transfers,
+                lp_transfers: args.lp_transfers,
+                receipts: args.receipts,
+                archive_dir: args.archive_dir,
             };
 
             logs_collector::collect(&conn, opts).await;
         }
 
         Commands::LogsProcessor(args) => {
-            let output_dir = args.output_dir.clone();
-            let processor = logs_processor::LogsProcessor::new(&conn, args);
-            processor.save_to_db(&conn).await;
+            let processor = logs_processor::LogsProcessor::new(&conn, &cli.db_url, args);
+            processor.process(&conn).await;
         }
 
         Commands::RawCSVProcessor(args) => {
@@ -144,5 +363,28 @@ async fn main() {
         Commands::BlocksCollector(args) => {
             blocks_collector::collect(&conn, args).await;
         }
+
+        Commands::TransfersProcessor(args) => {
+            let processor = transfers_processor::TransfersProcessor::new(args);
+            processor.save(&conn).await;
+        }
+
+        Commands::PoolState(args) => {
+            match db::db::pool_state_at(&conn, &args.pool.to_lowercase(), args.block_number) {
+                Some(state) => println!(
+                    "{}",
+                    serde_json::to_string_pretty(&state).expect("Failed to serialize pool state")
+                ),
+                None => println!("No state for {} at block {}", args.pool, args.block_number),
+            }
+        }
+
+        Commands::PoolAnalytics(args) => {
+            pool_analytics::save(&conn, args);
+        }
+
+        Commands::DatasetExport(args) => {
+            dataset_export::export(&conn, args);
+        }
     };
 }
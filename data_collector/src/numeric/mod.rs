@@ -0,0 +1,194 @@
+use bigdecimal::BigDecimal;
+use num_bigint::{BigInt, BigUint, Sign};
+use num_traits::{pow, Zero};
+use serde::{de, Deserialize, Deserializer};
+use web3::types::U256;
+
+// Significant digits a ratio keeps before it is rounded to f64, well past the 17 f64 holds.
+const RATIO_DIGITS: usize = 40;
+
+// On-chain amounts stay integers until they are either stored, as exact decimals,
+// or exported as features, as f64 rounded once from the exact value.
+
+pub fn to_biguint(value: U256) -> BigUint {
+    let mut bytes = [0u8; 32];
+    value.to_big_endian(&mut bytes);
+    BigUint::from_bytes_be(&bytes)
+}
+
+// `amount / 10^decimals`, exactly.
+pub fn to_decimal(amount: U256, decimals: u64) -> BigDecimal {
+    BigDecimal::new(
+        BigInt::from_biguint(Sign::Plus, to_biguint(amount)),
+        decimals as i64,
+    )
+}
+
+// `amount / 10^decimals`, rounded to the nearest f64.
+pub fn to_f64(amount: U256, decimals: u64) -> f64 {
+    scaled_to_f64(&amount.to_string(), decimals as i64)
+}
+
+// A stored decimal rounded to the nearest f64, for the feature and dataset exports.
+pub fn decimal_to_f64(value: &BigDecimal) -> f64 {
+    let (digits, scale) = value.as_bigint_and_exponent();
+    scaled_to_f64(&digits.to_string(), scale)
+}
+
+// Reads a decimal from its string. BigDecimal's own Deserialize goes through `deserialize_any`,
+// which the csv crate answers with an f64 for anything that looks like a number.
+pub fn deserialize_decimal<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BigDecimal, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    value.parse().map_err(de::Error::custom)
+}
+
+// The string is the exact value and float parsing rounds correctly, so this rounds only once.
+fn scaled_to_f64(digits: &str, scale: i64) -> f64 {
+    format!("{}e{}", digits, -scale)
+        .parse()
+        .expect("Invalid decimal digits")
+}
+
+// `(numerator / 10^numerator_decimals) / (denominator / 10^denominator_decimals)`,
+// zero when the denominator is.
+pub fn ratio(
+    numerator: &BigUint,
+    numerator_decimals: u64,
+    denominator: &BigUint,
+    denominator_decimals: u64,
+) -> f64 {
+    if denominator.is_zero() {
+        return 0.0;
+    }
+
+    // Scale the numerator up so the integer quotient has at least RATIO_DIGITS digits.
+    let bits = denominator.bits() as i64 - numerator.bits() as i64;
+    let shift = i64::max(0, RATIO_DIGITS as i64 + 2 + bits * 3 / 10) as usize;
+    let quotient = numerator * pow(BigUint::from(10u32), shift) / denominator;
+
+    scaled_to_f64(
+        &quotient.to_string(),
+        shift as i64 + numerator_decimals as i64 - denominator_decimals as i64,
+    )
+}
+
+pub fn u256_ratio(
+    numerator: U256,
+    numerator_decimals: u64,
+    denominator: U256,
+    denominator_decimals: u64,
+) -> f64 {
+    ratio(
+        &to_biguint(numerator),
+        numerator_decimals,
+        &to_biguint(denominator),
+        denominator_decimals,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Every finite f64 is an integer multiple of 2^-1074, this is that integer.
+    fn units(x: f64) -> BigUint {
+        let bits = x.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as usize;
+        let fraction = bits & ((1u64 << 52) - 1);
+
+        if exponent == 0 {
+            BigUint::from(fraction)
+        } else {
+            BigUint::from(fraction | (1u64 << 52)) << (exponent - 1)
+        }
+    }
+
+    fn u256() -> impl Strategy<Value = U256> {
+        prop_oneof![
+            any::<u64>().prop_map(U256::from),
+            any::<[u8; 32]>().prop_map(|x| U256::from_big_endian(&x)),
+            (0usize..256).prop_map(|x| U256::one() << x),
+        ]
+    }
+
+    fn power_of_ten(exponent: u64) -> BigUint {
+        pow(BigUint::from(10u32), exponent as usize)
+    }
+
+    proptest! {
+        #[test]
+        fn to_decimal_is_exact(amount in u256(), decimals in 0u64..40) {
+            let (digits, scale) = to_decimal(amount, decimals).into_bigint_and_exponent();
+            let expected = BigInt::from_biguint(Sign::Plus, to_biguint(amount));
+
+            prop_assert_eq!(scale, decimals as i64);
+            prop_assert_eq!(digits, expected);
+        }
+
+        #[test]
+        fn to_f64_rounds_to_nearest(amount in u256(), decimals in 0u64..40) {
+            let x = to_f64(amount, decimals);
+            prop_assert!(x.is_finite() && x >= 0.0);
+
+            // amount / 10^decimals lies between the midpoints to the neighbouring floats.
+            let exact = to_biguint(amount) << 1075;
+            let scale = power_of_ten(decimals);
+            let below = if x > 0.0 { units(x.next_down()) } else { BigUint::zero() };
+            prop_assert!((below + units(x)) * &scale <= exact);
+            prop_assert!(exact <= (units(x) + units(x.next_up())) * &scale);
+        }
+
+        #[test]
+        fn decimal_to_f64_matches_to_f64(amount in u256(), decimals in 0u64..40) {
+            let decimal = to_decimal(amount, decimals);
+            prop_assert_eq!(decimal_to_f64(&decimal), to_f64(amount, decimals));
+            prop_assert_eq!(decimal_to_f64(&-decimal), -to_f64(amount, decimals));
+        }
+
+        #[test]
+        fn small_integers_are_exact(amount in 0u64..(1u64 << 53)) {
+            prop_assert_eq!(to_f64(U256::from(amount), 0), amount as f64);
+        }
+
+        #[test]
+        fn ratio_is_within_an_ulp(
+            numerator in u256(),
+            numerator_decimals in 0u64..30,
+            denominator in u256(),
+            denominator_decimals in 0u64..30,
+        ) {
+            prop_assume!(!numerator.is_zero() && !denominator.is_zero());
+
+            let x = u256_ratio(numerator, numerator_decimals, denominator, denominator_decimals);
+            prop_assert!(x.is_finite() && x > 0.0);
+
+            // |x - n / d| <= 2^-52 * n / d with both sides scaled by d * 2^1074.
+            let n = to_biguint(numerator) * power_of_ten(denominator_decimals);
+            let d = to_biguint(denominator) * power_of_ten(numerator_decimals);
+            let approximation = units(x) * &d;
+            let exact = &n << 1074;
+            let error = if approximation > exact {
+                approximation - &exact
+            } else {
+                &exact - approximation
+            };
+
+            prop_assert!(error << 52 <= exact);
+        }
+
+        #[test]
+        fn ratio_of_zero_denominator_is_zero(numerator in u256(), decimals in 0u64..30) {
+            prop_assert_eq!(u256_ratio(numerator, decimals, U256::zero(), decimals), 0.0);
+        }
+    }
+
+    #[test]
+    fn tiny_reserves_keep_their_price() {
+        // One wei of an 18-decimal token against one unit of a 6-decimal one.
+        let price = u256_ratio(U256::from(1), 6, U256::from(1), 18);
+        assert_eq!(price, 1e12);
+    }
+}
@@ -0,0 +1,227 @@
+use crate::db::db::{load_pools, load_swap_ticks, load_sync_ticks, swap_ticks_block_range};
+use crate::db::models::{SwapTick, SyncTick};
+use crate::{numeric, utils, PoolAnalyticsArgs};
+use diesel::PgConnection;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Uniswap V2 and its forks charge 0.3% of the input amount.
+pub const FEE_RATE: f64 = 0.003;
+const BLOCKS_PER_YEAR: f64 = 300.0 * 24.0 * 365.0;
+const BLOCKS_PER_PAGE: i64 = 10000;
+
+#[derive(Serialize, Clone)]
+pub struct PoolMetrics {
+    pub open_block_number: u64,
+    pub close_block_number: u64,
+    pub address: String,
+    pub factory: Option<String>,
+    pub token0_symbol: String,
+    pub token1_symbol: String,
+    pub volume_usd: f64,
+    pub fees_usd: f64,
+    /// As of the pool's last Sync in or before the interval
+    pub tvl_usd: f64,
+    /// Fees of the interval over TVL, annualised
+    pub apr: f64,
+    /// Volume over TVL
+    pub turnover: f64,
+    pub trades_count: u64,
+    pub unique_traders: u64,
+}
+
+#[derive(Serialize, Clone)]
+pub struct DexMetrics {
+    pub open_block_number: u64,
+    pub close_block_number: u64,
+    pub factory: Option<String>,
+    /// Pools with a swap or sync in the interval, TVL only sums those
+    pub pools_count: u64,
+    pub volume_usd: f64,
+    pub fees_usd: f64,
+    pub tvl_usd: f64,
+    pub trades_count: u64,
+    pub unique_traders: u64,
+}
+
+#[derive(Default)]
+struct Interval {
+    token0_symbol: String,
+    token1_symbol: String,
+    volume_usd: f64,
+    trades_count: u64,
+    traders: HashSet<String>,
+    tvl_usd: Option<f64>,
+}
+
+// Accumulates swap and sync ticks into per-pool, per-interval buckets.
+// Ticks of one pool must come in block order, pools can be interleaved.
+pub struct PoolAnalytics {
+    interval_len: u64,
+    factories: HashMap<String, Option<String>>,
+    intervals: BTreeMap<(u64, String), Interval>,
+}
+
+impl PoolAnalytics {
+    // `factories` maps pool addresses to the factory that created them.
+    pub fn new(interval_len: u64, factories: HashMap<String, Option<String>>) -> Self {
+        PoolAnalytics {
+            interval_len,
+            factories,
+            intervals: BTreeMap::new(),
+        }
+    }
+
+    fn interval(&mut self, block_number: i64, address: &str) -> &mut Interval {
+        let block_number = block_number as u64;
+        let interval_start = block_number - block_number % self.interval_len;
+
+        self.intervals
+            .entry((interval_start, address.to_owned()))
+            .or_default()
+    }
+
+    pub fn handle_swap(&mut self, swap: &SwapTick) {
+        let interval = self.interval(swap.block_number, &swap.address);
+
+        interval.token0_symbol = swap.token0_symbol.clone();
+        interval.token1_symbol = swap.token1_symbol.clone();
+        interval.volume_usd += swap.notional_usd;
+        interval.trades_count += 1;
+        interval
+            .traders
+            .insert(swap.origin.clone().unwrap_or_else(|| swap.sender.clone()));
+    }
+
+    pub fn handle_sync(&mut self, sync: &SyncTick) {
+        let interval = self.interval(sync.block_number, &sync.address);
+
+        interval.token0_symbol = sync.token0_symbol.clone();
+        interval.token1_symbol = sync.token1_symbol.clone();
+        interval.tvl_usd = Some(
+            numeric::decimal_to_f64(&sync.reserve0) * sync.token0_usd_price
+                + numeric::decimal_to_f64(&sync.reserve1) * sync.token1_usd_price,
+        );
+    }
+
+    pub fn finish(self) -> (Vec<PoolMetrics>, Vec<DexMetrics>) {
+        let mut pools = Vec::new();
+        let mut last_tvl: HashMap<String, f64> = HashMap::new();
+
+        let mut dexes: BTreeMap<(u64, Option<String>), DexMetrics> = BTreeMap::new();
+        let mut dex_traders: HashMap<(u64, Option<String>), HashSet<String>> = HashMap::new();
+
+        for ((interval_start, address), interval) in self.intervals {
+            let tvl_usd = match interval.tvl_usd {
+                Some(r) => r,
+                None => last_tvl.get(&address).cloned().unwrap_or(0.0),
+            };
+            last_tvl.insert(address.clone(), tvl_usd);
+
+            let fees_usd = interval.volume_usd * FEE_RATE;
+            let factory = self.factories.get(&address).cloned().flatten();
+
+            let metrics = PoolMetrics {
+                open_block_number: interval_start,
+                close_block_number: interval_start + self.interval_len - 1,
+                address,
+                factory: factory.clone(),
+                token0_symbol: interval.token0_symbol,
+                token1_symbol: interval.token1_symbol,
+                volume_usd: interval.volume_usd,
+                fees_usd,
+                tvl_usd,
+                apr: if tvl_usd > 0.0 {
+                    fees_usd / tvl_usd * BLOCKS_PER_YEAR / self.interval_len as f64
+                } else {
+                    0.0
+                },
+                turnover: if tvl_usd > 0.0 {
+                    interval.volume_usd / tvl_usd
+                } else {
+                    0.0
+                },
+                trades_count: interval.trades_count,
+                unique_traders: interval.traders.len() as u64,
+            };
+
+            let key = (interval_start, factory.clone());
+            let dex = dexes.entry(key.clone()).or_insert_with(|| DexMetrics {
+                open_block_number: metrics.open_block_number,
+                close_block_number: metrics.close_block_number,
+                factory,
+                pools_count: 0,
+                volume_usd: 0.0,
+                fees_usd: 0.0,
+                tvl_usd: 0.0,
+                trades_count: 0,
+                unique_traders: 0,
+            });
+            dex.pools_count += 1;
+            dex.volume_usd += metrics.volume_usd;
+            dex.fees_usd += metrics.fees_usd;
+            dex.tvl_usd += metrics.tvl_usd;
+            dex.trades_count += metrics.trades_count;
+
+            let traders = dex_traders.entry(key).or_default();
+            traders.extend(interval.traders);
+            dex.unique_traders = traders.len() as u64;
+
+            pools.push(metrics);
+        }
+
+        (pools, dexes.into_values().collect())
+    }
+}
+
+// Per-pool and per-DEX metrics over the swap and sync ticks of the database, in block order.
+pub fn analyze(
+    conn: &PgConnection,
+    interval_len: u64,
+    from_block: Option<i64>,
+    to_block: Option<i64>,
+) -> (Vec<PoolMetrics>, Vec<DexMetrics>) {
+    let factories = load_pools(conn, &[], None)
+        .into_iter()
+        .map(|x| (x.address, x.factory))
+        .collect();
+    let mut analytics = PoolAnalytics::new(interval_len, factories);
+
+    let (first_block, last_block) = match swap_ticks_block_range(conn) {
+        Some(r) => r,
+        None => return analytics.finish(),
+    };
+    let from_block = from_block.unwrap_or(first_block);
+    let to_block = to_block.unwrap_or(last_block);
+
+    let mut page_start = from_block;
+    while page_start <= to_block {
+        let page_end = i64::min(page_start + BLOCKS_PER_PAGE - 1, to_block);
+
+        for sync in load_sync_ticks(conn, page_start, page_end, &[]) {
+            analytics.handle_sync(&sync);
+        }
+
+        for swap in load_swap_ticks(conn, page_start, page_end, &[]) {
+            analytics.handle_swap(&swap);
+        }
+
+        page_start = page_end + 1;
+    }
+
+    analytics.finish()
+}
+
+pub fn save(conn: &PgConnection, args: PoolAnalyticsArgs) {
+    let (pools, dexes) = analyze(
+        conn,
+        args.interval_len,
+        args.from_block.map(|x| x as i64),
+        args.to_block.map(|x| x as i64),
+    );
+
+    println!("[Pools analyzed]");
+
+    utils::write(&format!("{}/pool_analytics.csv", args.output_dir), pools);
+    utils::write(&format!("{}/dex_analytics.csv", args.output_dir), dexes);
+}
@@ -86,6 +86,7 @@ impl PoolCollector {
                     address: format!("{:?}", pool),
                     token0: format!("{:?}", token0),
                     token1: format!("{:?}", token1),
+                    factory: Some(format!("{:?}", address)),
                 }
             });
 
@@ -0,0 +1,251 @@
+use super::features::{price_change, ratio};
+use super::types::Candlestick;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use web3::types::Address;
+
+const ANCHOR_FEATURES: [&str; 5] = [
+    "price_change_candle",
+    "norm_price_change_candle",
+    "norm_price_change_6h",
+    "norm_volume_6h",
+    "norm_buys_usd",
+];
+
+// Over the candles being built, so with --tokens it's the breadth of that selection, not the market's.
+const BREADTH_FEATURES: [&str; 3] = [
+    "market_up_share",
+    "market_volume_usd",
+    "market_median_return",
+];
+
+// A reference asset whose features are joined onto every candle, `name=address` on the command line.
+#[derive(Clone, Debug, Serialize)]
+pub struct Anchor {
+    pub name: String,
+    pub address: Address,
+}
+
+impl FromStr for Anchor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, address) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected name=address, got {}", s))?;
+        let address = address
+            .parse()
+            .map_err(|_| format!("invalid anchor address {}", address))?;
+
+        Ok(Anchor {
+            name: name.to_owned(),
+            address,
+        })
+    }
+}
+
+// Column names, in the order `fill` fills `Candlestick::context`.
+pub fn columns(anchors: &[Anchor]) -> Vec<String> {
+    let mut columns = Vec::new();
+    for anchor in anchors {
+        for feature in ANCHOR_FEATURES {
+            columns.push(format!("{}_{}", anchor.name, feature));
+        }
+    }
+    for feature in BREADTH_FEATURES {
+        columns.push(feature.to_owned());
+    }
+
+    columns
+}
+
+#[derive(Default)]
+struct Breadth {
+    traded_count: u64,
+    up_count: u64,
+    volume: f64,
+    returns: Vec<f64>,
+}
+
+// Joins the market context onto every candle as of its open block.
+// Anchor features are those of the anchor's last candle opened at or before it, so a quiet
+// interval of the anchor carries its previous candle. Breadth is over the candles of the same
+// interval that had swaps, filled empty candles don't count.
+// `std_floor` bounds the anchor's std_price_change_6h from below, as for the token features.
+pub fn fill(candles: &mut [Candlestick], anchors: &[Anchor], std_floor: f64) {
+    let mut anchor_features: HashMap<Address, BTreeMap<u64, Vec<Option<f64>>>> = HashMap::new();
+    let mut breadth: BTreeMap<u64, Breadth> = BTreeMap::new();
+
+    for candle in candles.iter() {
+        if anchors.iter().any(|x| x.address == candle.token_address) {
+            anchor_features
+                .entry(candle.token_address)
+                .or_default()
+                .insert(candle.open_block_number, features(candle, std_floor));
+        }
+
+        if candle.buys_count + candle.sells_count == 0 {
+            continue;
+        }
+
+        let interval = breadth.entry(candle.open_block_number).or_default();
+        interval.traded_count += 1;
+        if candle.close_price > candle.open_price {
+            interval.up_count += 1;
+        }
+        interval.volume += candle.volume;
+        if let Some(r) = price_change(candle.close_price, candle.open_price) {
+            interval.returns.push(r);
+        }
+    }
+
+    let breadth: HashMap<u64, Vec<Option<f64>>> = breadth
+        .into_iter()
+        .map(|(open_block_number, mut interval)| {
+            let features = vec![
+                Some(interval.up_count as f64 / interval.traded_count as f64),
+                // Every swap adds its notional to both of its tokens.
+                Some(interval.volume / 2.0),
+                median(&mut interval.returns),
+            ];
+            (open_block_number, features)
+        })
+        .collect();
+
+    for candle in candles.iter_mut() {
+        let mut context = Vec::new();
+
+        for anchor in anchors {
+            let latest = anchor_features
+                .get(&anchor.address)
+                .and_then(|x| x.range(..=candle.open_block_number).next_back());
+            match latest {
+                Some((_, features)) => context.extend(features),
+                None => context.extend([None; ANCHOR_FEATURES.len()]),
+            }
+        }
+
+        match breadth.get(&candle.open_block_number) {
+            Some(features) => context.extend(features),
+            None => context.extend([None; BREADTH_FEATURES.len()]),
+        }
+
+        candle.context = context;
+    }
+}
+
+// Values of ANCHOR_FEATURES, as preprocessing.ipynb computes them for WETH.
+fn features(candle: &Candlestick, std_floor: f64) -> Vec<Option<f64>> {
+    let std = f64::max(candle.std_price_change_6h, std_floor);
+
+    vec![
+        price_change(candle.close_price, candle.open_price),
+        price_change(candle.close_price, candle.open_price).map(|x| x / std),
+        price_change(candle.close_price, candle.open_price_6h).map(|x| x / std),
+        ratio(candle.volume_6h, candle.volume_week),
+        ratio(candle.buys_usd, candle.volume),
+    ]
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        Some((values[middle - 1] + values[middle]) / 2.0)
+    } else {
+        Some(values[middle])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WETH: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+    const WBTC: &str = "0x2260fac5e5542a773aa44fbcfedf7c193bc2c599";
+
+    fn token(i: u64) -> Address {
+        Address::from_low_u64_be(i)
+    }
+
+    fn candle(
+        token_address: Address,
+        open_block_number: u64,
+        open_price: f64,
+        close_price: f64,
+        volume: f64,
+    ) -> Candlestick {
+        Candlestick {
+            open_block_number,
+            close_block_number: open_block_number + 299,
+            token_address,
+            open_price,
+            close_price,
+            volume,
+            buys_count: if volume > 0.0 { 1 } else { 0 },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn anchors_carry_forward_and_breadth_counts_traded_candles() {
+        let weth: Address = WETH.parse().unwrap();
+        let anchor = Candlestick {
+            open_price_6h: 80.0,
+            std_price_change_6h: 2.0,
+            volume_6h: 20.0,
+            volume_week: 80.0,
+            buys_usd: 4.0,
+            ..candle(weth, 0, 100.0, 125.0, 10.0)
+        };
+
+        // WETH is quiet at 300, token 2 only has a filled candle there.
+        let mut candles = vec![
+            anchor,
+            candle(token(1), 0, 2.0, 1.0, 6.0),
+            candle(token(2), 0, 4.0, 5.0, 4.0),
+            candle(token(1), 300, 1.0, 2.0, 8.0),
+            candle(token(2), 300, 5.0, 5.0, 0.0),
+            candle(token(3), 300, 10.0, 11.0, 2.0),
+            candle(weth, 600, 125.0, 100.0, 10.0),
+            candle(token(1), 600, 1.0, 1.0, 2.0),
+            candle(token(2), 600, 5.0, 6.0, 2.0),
+        ];
+        let anchors = vec![
+            Anchor::from_str(&format!("weth={}", WETH)).unwrap(),
+            Anchor::from_str(&format!("wbtc={}", WBTC)).unwrap(),
+        ];
+        fill(&mut candles, &anchors, 1.0);
+
+        let weth_at_0 = vec![Some(25.0), Some(12.5), Some(28.125), Some(0.25), Some(0.4)];
+        let no_wbtc = vec![None; ANCHOR_FEATURES.len()];
+        let context = |anchor: &[Option<f64>], breadth: [f64; 3]| -> Vec<Option<f64>> {
+            [anchor, &no_wbtc, &breadth.map(Some)].concat()
+        };
+
+        // Up: WETH and token 2 of three, returns 25, -50 and 25.
+        let at_0 = context(&weth_at_0, [2.0 / 3.0, 10.0, 25.0]);
+        // The filled candle of token 2 doesn't count, the median of two returns is their mean.
+        let at_300 = context(&weth_at_0, [1.0, 5.0, 55.0]);
+        let weth_at_600 = features(&candles[6], 1.0);
+        // Returns -20, 0 and 20.
+        let at_600 = context(&weth_at_600, [1.0 / 3.0, 7.0, 0.0]);
+
+        let expected = [
+            &at_0, &at_0, &at_0, &at_300, &at_300, &at_300, &at_600, &at_600, &at_600,
+        ];
+        for (candle, expected) in candles.iter().zip(expected) {
+            assert_eq!(
+                &candle.context, expected,
+                "block {}",
+                candle.open_block_number
+            );
+        }
+        assert_eq!(columns(&anchors).len(), at_0.len());
+    }
+}
@@ -0,0 +1,343 @@
+use super::types::Candlestick;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use web3::types::Address;
+
+// Bounds preprocessing.ipynb clipped the features to before fitting.
+pub const DEFAULT_CLIPS: &str = "norm_price_change_candle=-20:20,norm_price_change_6h=-85:85,norm_price_change_1d=-170:170,norm_buys_usd=0:1,norm_buys_usd_6h=0:1";
+
+// Lower bound of std_price_change_* before anything is divided by it, as in preprocessing.ipynb.
+pub const DEFAULT_STD_FLOOR: f64 = 0.0015;
+
+// Bounds of one feature column, `name=lower:upper` on the command line, either side can be empty.
+#[derive(Clone, Debug, Serialize)]
+pub struct Clip {
+    pub column: String,
+    pub lower: Option<f64>,
+    pub upper: Option<f64>,
+}
+
+impl FromStr for Clip {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (column, bounds) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected column=lower:upper, got {}", s))?;
+        let (lower, upper) = bounds
+            .split_once(':')
+            .ok_or_else(|| format!("expected lower:upper, got {}", bounds))?;
+
+        let bound = |x: &str| -> Result<Option<f64>, String> {
+            if x.is_empty() {
+                Ok(None)
+            } else {
+                x.parse()
+                    .map(Some)
+                    .map_err(|_| format!("invalid bound {}", x))
+            }
+        };
+
+        Ok(Clip {
+            column: column.to_owned(),
+            lower: bound(lower)?,
+            upper: bound(upper)?,
+        })
+    }
+}
+
+impl Clip {
+    fn apply(&self, value: f64) -> f64 {
+        let value = self.lower.map_or(value, |x| f64::max(value, x));
+        self.upper.map_or(value, |x| f64::min(value, x))
+    }
+}
+
+pub struct FeatureConfig {
+    pub std_floor: f64,
+    /// Applied to the features, and to their z-scores when named with the `_z` suffix
+    pub clips: Vec<Clip>,
+    /// In blocks; every feature gets a `_z` column over the token's candles in the window
+    pub zscore_window: Option<u64>,
+}
+
+impl FeatureConfig {
+    // Column names, in the order `fill` fills `Candlestick::features`.
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns = base_columns();
+        if self.zscore_window.is_some() {
+            let zscores: Vec<String> = columns.iter().map(|x| format!("{}_z", x)).collect();
+            columns.extend(zscores);
+        }
+
+        columns
+    }
+
+    // The std a price change is divided by, floored so quiet tokens don't blow up.
+    pub fn std(&self, std: f64) -> f64 {
+        f64::max(std, self.std_floor)
+    }
+}
+
+fn base_columns() -> Vec<String> {
+    let mut columns = vec![
+        "norm_price_change_candle".to_owned(),
+        "norm_volume".to_owned(),
+        "norm_buys_usd".to_owned(),
+        "norm_buys_count".to_owned(),
+    ];
+    for window in ["6h", "1d", "3d"] {
+        columns.push(format!("norm_price_change_{}", window));
+        columns.push(format!("norm_lh_price_change_{}", window));
+        columns.push(format!("norm_volume_{}", window));
+        columns.push(format!("norm_buys_usd_{}", window));
+        columns.push(format!("norm_buys_count_{}", window));
+    }
+    columns.push("volume_ratio_6h_1d".to_owned());
+    columns.push("volume_ratio_1d_3d".to_owned());
+
+    columns
+}
+
+// The model's feature set, computed from the candle columns the way the notebooks did.
+// Z-scores only look at the token's candles opened in the window up to and including the
+// candle, so they are known when it closes.
+pub fn fill(candles: &mut [Candlestick], config: &FeatureConfig) {
+    let columns = base_columns();
+    let clips: Vec<Option<&Clip>> = columns
+        .iter()
+        .map(|x| config.clips.iter().find(|clip| &clip.column == x))
+        .collect();
+
+    let mut token_candles: HashMap<Address, Vec<usize>> = HashMap::new();
+    for (i, candle) in candles.iter_mut().enumerate() {
+        candle.features = features(candle, config)
+            .into_iter()
+            .zip(&clips)
+            .map(|(value, clip)| match clip {
+                Some(clip) => value.map(|x| clip.apply(x)),
+                None => value,
+            })
+            .collect();
+
+        token_candles
+            .entry(candle.token_address)
+            .or_default()
+            .push(i);
+    }
+
+    let window = match config.zscore_window {
+        Some(r) => r,
+        None => return,
+    };
+    let z_clips: Vec<Option<&Clip>> = columns
+        .iter()
+        .map(|x| {
+            let column = format!("{}_z", x);
+            config.clips.iter().find(|clip| clip.column == column)
+        })
+        .collect();
+
+    for (_, mut indices) in token_candles {
+        indices.sort_by_key(|&i| candles[i].open_block_number);
+
+        let zscores: Vec<Vec<Option<f64>>> = (0..indices.len())
+            .map(|k| {
+                let open_block_number = candles[indices[k]].open_block_number;
+                let start = indices[..k]
+                    .iter()
+                    .rposition(|&i| open_block_number - candles[i].open_block_number >= window)
+                    .map_or(0, |x| x + 1);
+                let trailing: Vec<&Candlestick> =
+                    indices[start..=k].iter().map(|&i| &candles[i]).collect();
+
+                (0..columns.len())
+                    .map(|column| {
+                        let value = candles[indices[k]].features[column]?;
+                        let values: Vec<f64> =
+                            trailing.iter().filter_map(|x| x.features[column]).collect();
+                        let z = zscore(value, &values)?;
+                        Some(z_clips[column].map_or(z, |clip| clip.apply(z)))
+                    })
+                    .collect()
+            })
+            .collect();
+
+        for (k, z) in zscores.into_iter().enumerate() {
+            candles[indices[k]].features.extend(z);
+        }
+    }
+}
+
+// Values of `base_columns`.
+fn features(candle: &Candlestick, config: &FeatureConfig) -> Vec<Option<f64>> {
+    let std_6h = config.std(candle.std_price_change_6h);
+    let std_1d = config.std(candle.std_price_change_1d);
+    let std_3d = config.std(candle.std_price_change_3d);
+
+    vec![
+        price_change(candle.close_price, candle.open_price).map(|x| x / std_6h),
+        ratio(candle.volume, candle.volume_week),
+        ratio(candle.buys_usd, candle.volume),
+        ratio(
+            candle.buys_count as f64,
+            (candle.buys_count + candle.sells_count) as f64,
+        ),
+        price_change(candle.close_price, candle.open_price_6h).map(|x| x / std_6h),
+        price_change(candle.high_price_6h, candle.low_price_6h).map(|x| x / std_6h),
+        ratio(candle.volume_6h, candle.volume_week),
+        ratio(candle.buys_usd_6h, candle.volume_6h),
+        ratio(
+            candle.buys_count_6h as f64,
+            (candle.buys_count_6h + candle.sells_count_6h) as f64,
+        ),
+        price_change(candle.close_price, candle.open_price_1d).map(|x| x / std_1d),
+        price_change(candle.high_price_1d, candle.low_price_1d).map(|x| x / std_1d),
+        ratio(candle.volume_1d, candle.volume_week),
+        ratio(candle.buys_usd_1d, candle.volume_1d),
+        ratio(
+            candle.buys_count_1d as f64,
+            (candle.buys_count_1d + candle.sells_count_1d) as f64,
+        ),
+        price_change(candle.close_price, candle.open_price_3d).map(|x| x / std_3d),
+        price_change(candle.high_price_3d, candle.low_price_3d).map(|x| x / std_3d),
+        ratio(candle.volume_3d, candle.volume_week),
+        ratio(candle.buys_usd_3d, candle.volume_3d),
+        ratio(
+            candle.buys_count_3d as f64,
+            (candle.buys_count_3d + candle.sells_count_3d) as f64,
+        ),
+        ratio(candle.volume_6h, candle.volume_1d),
+        ratio(candle.volume_1d, candle.volume_3d),
+    ]
+}
+
+// From `from` to `to`, in percent.
+pub fn price_change(to: f64, from: f64) -> Option<f64> {
+    ratio(to - from, from).map(|x| x * 100.0)
+}
+
+pub fn ratio(numerator: f64, denominator: f64) -> Option<f64> {
+    if denominator > 0.0 {
+        Some(numerator / denominator)
+    } else {
+        None
+    }
+}
+
+// Undefined until the window has two distinct values.
+fn zscore(value: f64, values: &[f64]) -> Option<f64> {
+    if values.len() < 2 {
+        return None;
+    }
+
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance =
+        values.iter().map(|x| (x - mean) * (x - mean)).sum::<f64>() / values.len() as f64;
+    if variance > 0.0 {
+        Some((value - mean) / variance.sqrt())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::labels::{LabelConfig, StdWindow};
+    use super::super::tokens::Tokens;
+    use super::*;
+    use csv::{Reader, WriterBuilder};
+    use std::{env, fs};
+
+    // Swaps of two tokens against WETH over 48 one-hour candles.
+    // Set UPDATE_GOLDEN=1 to rewrite the expected files after an intended change.
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/features");
+    const CANDLESTICK_LEN: u64 = 300;
+
+    fn render(config: &FeatureConfig) -> String {
+        let label_config = LabelConfig {
+            horizons: Vec::new(),
+            std_window: StdWindow::SixHours,
+            take_profit: None,
+            stop_loss: None,
+        };
+        let mut tokens = Tokens::new(&[CANDLESTICK_LEN], 0, label_config);
+        let mut rdr = Reader::from_path(format!("{}/swaps.csv", FIXTURES)).unwrap();
+        for result in rdr.deserialize() {
+            tokens.handle_swap(result.unwrap());
+        }
+        tokens.build_candlesticks();
+        tokens.fill_features(config);
+
+        let (_, mut candles) = tokens.to_vec().remove(0);
+        candles.sort_by(|a, b| {
+            (&a.token_symbol, a.open_block_number).cmp(&(&b.token_symbol, b.open_block_number))
+        });
+
+        let mut wtr = WriterBuilder::new().has_headers(false).from_writer(vec![]);
+        let mut header = vec!["token_symbol".to_owned(), "open_block_number".to_owned()];
+        header.extend(config.columns());
+        wtr.write_record(&header).unwrap();
+        for candle in candles {
+            wtr.serialize((
+                &candle.token_symbol,
+                candle.open_block_number,
+                &candle.features,
+            ))
+            .unwrap();
+        }
+
+        String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+    }
+
+    fn assert_golden(name: &str, actual: &str) {
+        let path = format!("{}/{}", FIXTURES, name);
+        if env::var("UPDATE_GOLDEN").is_ok() {
+            fs::write(&path, actual).unwrap();
+        }
+
+        let expected = fs::read_to_string(&path).unwrap();
+        assert!(
+            expected == actual,
+            "{} differs from the features built",
+            name
+        );
+    }
+
+    fn default_config() -> FeatureConfig {
+        FeatureConfig {
+            std_floor: DEFAULT_STD_FLOOR,
+            clips: DEFAULT_CLIPS
+                .split(',')
+                .map(|x| x.parse().unwrap())
+                .collect(),
+            zscore_window: None,
+        }
+    }
+
+    #[test]
+    fn notebook_features_match_golden() {
+        assert_golden("features.csv", &render(&default_config()));
+    }
+
+    #[test]
+    fn zscores_match_golden() {
+        let mut config = default_config();
+        config.zscore_window = Some(CANDLESTICK_LEN * 6);
+        config.clips.push("norm_volume_z=-3:3".parse().unwrap());
+
+        assert_golden("features_zscore.csv", &render(&config));
+    }
+
+    #[test]
+    fn clips_parse_open_bounds() {
+        let clip: Clip = "norm_lh_price_change_6h=:7500".parse().unwrap();
+
+        assert_eq!(clip.lower, None);
+        assert_eq!(clip.upper, Some(7500.0));
+        assert_eq!(clip.apply(-1.0), -1.0);
+        assert_eq!(clip.apply(8000.0), 7500.0);
+        assert!("norm_volume=1".parse::<Clip>().is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use super::types::Candlestick;
+use clap::ValueEnum;
+
+// Which std_price_change_* normalises returns. It is computed from the candle and the ones
+// before it, so it is known when the candle closes.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum StdWindow {
+    #[value(name = "6h")]
+    SixHours,
+    #[value(name = "1d")]
+    OneDay,
+    #[value(name = "3d")]
+    ThreeDays,
+}
+
+pub struct LabelConfig {
+    /// In candles
+    pub horizons: Vec<u64>,
+    pub std_window: StdWindow,
+    /// Percent above the close, the triple-barrier label needs both barriers
+    pub take_profit: Option<f64>,
+    /// Percent below the close
+    pub stop_loss: Option<f64>,
+}
+
+impl LabelConfig {
+    // Column names, in the order `label` fills `Candlestick::labels`.
+    pub fn columns(&self) -> Vec<String> {
+        let mut columns = Vec::new();
+        for horizon in &self.horizons {
+            columns.push(format!("return_{}", horizon));
+            columns.push(format!("norm_return_{}", horizon));
+            columns.push(format!("mfe_{}", horizon));
+            if self.barriers().is_some() {
+                columns.push(format!("barrier_{}", horizon));
+            }
+        }
+
+        columns
+    }
+
+    fn barriers(&self) -> Option<(f64, f64)> {
+        Some((self.take_profit?, self.stop_loss?))
+    }
+}
+
+// Fills the labels of one token's candles, sorted by open block.
+// A candle is entered at its close, so its labels only look at the candles after it.
+// Horizons count intervals, not candles: a label whose horizon ends in an interval without
+// a candle is left empty rather than reaching for a later one.
+pub fn label(candles: &mut [Candlestick], candlestick_len: u64, config: &LabelConfig) {
+    for i in 0..candles.len() {
+        let mut labels = Vec::new();
+
+        for horizon in &config.horizons {
+            let target_block = candles[i].open_block_number + horizon * candlestick_len;
+            let end = candles
+                .binary_search_by_key(&target_block, |x| x.open_block_number)
+                .ok();
+
+            let entry = candles[i].close_price;
+            let future = match end {
+                Some(end) if entry > 0.0 => &candles[i + 1..=end],
+                _ => {
+                    labels.push(None);
+                    labels.push(None);
+                    labels.push(None);
+                    if config.barriers().is_some() {
+                        labels.push(None);
+                    }
+                    continue;
+                }
+            };
+
+            let close = future.last().unwrap().close_price;
+            let price_change = (close - entry) / entry * 100.0;
+            labels.push(Some(price_change));
+
+            let std = match config.std_window {
+                StdWindow::SixHours => candles[i].std_price_change_6h,
+                StdWindow::OneDay => candles[i].std_price_change_1d,
+                StdWindow::ThreeDays => candles[i].std_price_change_3d,
+            };
+            labels.push(if std > 0.0 {
+                Some(price_change / std)
+            } else {
+                None
+            });
+
+            let high = future.iter().map(|x| x.high_price).fold(f64::MIN, f64::max);
+            labels.push(Some(f64::max(0.0, (high - entry) / entry * 100.0)));
+
+            if let Some((take_profit, stop_loss)) = config.barriers() {
+                labels.push(Some(barrier(entry, future, take_profit, stop_loss)));
+            }
+        }
+
+        candles[i].labels = labels;
+    }
+}
+
+// 1 when the take-profit barrier is hit first, -1 for the stop-loss, 0 when neither is.
+// Within a candle the order of the high and the low is unknown, hitting both counts as a loss.
+fn barrier(entry: f64, future: &[Candlestick], take_profit: f64, stop_loss: f64) -> f64 {
+    let upper = entry * (1.0 + take_profit / 100.0);
+    let lower = entry * (1.0 - stop_loss / 100.0);
+
+    for candle in future {
+        if candle.low_price <= lower {
+            return -1.0;
+        }
+
+        if candle.high_price >= upper {
+            return 1.0;
+        }
+    }
+
+    0.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candle(open_block_number: u64, close: f64, high: f64, low: f64) -> Candlestick {
+        Candlestick {
+            open_block_number,
+            close_block_number: open_block_number + 99,
+            close_price: close,
+            high_price: high,
+            low_price: low,
+            std_price_change_6h: 5.0,
+            ..Default::default()
+        }
+    }
+
+    fn config(horizons: &[u64]) -> LabelConfig {
+        LabelConfig {
+            horizons: horizons.to_vec(),
+            std_window: StdWindow::SixHours,
+            take_profit: Some(10.0),
+            stop_loss: Some(10.0),
+        }
+    }
+
+    fn assert_labels(labels: &[Option<f64>], expected: &[Option<f64>]) {
+        assert_eq!(labels.len(), expected.len());
+        for (label, expected) in labels.iter().zip(expected) {
+            match (label, expected) {
+                (Some(x), Some(y)) => {
+                    assert!((x - y).abs() < 1e-9, "{:?} != {:?}", labels, expected)
+                }
+                _ => assert_eq!(label, expected),
+            }
+        }
+    }
+
+    #[test]
+    fn labels_look_at_the_candles_after_the_close() {
+        let mut candles = vec![
+            candle(0, 100.0, 100.0, 100.0),
+            candle(100, 105.0, 108.0, 95.0),
+            candle(200, 110.0, 112.0, 100.0),
+        ];
+        label(&mut candles, 100, &config(&[1, 2]));
+
+        // Return, normalised return, MFE and barrier for one, then two candles ahead.
+        assert_labels(
+            &candles[0].labels,
+            &[
+                Some(5.0),
+                Some(1.0),
+                Some(8.0),
+                Some(0.0),
+                Some(10.0),
+                Some(2.0),
+                Some(12.0),
+                Some(1.0),
+            ],
+        );
+        assert_labels(&candles[2].labels, &[None; 8]);
+    }
+
+    #[test]
+    fn both_barriers_in_one_candle_count_as_a_loss() {
+        let mut candles = vec![
+            candle(0, 100.0, 100.0, 100.0),
+            candle(100, 100.0, 120.0, 80.0),
+        ];
+        label(&mut candles, 100, &config(&[1]));
+
+        assert_eq!(candles[0].labels[3], Some(-1.0));
+    }
+
+    #[test]
+    fn horizon_ending_in_a_gap_is_empty() {
+        let mut candles = vec![
+            candle(0, 100.0, 100.0, 100.0),
+            candle(100, 105.0, 105.0, 105.0),
+            candle(300, 120.0, 120.0, 120.0),
+        ];
+        label(&mut candles, 100, &config(&[2, 3]));
+
+        // Two intervals ahead is the missing candle at 200, three is the one at 300.
+        assert_labels(
+            &candles[0].labels,
+            &[
+                None,
+                None,
+                None,
+                None,
+                Some(20.0),
+                Some(4.0),
+                Some(20.0),
+                Some(1.0),
+            ],
+        );
+    }
+}
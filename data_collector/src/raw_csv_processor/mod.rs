@@ -1,11 +1,24 @@
+pub mod context;
+pub mod features;
+pub mod labels;
+pub mod output;
+mod pools;
 mod tokens;
 mod types;
 
+use crate::db::db::{load_swap_ticks, load_sync_ticks, swap_ticks_block_range};
+use crate::db::models::{SwapTick, SyncTick};
 use csv::Reader;
 use diesel::PgConnection;
+use features::FeatureConfig;
+use labels::LabelConfig;
 use tokens::Tokens;
 
-use crate::{utils, RawCSVsProcessorArgs};
+use crate::RawCSVsProcessorArgs;
+use clap::ValueEnum;
+use std::collections::HashMap;
+
+const BLOCKS_PER_PAGE: i64 = 10000;
 
 pub struct RawCSVProcessor {
     args: RawCSVsProcessorArgs,
@@ -17,20 +30,199 @@ impl RawCSVProcessor {
     }
 
     pub fn save_tokens_db(&self, conn: PgConnection) {
-        let mut rdr = Reader::from_path(&self.args.swaps_path).expect("can't read swaps csv");
+        let label_config = LabelConfig {
+            horizons: self.args.label_horizons.clone(),
+            std_window: self.args.label_std_window,
+            take_profit: self.args.take_profit,
+            stop_loss: self.args.stop_loss,
+        };
+        let feature_config = FeatureConfig {
+            std_floor: self.args.std_floor,
+            clips: self.args.clip.clone(),
+            zscore_window: self.args.zscore_window,
+        };
+        let feature_columns = feature_config.columns();
+        let context_columns = context::columns(&self.args.anchors);
+        let label_columns = label_config.columns();
+        let columns = [
+            feature_columns.clone(),
+            context_columns.clone(),
+            label_columns.clone(),
+        ]
+        .concat();
 
-        let mut tokens = Tokens::new(self.args.candlestick_len);
-        for result in rdr.deserialize() {
-            tokens.handle_swap(result.unwrap());
+        // How the columns were built, stored with Parquet and Arrow output.
+        let feature_spec = serde_json::json!({
+            "max_gap": self.args.max_gap,
+            "features": feature_columns,
+            "std_floor": self.args.std_floor,
+            "clips": self.args.clip,
+            "zscore_window": self.args.zscore_window,
+            "context": context_columns,
+            "anchors": self.args.anchors,
+            "labels": label_columns,
+            "label_horizons": self.args.label_horizons,
+            "label_std_window": self.args.label_std_window.to_possible_value().unwrap().get_name(),
+            "take_profit": self.args.take_profit,
+            "stop_loss": self.args.stop_loss,
+        });
+
+        let mut tokens = Tokens::new(&self.args.candlestick_len, self.args.max_gap, label_config);
+        match &self.args.swaps_path {
+            Some(swaps_path) => self.read_swaps(swaps_path, &mut tokens),
+            None => self.stream_swaps(&conn, &mut tokens),
         }
 
         println!("[SWAPS HANDLED]");
 
         tokens.build_candlesticks();
+        tokens.fill_features(&feature_config);
+        tokens.fill_context(&self.args.anchors, feature_config.std_floor);
+
+        println!("[FEATURES BUILT]");
+
+        if let Some(holders_path) = &self.args.holders_path {
+            let mut rdr = Reader::from_path(holders_path).expect("can't read holders csv");
+            let holders = rdr.deserialize().map(|x| x.unwrap()).collect();
+            tokens.fill_holders(holders);
+
+            println!("[HOLDERS JOINED]");
+        }
+
+        let source = self
+            .args
+            .swaps_path
+            .clone()
+            .unwrap_or_else(|| "swap_ticks".to_owned());
+
+        // One length keeps the output directory as is, several get a subdirectory each.
+        let resolutions = tokens.to_vec();
+        let single = resolutions.len() == 1;
+        let mut pool_resolutions: HashMap<u64, Vec<_>> =
+            tokens.pools().to_vec().into_iter().collect();
+        for (len, candles) in resolutions {
+            let output_dir = if single {
+                self.args.output_dir.clone()
+            } else {
+                format!("{}/{}", self.args.output_dir, len)
+            };
+
+            let metadata = output::metadata(len, &feature_spec, &source, &candles);
+
+            if self.args.save_candles {
+                output::save(&conn, len, &candles);
+            }
+
+            output::write(
+                &conn,
+                &output_dir,
+                self.args.format,
+                self.args.partition,
+                candles,
+                &columns,
+                metadata.clone(),
+            );
+            output::write_pools(
+                &output_dir,
+                self.args.format,
+                pool_resolutions.remove(&len).unwrap_or_default(),
+                metadata,
+            );
+
+            println!("[{} BLOCK CANDLES WRITTEN]", len);
+        }
+    }
+
+    // Addresses are stored lowercase.
+    fn tokens(&self) -> Vec<String> {
+        self.args.tokens.iter().map(|x| x.to_lowercase()).collect()
+    }
+
+    fn selected(
+        &self,
+        tokens: &[String],
+        block_number: i64,
+        token0_address: &str,
+        token1_address: &str,
+    ) -> bool {
+        let block_number = block_number as u64;
 
-        utils::write(
-            &format!("{}/tokens.csv", self.args.output_dir),
-            tokens.to_vec(),
-        );
+        self.args.from_block.is_none_or(|x| block_number >= x)
+            && self.args.to_block.is_none_or(|x| block_number <= x)
+            && (tokens.is_empty()
+                || tokens
+                    .iter()
+                    .any(|x| x == token0_address || x == token1_address))
+    }
+
+    fn read_swaps(&self, swaps_path: &str, tokens: &mut Tokens) {
+        let token_addresses = self.tokens();
+
+        if let Some(syncs_path) = &self.args.syncs_path {
+            let mut rdr = Reader::from_path(syncs_path).expect("can't read syncs csv");
+            for result in rdr.deserialize() {
+                let sync: SyncTick = result.unwrap();
+                if self.selected(
+                    &token_addresses,
+                    sync.block_number,
+                    &sync.token0_address,
+                    &sync.token1_address,
+                ) {
+                    tokens.handle_sync(sync);
+                }
+            }
+
+            println!("[SYNCS HANDLED]");
+        }
+
+        let mut rdr = Reader::from_path(swaps_path).expect("can't read swaps csv");
+        // Swaps written before ticks carried their side have none of the base/quote columns.
+        let headers = rdr.headers().expect("can't read swaps csv header");
+        if !headers.iter().any(|x| x == "base_address") {
+            panic!(
+                "{} has no base_address column, regenerate swaps with the logs processor",
+                swaps_path
+            );
+        }
+
+        for result in rdr.deserialize() {
+            let swap: SwapTick = result.unwrap();
+            if self.selected(
+                &token_addresses,
+                swap.block_number,
+                &swap.token0_address,
+                &swap.token1_address,
+            ) {
+                tokens.handle_swap(swap);
+            }
+        }
+    }
+
+    // Swap and sync ticks of the database in block order, a page of blocks at a time.
+    fn stream_swaps(&self, conn: &PgConnection, tokens: &mut Tokens) {
+        let (first_block, last_block) = match swap_ticks_block_range(conn) {
+            Some(r) => r,
+            None => return,
+        };
+        let from_block = self.args.from_block.map_or(first_block, |x| x as i64);
+        let to_block = self.args.to_block.map_or(last_block, |x| x as i64);
+        let token_addresses = self.tokens();
+
+        let mut page_start = from_block;
+        while page_start <= to_block {
+            let page_end = i64::min(page_start + BLOCKS_PER_PAGE - 1, to_block);
+
+            for sync in load_sync_ticks(conn, page_start, page_end, &token_addresses) {
+                tokens.handle_sync(sync);
+            }
+
+            for swap in load_swap_ticks(conn, page_start, page_end, &token_addresses) {
+                tokens.handle_swap(swap);
+            }
+
+            println!("[SWAPS LOADED] up to block {}", page_end);
+
+            page_start = page_end + 1;
+        }
     }
 }
@@ -0,0 +1,602 @@
+use super::types::{Candlestick, PoolCandlestick};
+use crate::archive::write_parquet;
+use crate::db::db::{block_timestamp_at, first_block_at, replace_candles};
+use crate::db::models::CandleRecord;
+use crate::logs_processor::sink::{floats, ints, strings};
+use crate::utils;
+use arrow::array::{ArrayRef, BooleanArray, Float64Array, RecordBatch, StringArray};
+use arrow::ipc::writer::FileWriter;
+use chrono::{DateTime, Datelike, Months, NaiveDate};
+use clap::ValueEnum;
+use diesel::PgConnection;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::path::Path;
+use std::sync::Arc;
+use web3::types::Address;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum OutputFormat {
+    Csv,
+    Parquet,
+    /// Arrow IPC file
+    Arrow,
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Parquet => "parquet",
+            OutputFormat::Arrow => "arrow",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Partitioning {
+    Token,
+    /// Calendar month (UTC) of the candle's open block, needs the blocks table
+    Month,
+}
+
+// The extra columns of a candle, in the order of `columns` passed to `write`.
+pub fn values(candle: &Candlestick) -> Vec<Option<f64>> {
+    [
+        candle.features.clone(),
+        candle.context.clone(),
+        candle.labels.clone(),
+    ]
+    .concat()
+}
+
+// Schema metadata of the files of one candle length.
+pub fn metadata(
+    len: u64,
+    feature_spec: &serde_json::Value,
+    source: &str,
+    candles: &[Candlestick],
+) -> HashMap<String, String> {
+    let from_block = candles.iter().map(|x| x.open_block_number).min();
+    let to_block = candles.iter().map(|x| x.close_block_number).max();
+
+    HashMap::from([
+        ("candlestick_len".to_owned(), len.to_string()),
+        ("feature_spec".to_owned(), feature_spec.to_string()),
+        ("source".to_owned(), source.to_owned()),
+        (
+            "from_block".to_owned(),
+            from_block.unwrap_or_default().to_string(),
+        ),
+        (
+            "to_block".to_owned(),
+            to_block.unwrap_or_default().to_string(),
+        ),
+    ])
+}
+
+// Writes the candles to `{output_dir}/tokens.{ext}`, or to one file per partition under
+// `{output_dir}/tokens/`, named hive style: `token_address=0x…/` or `month=2023-10/`.
+// Parquet and Arrow files carry `metadata` in their schema, CSV files can't.
+pub fn write(
+    conn: &PgConnection,
+    output_dir: &str,
+    format: OutputFormat,
+    partitioning: Option<Partitioning>,
+    candles: Vec<Candlestick>,
+    columns: &[String],
+    metadata: HashMap<String, String>,
+) {
+    let partitions = partition(partitioning, candles, |from_block, to_block| {
+        months(conn, from_block, to_block)
+    });
+    write_partitions(output_dir, format, partitions, columns, &metadata);
+}
+
+// Candles by partition directory, the empty one when they aren't partitioned.
+// `months` returns the first block and name of the months from one block to another.
+fn partition(
+    partitioning: Option<Partitioning>,
+    candles: Vec<Candlestick>,
+    months: impl FnOnce(u64, u64) -> Vec<(u64, String)>,
+) -> BTreeMap<String, Vec<Candlestick>> {
+    let mut partitions: BTreeMap<String, Vec<Candlestick>> = BTreeMap::new();
+    match partitioning {
+        None => {
+            partitions.insert(String::new(), candles);
+        }
+        Some(Partitioning::Token) => {
+            for candle in candles {
+                partitions
+                    .entry(format!("token_address={:?}", candle.token_address))
+                    .or_default()
+                    .push(candle);
+            }
+        }
+        Some(Partitioning::Month) => {
+            let from_block = candles.iter().map(|x| x.open_block_number).min();
+            let to_block = candles.iter().map(|x| x.close_block_number).max();
+            let months = match (from_block, to_block) {
+                (Some(from), Some(to)) => months(from, to),
+                _ => Vec::new(),
+            };
+
+            for candle in candles {
+                let i = months.partition_point(|x| x.0 <= candle.open_block_number);
+                partitions
+                    .entry(format!("month={}", months[i - 1].1))
+                    .or_default()
+                    .push(candle);
+            }
+        }
+    }
+
+    partitions
+}
+
+fn write_partitions(
+    output_dir: &str,
+    format: OutputFormat,
+    partitions: BTreeMap<String, Vec<Candlestick>>,
+    columns: &[String],
+    metadata: &HashMap<String, String>,
+) {
+    let file_name = format!("tokens.{}", format.extension());
+
+    for (partition, candles) in partitions {
+        let path = if partition.is_empty() {
+            Path::new(output_dir).join(&file_name)
+        } else {
+            Path::new(output_dir)
+                .join("tokens")
+                .join(&partition)
+                .join(&file_name)
+        };
+        fs::create_dir_all(path.parent().unwrap()).expect("Can't create output directory");
+
+        match format {
+            OutputFormat::Csv => {
+                utils::write_with_columns(path.to_str().unwrap(), candles, columns, values)
+            }
+            OutputFormat::Parquet => {
+                write_parquet(&path, candles_batch(&candles, columns, metadata))
+            }
+            OutputFormat::Arrow => write_ipc(&path, candles_batch(&candles, columns, metadata)),
+        }
+    }
+}
+
+// Writes the pool candles to `{output_dir}/pools.{ext}`, they aren't partitioned.
+pub fn write_pools(
+    output_dir: &str,
+    format: OutputFormat,
+    candles: Vec<PoolCandlestick>,
+    metadata: HashMap<String, String>,
+) {
+    fs::create_dir_all(output_dir).expect("Can't create output directory");
+    let path = Path::new(output_dir).join(format!("pools.{}", format.extension()));
+
+    match format {
+        OutputFormat::Csv => utils::write(path.to_str().unwrap(), candles),
+        OutputFormat::Parquet => write_parquet(&path, pool_candles_batch(&candles, &metadata)),
+        OutputFormat::Arrow => write_ipc(&path, pool_candles_batch(&candles, &metadata)),
+    }
+}
+
+// Writes the candles of one length to the candles table, replacing the ones of the same tokens
+// there that open in the blocks they cover.
+pub fn save(conn: &PgConnection, len: u64, candles: &[Candlestick]) {
+    let from_block = candles.iter().map(|x| x.open_block_number).min();
+    let to_block = candles.iter().map(|x| x.open_block_number).max();
+    let (from_block, to_block) = match (from_block, to_block) {
+        (Some(from), Some(to)) => (from, to),
+        _ => return,
+    };
+
+    let records = candles
+        .iter()
+        .map(|x| CandleRecord {
+            token_address: format!("{:?}", x.token_address),
+            candlestick_len: len as i64,
+            open_block_number: x.open_block_number as i64,
+            close_block_number: x.close_block_number as i64,
+            token_symbol: x.token_symbol.clone(),
+            open_price: x.open_price,
+            close_price: x.close_price,
+            high_price: x.high_price,
+            low_price: x.low_price,
+            vwap: x.vwap,
+            volume: x.volume,
+            buys_count: x.buys_count as i64,
+            sells_count: x.sells_count as i64,
+            buys_usd: x.buys_usd,
+            sells_usd: x.sells_usd,
+            buy_volume_share: x.buy_volume_share,
+            realized_volatility: x.realized_volatility,
+            target_price: x.target_price,
+            target_valid: x.target_valid,
+        })
+        .collect();
+
+    replace_candles(
+        conn,
+        len as i64,
+        from_block as i64,
+        to_block as i64,
+        records,
+    );
+}
+
+// First block and `YYYY-MM` of every month from `from_block` to `to_block`,
+// the first month starts at block 0 so every candle falls into one.
+// A month starting before `to_block` without a collected block would leave its candles
+// in the month before, so the blocks must be collected through `to_block`.
+fn months(conn: &PgConnection, from_block: u64, to_block: u64) -> Vec<(u64, String)> {
+    let timestamp_at = |block_number: u64| {
+        block_timestamp_at(conn, block_number as i64).unwrap_or_else(|| {
+            panic!(
+                "Month partitioning needs blocks collected up to block {}",
+                block_number
+            )
+        })
+    };
+    let date = DateTime::from_timestamp(timestamp_at(from_block), 0)
+        .unwrap()
+        .date_naive();
+    let end = timestamp_at(to_block);
+
+    let mut month = NaiveDate::from_ymd_opt(date.year(), date.month(), 1).unwrap();
+    let mut months = vec![(0, month.format("%Y-%m").to_string())];
+    loop {
+        month = month + Months::new(1);
+        let start = month.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp();
+        if start > end {
+            break;
+        }
+
+        let block_number = first_block_at(conn, start).unwrap_or_else(|| {
+            panic!(
+                "Month partitioning needs blocks collected up to block {}",
+                to_block
+            )
+        });
+        months.push((block_number as u64, month.format("%Y-%m").to_string()));
+    }
+
+    months
+}
+
+fn write_ipc(path: &Path, batch: RecordBatch) {
+    let file = File::create(path).expect("Can't create arrow file");
+    let mut writer = FileWriter::try_new(file, &batch.schema()).expect("Can't create arrow writer");
+    writer.write(&batch).expect("Can't write arrow batch");
+    writer.finish().expect("Can't close arrow file");
+}
+
+// Extra columns are nullable in every partition, so all partitions share one schema.
+fn candles_batch(
+    candles: &[Candlestick],
+    columns: &[String],
+    metadata: &HashMap<String, String>,
+) -> RecordBatch {
+    let counts = |f: fn(&Candlestick) -> u64| ints(candles, move |x| f(x) as i64);
+
+    let mut arrays: Vec<(&str, ArrayRef, bool)> = vec![
+        ("open_block_number", counts(|x| x.open_block_number), false),
+        (
+            "close_block_number",
+            counts(|x| x.close_block_number),
+            false,
+        ),
+        ("token_symbol", strings(candles, |x| &x.token_symbol), false),
+        (
+            "token_address",
+            Arc::new(StringArray::from_iter_values(
+                candles.iter().map(|x| format!("{:?}", x.token_address)),
+            )),
+            false,
+        ),
+        ("open_price", floats(candles, |x| x.open_price), false),
+        ("close_price", floats(candles, |x| x.close_price), false),
+        ("high_price", floats(candles, |x| x.high_price), false),
+        ("low_price", floats(candles, |x| x.low_price), false),
+        ("vwap", floats(candles, |x| x.vwap), false),
+        ("target_price", floats(candles, |x| x.target_price), false),
+        (
+            "target_valid",
+            Arc::new(BooleanArray::from_iter(
+                candles.iter().map(|x| Some(x.target_valid)),
+            )),
+            false,
+        ),
+        ("volume", floats(candles, |x| x.volume), false),
+        ("buys_count", counts(|x| x.buys_count), false),
+        ("sells_count", counts(|x| x.sells_count), false),
+        ("buys_usd", floats(candles, |x| x.buys_usd), false),
+        ("sells_usd", floats(candles, |x| x.sells_usd), false),
+        (
+            "buy_volume_share",
+            floats(candles, |x| x.buy_volume_share),
+            false,
+        ),
+        (
+            "realized_volatility",
+            floats(candles, |x| x.realized_volatility),
+            false,
+        ),
+        ("open_price_6h", floats(candles, |x| x.open_price_6h), false),
+        ("volume_6h", floats(candles, |x| x.volume_6h), false),
+        ("buys_count_6h", counts(|x| x.buys_count_6h), false),
+        ("sells_count_6h", counts(|x| x.sells_count_6h), false),
+        ("buys_usd_6h", floats(candles, |x| x.buys_usd_6h), false),
+        ("sells_usd_6h", floats(candles, |x| x.sells_usd_6h), false),
+        ("high_price_6h", floats(candles, |x| x.high_price_6h), false),
+        ("low_price_6h", floats(candles, |x| x.low_price_6h), false),
+        (
+            "std_price_change_6h",
+            floats(candles, |x| x.std_price_change_6h),
+            false,
+        ),
+        (
+            "avg_price_change_6h",
+            floats(candles, |x| x.avg_price_change_6h),
+            false,
+        ),
+        ("open_price_1d", floats(candles, |x| x.open_price_1d), false),
+        ("volume_1d", floats(candles, |x| x.volume_1d), false),
+        ("buys_count_1d", counts(|x| x.buys_count_1d), false),
+        ("sells_count_1d", counts(|x| x.sells_count_1d), false),
+        ("buys_usd_1d", floats(candles, |x| x.buys_usd_1d), false),
+        ("sells_usd_1d", floats(candles, |x| x.sells_usd_1d), false),
+        ("high_price_1d", floats(candles, |x| x.high_price_1d), false),
+        ("low_price_1d", floats(candles, |x| x.low_price_1d), false),
+        (
+            "std_price_change_1d",
+            floats(candles, |x| x.std_price_change_1d),
+            false,
+        ),
+        (
+            "avg_price_change_1d",
+            floats(candles, |x| x.avg_price_change_1d),
+            false,
+        ),
+        ("open_price_3d", floats(candles, |x| x.open_price_3d), false),
+        ("volume_3d", floats(candles, |x| x.volume_3d), false),
+        ("buys_count_3d", counts(|x| x.buys_count_3d), false),
+        ("sells_count_3d", counts(|x| x.sells_count_3d), false),
+        ("buys_usd_3d", floats(candles, |x| x.buys_usd_3d), false),
+        ("sells_usd_3d", floats(candles, |x| x.sells_usd_3d), false),
+        ("high_price_3d", floats(candles, |x| x.high_price_3d), false),
+        ("low_price_3d", floats(candles, |x| x.low_price_3d), false),
+        (
+            "std_price_change_3d",
+            floats(candles, |x| x.std_price_change_3d),
+            false,
+        ),
+        (
+            "avg_price_change_3d",
+            floats(candles, |x| x.avg_price_change_3d),
+            false,
+        ),
+        ("buys_count_week", counts(|x| x.buys_count_week), false),
+        ("sells_count_week", counts(|x| x.sells_count_week), false),
+        ("buys_usd_week", floats(candles, |x| x.buys_usd_week), false),
+        (
+            "sells_usd_week",
+            floats(candles, |x| x.sells_usd_week),
+            false,
+        ),
+        ("volume_week", floats(candles, |x| x.volume_week), false),
+        ("holders_count", counts(|x| x.holders_count), false),
+        (
+            "top_holders_share",
+            floats(candles, |x| x.top_holders_share),
+            false,
+        ),
+    ];
+
+    let values: Vec<Vec<Option<f64>>> = candles.iter().map(values).collect();
+    for (i, column) in columns.iter().enumerate() {
+        let array = Float64Array::from_iter(values.iter().map(|x| x[i]));
+        arrays.push((column, Arc::new(array), true));
+    }
+
+    let batch = RecordBatch::try_from_iter_with_nullable(arrays).expect("Invalid candles batch");
+    let schema = batch
+        .schema()
+        .as_ref()
+        .clone()
+        .with_metadata(metadata.clone());
+    batch
+        .with_schema(Arc::new(schema))
+        .expect("Invalid candles schema")
+}
+
+fn pool_candles_batch(
+    candles: &[PoolCandlestick],
+    metadata: &HashMap<String, String>,
+) -> RecordBatch {
+    let counts = |f: fn(&PoolCandlestick) -> u64| ints(candles, move |x| f(x) as i64);
+    let addresses = |f: fn(&PoolCandlestick) -> Address| -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(
+            candles.iter().map(|x| format!("{:?}", f(x))),
+        ))
+    };
+
+    let batch = RecordBatch::try_from_iter(vec![
+        ("open_block_number", counts(|x| x.open_block_number)),
+        ("close_block_number", counts(|x| x.close_block_number)),
+        ("pool_address", addresses(|x| x.pool_address)),
+        ("base_symbol", strings(candles, |x| &x.base_symbol)),
+        ("base_address", addresses(|x| x.base_address)),
+        ("quote_symbol", strings(candles, |x| &x.quote_symbol)),
+        ("quote_address", addresses(|x| x.quote_address)),
+        ("open_price", floats(candles, |x| x.open_price)),
+        ("close_price", floats(candles, |x| x.close_price)),
+        ("high_price", floats(candles, |x| x.high_price)),
+        ("low_price", floats(candles, |x| x.low_price)),
+        ("vwap", floats(candles, |x| x.vwap)),
+        ("trade_high_price", floats(candles, |x| x.trade_high_price)),
+        ("trade_low_price", floats(candles, |x| x.trade_low_price)),
+        ("volume", floats(candles, |x| x.volume)),
+        ("base_volume", floats(candles, |x| x.base_volume)),
+        ("quote_volume", floats(candles, |x| x.quote_volume)),
+        ("buys_count", counts(|x| x.buys_count)),
+        ("sells_count", counts(|x| x.sells_count)),
+        ("buys_usd", floats(candles, |x| x.buys_usd)),
+        ("sells_usd", floats(candles, |x| x.sells_usd)),
+        ("buy_volume_share", floats(candles, |x| x.buy_volume_share)),
+        (
+            "realized_volatility",
+            floats(candles, |x| x.realized_volatility),
+        ),
+        ("reserve_base", floats(candles, |x| x.reserve_base)),
+        ("reserve_quote", floats(candles, |x| x.reserve_quote)),
+        ("liquidity_usd", floats(candles, |x| x.liquidity_usd)),
+    ])
+    .expect("Invalid pool candles batch");
+    let schema = batch
+        .schema()
+        .as_ref()
+        .clone()
+        .with_metadata(metadata.clone());
+    batch
+        .with_schema(Arc::new(schema))
+        .expect("Invalid pool candles schema")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    const TOKEN_A: &str = "0x00000000000000000000000000000000000000aa";
+    const TOKEN_B: &str = "0x00000000000000000000000000000000000000bb";
+
+    fn output_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        dir.to_str().unwrap().to_owned()
+    }
+
+    // Two 300 block candles of two tokens, with one feature column.
+    fn candles() -> Vec<Candlestick> {
+        [TOKEN_A, TOKEN_B]
+            .iter()
+            .flat_map(|token| {
+                [0, 300].map(|open_block_number| Candlestick {
+                    open_block_number,
+                    close_block_number: open_block_number + 299,
+                    token_symbol: "TK".to_owned(),
+                    token_address: token.parse().unwrap(),
+                    features: vec![Some(1.0)],
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    fn csv_header(path: &Path) -> Vec<String> {
+        csv::Reader::from_path(path)
+            .unwrap()
+            .headers()
+            .unwrap()
+            .iter()
+            .map(|x| x.to_owned())
+            .collect()
+    }
+
+    fn parquet_schema(path: &Path) -> arrow::datatypes::SchemaRef {
+        let file = File::open(path).unwrap();
+        ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .schema()
+            .clone()
+    }
+
+    fn column_names(schema: &arrow::datatypes::Schema) -> Vec<String> {
+        schema.fields().iter().map(|x| x.name().clone()).collect()
+    }
+
+    #[test]
+    fn parquet_columns_match_the_csv_header() {
+        let dir = output_dir("candles_output_columns");
+        let columns = vec!["feature".to_owned()];
+        for format in [OutputFormat::Csv, OutputFormat::Parquet] {
+            let partitions = partition(None, candles(), |_, _| unreachable!());
+            write_partitions(&dir, format, partitions, &columns, &HashMap::new());
+            write_pools(
+                &dir,
+                format,
+                vec![PoolCandlestick::default()],
+                HashMap::new(),
+            );
+        }
+
+        let dir = Path::new(&dir);
+        let tokens = csv_header(&dir.join("tokens.csv"));
+        assert_eq!(tokens.last().unwrap(), "feature");
+        assert_eq!(
+            column_names(&parquet_schema(&dir.join("tokens.parquet"))),
+            tokens
+        );
+        assert_eq!(
+            column_names(&parquet_schema(&dir.join("pools.parquet"))),
+            csv_header(&dir.join("pools.csv"))
+        );
+    }
+
+    #[test]
+    fn token_partitions_carry_the_metadata() {
+        let dir = output_dir("candles_output_tokens");
+        let feature_spec = serde_json::json!({ "windows": [6] });
+        let metadata = metadata(300, &feature_spec, "swaps.csv", &candles());
+
+        let partitions = partition(Some(Partitioning::Token), candles(), |_, _| unreachable!());
+        write_partitions(
+            &dir,
+            OutputFormat::Parquet,
+            partitions,
+            &["feature".to_owned()],
+            &metadata,
+        );
+
+        for token in [TOKEN_A, TOKEN_B] {
+            let path = Path::new(&dir)
+                .join("tokens")
+                .join(format!("token_address={}", token))
+                .join("tokens.parquet");
+            let schema = parquet_schema(&path);
+            let metadata = schema.metadata();
+
+            assert_eq!(metadata["candlestick_len"], "300");
+            assert_eq!(metadata["feature_spec"], r#"{"windows":[6]}"#);
+            assert_eq!(metadata["source"], "swaps.csv");
+            assert_eq!(metadata["from_block"], "0");
+            assert_eq!(metadata["to_block"], "599");
+        }
+    }
+
+    #[test]
+    fn month_partitions_follow_the_first_blocks() {
+        let partitions = partition(Some(Partitioning::Month), candles(), |from, to| {
+            assert_eq!((from, to), (0, 599));
+            vec![(0, "2024-01".to_owned()), (300, "2024-02".to_owned())]
+        });
+
+        let layout: Vec<(&str, Vec<u64>)> = partitions
+            .iter()
+            .map(|(name, candles)| {
+                let blocks = candles.iter().map(|x| x.open_block_number).collect();
+                (name.as_str(), blocks)
+            })
+            .collect();
+        assert_eq!(
+            layout,
+            vec![
+                ("month=2024-01", vec![0, 0]),
+                ("month=2024-02", vec![300, 300])
+            ]
+        );
+    }
+}
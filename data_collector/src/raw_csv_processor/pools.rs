@@ -0,0 +1,493 @@
+use super::features::ratio;
+use super::types::{Ohlc, PoolCandlestick, PoolTick, Trades};
+use crate::db::models::{SwapTick, SyncTick};
+use crate::logs_processor::BUY;
+use crate::numeric;
+use std::collections::BTreeMap;
+use web3::types::Address;
+
+struct Pool {
+    base_symbol: String,
+    base_address: Address,
+    quote_symbol: String,
+    quote_address: Address,
+    token0_is_base: bool,
+    // Execution, base USD and quote USD price of the last swap.
+    last_prices: Option<(f64, f64, f64)>,
+    ticks: BTreeMap<u64, PoolTick>,
+}
+
+// Swaps and reserves of every pool, and the candles built from them.
+pub struct Pools {
+    pools: BTreeMap<Address, Pool>,
+    swaps_count: u64,
+    // Reserves after the last sync of each block, in token0/token1 order.
+    reserves: BTreeMap<Address, BTreeMap<u64, (f64, f64)>>,
+    candlesticks: BTreeMap<u64, Vec<PoolCandlestick>>,
+}
+
+impl Pools {
+    pub fn new() -> Self {
+        Self {
+            pools: BTreeMap::new(),
+            swaps_count: 0,
+            reserves: BTreeMap::new(),
+            candlesticks: BTreeMap::new(),
+        }
+    }
+
+    pub fn handle_sync(&mut self, sync: SyncTick) {
+        self.reserves
+            .entry(sync.address.parse().unwrap())
+            .or_default()
+            .insert(
+                sync.block_number as u64,
+                (
+                    numeric::decimal_to_f64(&sync.reserve0),
+                    numeric::decimal_to_f64(&sync.reserve1),
+                ),
+            );
+    }
+
+    // Swaps must come in block and log order.
+    pub fn handle_swap(&mut self, swap: SwapTick) {
+        self.swaps_count += 1;
+        let token0_is_base = swap.token0_address == swap.base_address;
+        let (base_symbol, quote_symbol, quote_address, base_usd_price, quote_usd_price) =
+            if token0_is_base {
+                (
+                    &swap.token0_symbol,
+                    &swap.token1_symbol,
+                    &swap.token1_address,
+                    swap.token0_usd_price,
+                    swap.token1_usd_price,
+                )
+            } else {
+                (
+                    &swap.token1_symbol,
+                    &swap.token0_symbol,
+                    &swap.token0_address,
+                    swap.token1_usd_price,
+                    swap.token0_usd_price,
+                )
+            };
+
+        let pool = self
+            .pools
+            .entry(swap.address.parse().unwrap())
+            .or_insert_with(|| Pool {
+                base_symbol: base_symbol.clone(),
+                base_address: swap.base_address.parse().unwrap(),
+                quote_symbol: quote_symbol.clone(),
+                quote_address: quote_address.parse().unwrap(),
+                token0_is_base,
+                last_prices: None,
+                ticks: BTreeMap::new(),
+            });
+
+        // Both sides at the execution price, with the oracle's USD price of the other side.
+        let execution_price = swap.execution_price;
+        let (base_price, quote_price) = if execution_price > 0.0 {
+            (
+                execution_price * quote_usd_price,
+                base_usd_price / execution_price,
+            )
+        } else {
+            (base_usd_price, quote_usd_price)
+        };
+        let (execution_return, base_return, quote_return) = match pool.last_prices {
+            Some((execution, base, quote)) => (
+                log_return(execution, execution_price),
+                log_return(base, base_price),
+                log_return(quote, quote_price),
+            ),
+            None => (0.0, 0.0, 0.0),
+        };
+        pool.last_prices = Some((execution_price, base_price, quote_price));
+
+        let tick = pool
+            .ticks
+            .entry(swap.block_number as u64)
+            .or_insert_with(|| PoolTick {
+                first_swap: self.swaps_count,
+                execution: Ohlc::new(execution_price),
+                base: Trades::new(base_price),
+                quote: Trades::new(quote_price),
+                ..Default::default()
+            });
+
+        tick.last_swap = self.swaps_count;
+        tick.execution.push(execution_price);
+        tick.squared_returns += execution_return * execution_return;
+        tick.base.push(
+            base_price,
+            numeric::decimal_to_f64(&swap.base_amount),
+            base_return,
+        );
+        tick.quote.push(
+            quote_price,
+            numeric::decimal_to_f64(&swap.quote_amount),
+            quote_return,
+        );
+        tick.base_usd_price = base_usd_price;
+        tick.quote_usd_price = quote_usd_price;
+        tick.volume += swap.notional_usd;
+
+        if swap.side == BUY {
+            tick.buys_count += 1;
+            tick.buys_usd += swap.notional_usd;
+        } else {
+            tick.sells_count += 1;
+            tick.sells_usd += swap.notional_usd;
+        }
+    }
+
+    // Candles of every pool at each length, longer ones rolled up from the shortest.
+    // Intervals without swaps have no candle.
+    pub fn build_candlesticks(&mut self, candlestick_lens: &[u64]) {
+        let base_len = candlestick_lens[0];
+
+        for (address, pool) in &self.pools {
+            let reserves = self.reserves.get(address);
+            let base = pool_candlesticks(*address, pool, reserves, base_len);
+
+            for &len in candlestick_lens {
+                let candlesticks = if len == base_len {
+                    base.clone()
+                } else {
+                    roll_up(&base, len)
+                };
+
+                self.candlesticks
+                    .entry(len)
+                    .or_default()
+                    .extend(candlesticks);
+            }
+        }
+    }
+
+    // Candles of one length, sorted by pool and open block.
+    pub fn candlesticks(&self, len: u64) -> &[PoolCandlestick] {
+        self.candlesticks.get(&len).map_or(&[], |x| x.as_slice())
+    }
+
+    // Candles of every length, shortest first, each sorted by open block.
+    pub fn to_vec(&self) -> Vec<(u64, Vec<PoolCandlestick>)> {
+        self.candlesticks
+            .iter()
+            .map(|(len, candlesticks)| {
+                let mut candlesticks = candlesticks.clone();
+                candlesticks.sort_by_key(|x| x.open_block_number);
+                (*len, candlesticks)
+            })
+            .collect()
+    }
+}
+
+// The pool's price is taken from its reserves as of the tick's block, the execution price of the
+// block's last swap stands in until the pool has a sync.
+fn pool_candlesticks(
+    address: Address,
+    pool: &Pool,
+    reserves: Option<&BTreeMap<u64, (f64, f64)>>,
+    len: u64,
+) -> Vec<PoolCandlestick> {
+    let mut candlesticks: Vec<PoolCandlestick> = Vec::new();
+
+    for (block_number, tick) in &pool.ticks {
+        let (reserve_base, reserve_quote) = reserves
+            .and_then(|x| x.range(..=block_number).next_back())
+            .map_or((0.0, 0.0), |(_, &(reserve0, reserve1))| {
+                if pool.token0_is_base {
+                    (reserve0, reserve1)
+                } else {
+                    (reserve1, reserve0)
+                }
+            });
+        let price = if reserve_base > 0.0 {
+            reserve_quote / reserve_base
+        } else {
+            tick.execution.close
+        };
+
+        let candlestick = PoolCandlestick {
+            open_block_number: block_number - block_number % len,
+            close_block_number: block_number - block_number % len + len - 1,
+            pool_address: address,
+            base_symbol: pool.base_symbol.clone(),
+            base_address: pool.base_address,
+            quote_symbol: pool.quote_symbol.clone(),
+            quote_address: pool.quote_address,
+            open_price: price,
+            close_price: price,
+            high_price: price,
+            low_price: price,
+            vwap: ratio(tick.quote.amount, tick.base.amount).unwrap_or(tick.execution.close),
+            trade_high_price: tick.execution.high,
+            trade_low_price: tick.execution.low,
+            volume: tick.volume,
+            base_volume: tick.base.amount,
+            quote_volume: tick.quote.amount,
+            buys_count: tick.buys_count,
+            sells_count: tick.sells_count,
+            buys_usd: tick.buys_usd,
+            sells_usd: tick.sells_usd,
+            buy_volume_share: ratio(tick.buys_usd, tick.volume).unwrap_or(0.0),
+            realized_volatility: tick.squared_returns.sqrt(),
+            reserve_base,
+            reserve_quote,
+            liquidity_usd: reserve_base * tick.base_usd_price
+                + reserve_quote * tick.quote_usd_price,
+            first_swap: tick.first_swap,
+            last_swap: tick.last_swap,
+            base: tick.base.clone(),
+            quote: tick.quote.clone(),
+        };
+
+        match candlesticks.last_mut() {
+            Some(last) if last.open_block_number == candlestick.open_block_number => {
+                merge(last, &candlestick)
+            }
+            _ => candlesticks.push(candlestick),
+        }
+    }
+
+    candlesticks
+}
+
+// Candles of `len` blocks from the shorter, sorted candles of one pool.
+fn roll_up(candlesticks: &[PoolCandlestick], len: u64) -> Vec<PoolCandlestick> {
+    let mut rolled_up: Vec<PoolCandlestick> = Vec::new();
+
+    for candlestick in candlesticks {
+        let open_block_number = candlestick.open_block_number - candlestick.open_block_number % len;
+
+        match rolled_up.last_mut() {
+            Some(last) if last.open_block_number == open_block_number => merge(last, candlestick),
+            _ => rolled_up.push(PoolCandlestick {
+                open_block_number,
+                close_block_number: open_block_number + len - 1,
+                ..candlestick.clone()
+            }),
+        }
+    }
+
+    rolled_up
+}
+
+// Extends a pool's candle with a later one of the same pool.
+fn merge(candlestick: &mut PoolCandlestick, other: &PoolCandlestick) {
+    candlestick.close_price = other.close_price;
+    candlestick.high_price = f64::max(candlestick.high_price, other.high_price);
+    candlestick.low_price = f64::min(candlestick.low_price, other.low_price);
+    candlestick.trade_high_price = f64::max(candlestick.trade_high_price, other.trade_high_price);
+    candlestick.trade_low_price = f64::min(candlestick.trade_low_price, other.trade_low_price);
+    candlestick.volume += other.volume;
+    candlestick.base_volume += other.base_volume;
+    candlestick.quote_volume += other.quote_volume;
+    candlestick.buys_count += other.buys_count;
+    candlestick.sells_count += other.sells_count;
+    candlestick.buys_usd += other.buys_usd;
+    candlestick.sells_usd += other.sells_usd;
+    candlestick.reserve_base = other.reserve_base;
+    candlestick.reserve_quote = other.reserve_quote;
+    candlestick.liquidity_usd = other.liquidity_usd;
+    candlestick.last_swap = other.last_swap;
+    candlestick.base.merge(&other.base);
+    candlestick.quote.merge(&other.quote);
+
+    candlestick.vwap =
+        ratio(candlestick.quote_volume, candlestick.base_volume).unwrap_or(candlestick.vwap);
+    candlestick.buy_volume_share = ratio(candlestick.buys_usd, candlestick.volume).unwrap_or(0.0);
+    candlestick.realized_volatility =
+        f64::hypot(candlestick.realized_volatility, other.realized_volatility);
+}
+
+// Zero when either price is unknown.
+fn log_return(from: f64, to: f64) -> f64 {
+    if from > 0.0 && to > 0.0 {
+        (to / from).ln()
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bigdecimal::BigDecimal;
+
+    const TOKEN: &str = "0x00000000000000000000000000000000000000aa";
+    const WETH: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+    const POOL: &str = "0x00000000000000000000000000000000000a0001";
+
+    // TKA/WETH when `token0_is_base`, WETH/TKA otherwise.
+    fn tokens(token0_is_base: bool) -> [(&'static str, &'static str); 2] {
+        if token0_is_base {
+            [("TKA", TOKEN), ("WETH", WETH)]
+        } else {
+            [("WETH", WETH), ("TKA", TOKEN)]
+        }
+    }
+
+    fn sync(
+        token0_is_base: bool,
+        block_number: i64,
+        reserve_base: f64,
+        reserve_quote: f64,
+    ) -> SyncTick {
+        let [(token0_symbol, token0), (token1_symbol, token1)] = tokens(token0_is_base);
+        let (reserve0, reserve1) = if token0_is_base {
+            (reserve_base, reserve_quote)
+        } else {
+            (reserve_quote, reserve_base)
+        };
+
+        SyncTick {
+            token0_symbol: token0_symbol.to_owned(),
+            token1_symbol: token1_symbol.to_owned(),
+            token0_address: token0.to_owned(),
+            token1_address: token1.to_owned(),
+            block_number,
+            address: POOL.to_owned(),
+            reserve0: BigDecimal::from(reserve0),
+            reserve1: BigDecimal::from(reserve1),
+            token0_usd_price: 0.0,
+            token1_usd_price: 0.0,
+            transaction_hash: None,
+            log_index: None,
+            cause: None,
+        }
+    }
+
+    // A swap at `price` WETH per TKA, with WETH at 2000 USD.
+    fn swap(
+        token0_is_base: bool,
+        block_number: i64,
+        price: f64,
+        base_amount: f64,
+        bought: bool,
+    ) -> SwapTick {
+        let [(token0_symbol, token0), (token1_symbol, token1)] = tokens(token0_is_base);
+        let (token0_usd_price, token1_usd_price) = if token0_is_base {
+            (price * 2000.0, 2000.0)
+        } else {
+            (2000.0, price * 2000.0)
+        };
+
+        SwapTick {
+            token0_symbol: token0_symbol.to_owned(),
+            token1_symbol: token1_symbol.to_owned(),
+            token0_address: token0.to_owned(),
+            token1_address: token1.to_owned(),
+            block_number,
+            address: POOL.to_owned(),
+            sender: POOL.to_owned(),
+            amount0_in: BigDecimal::from(0),
+            amount0_out: BigDecimal::from(0),
+            amount1_in: BigDecimal::from(0),
+            amount1_out: BigDecimal::from(0),
+            token0_usd_price,
+            token1_usd_price,
+            transaction_hash: None,
+            origin: None,
+            log_index: None,
+            base_address: TOKEN.to_owned(),
+            side: if bought { BUY } else { "sell" }.to_owned(),
+            base_amount: BigDecimal::from(base_amount),
+            quote_amount: BigDecimal::from(base_amount * price),
+            execution_price: price,
+            notional_usd: base_amount * price * 2000.0,
+            price_impact: 0.0,
+        }
+    }
+
+    #[test]
+    fn execution_price_stands_in_until_the_first_sync() {
+        let mut pools = Pools::new();
+        pools.handle_swap(swap(true, 100, 0.002, 10.0, true));
+        pools.handle_sync(sync(true, 400, 1000.0, 3.0));
+        pools.handle_swap(swap(true, 400, 0.0031, 10.0, true));
+        pools.handle_swap(swap(true, 700, 0.0029, 10.0, false));
+        pools.build_candlesticks(&[300]);
+
+        let candlesticks = pools.candlesticks(300);
+        let closes: Vec<_> = candlesticks.iter().map(|x| x.close_price).collect();
+        assert_eq!(closes, vec![0.002, 0.003, 0.003]);
+        assert_eq!(
+            (candlesticks[0].reserve_base, candlesticks[0].liquidity_usd),
+            (0.0, 0.0)
+        );
+        // The trades still go by their execution prices.
+        assert_eq!(candlesticks[2].trade_high_price, 0.0029);
+    }
+
+    #[test]
+    fn token1_base_pools_are_turned_around() {
+        let mut pools = Pools::new();
+        pools.handle_sync(sync(false, 100, 1000.0, 3.0));
+        pools.handle_swap(swap(false, 100, 0.0031, 10.0, true));
+        pools.build_candlesticks(&[300]);
+
+        let candlestick = &pools.candlesticks(300)[0];
+        assert_eq!(candlestick.base_symbol, "TKA");
+        assert_eq!(candlestick.base_address, TOKEN.parse::<Address>().unwrap());
+        assert_eq!(candlestick.quote_address, WETH.parse::<Address>().unwrap());
+        assert_eq!(
+            (candlestick.reserve_base, candlestick.reserve_quote),
+            (1000.0, 3.0)
+        );
+        assert_eq!(candlestick.close_price, 0.003);
+        assert_eq!(
+            candlestick.liquidity_usd,
+            1000.0 * (0.0031 * 2000.0) + 3.0 * 2000.0
+        );
+    }
+
+    #[test]
+    fn rolled_up_candles_match_the_ones_built_directly() {
+        let mut rolled_up = Pools::new();
+        let mut direct = Pools::new();
+        for i in 0..40 {
+            let block_number = 100 + i * 90;
+            let price = 0.001 * (1.0 + ((i * 7) % 11) as f64 / 10.0);
+            let base_amount = 10.0 + (i % 5) as f64 * 3.0;
+            for pools in [&mut rolled_up, &mut direct] {
+                if i % 4 == 1 {
+                    pools.handle_sync(sync(true, block_number, 1000.0 + i as f64, price * 1000.0));
+                }
+                pools.handle_swap(swap(true, block_number, price, base_amount, i % 3 != 0));
+            }
+        }
+        rolled_up.build_candlesticks(&[300, 1800]);
+        direct.build_candlesticks(&[1800]);
+
+        let rolled_up = rolled_up.candlesticks(1800);
+        let direct = direct.candlesticks(1800);
+        assert_eq!(rolled_up.len(), 3);
+        assert_eq!(rolled_up.len(), direct.len());
+
+        for (x, y) in rolled_up.iter().zip(direct) {
+            assert_eq!(x.open_block_number, y.open_block_number);
+            assert_eq!(x.close_block_number, y.close_block_number);
+            assert_eq!(
+                (x.open_price, x.high_price, x.low_price, x.close_price),
+                (y.open_price, y.high_price, y.low_price, y.close_price)
+            );
+            assert_eq!(
+                (x.trade_high_price, x.trade_low_price),
+                (y.trade_high_price, y.trade_low_price)
+            );
+            assert_eq!(
+                (x.reserve_base, x.reserve_quote, x.liquidity_usd),
+                (y.reserve_base, y.reserve_quote, y.liquidity_usd)
+            );
+            assert_eq!((x.buys_count, x.sells_count), (y.buys_count, y.sells_count));
+            // Sums are taken in a different order.
+            assert!((x.volume - y.volume).abs() < 1e-9);
+            assert!((x.base_volume - y.base_volume).abs() < 1e-9);
+            assert!((x.vwap - y.vwap).abs() < 1e-12);
+            assert!((x.realized_volatility - y.realized_volatility).abs() < 1e-12);
+        }
+    }
+}
@@ -1,6 +1,9 @@
-use super::types::Candlestick;
-use super::types::TokenTick;
-use crate::db::models::SwapTick;
+use super::context::{self, Anchor};
+use super::features::{self, ratio, FeatureConfig};
+use super::labels::{self, LabelConfig};
+use super::pools::Pools;
+use super::types::{Candlestick, PoolCandlestick, Trades};
+use crate::db::models::{HolderStats, SwapTick, SyncTick};
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::VecDeque;
@@ -8,203 +11,384 @@ use ta::{indicators::ExponentialMovingAverage, Next};
 use web3::types::Address;
 
 pub struct Tokens {
-    candlestick_len: u64,
-    agr_token_ticks: HashMap<Address, BTreeMap<u64, TokenTick>>,
-    candlesticks: Vec<Candlestick>,
+    // Sorted, the first one is built from the pools and the others are rolled up from it.
+    candlestick_lens: Vec<u64>,
+    max_gap: u64,
+    label_config: LabelConfig,
+    pools: Pools,
+    candlesticks: BTreeMap<u64, Vec<Candlestick>>,
 }
 
 impl Tokens {
-    pub fn new(candlestick_len: u64) -> Self {
+    pub fn new(candlestick_lens: &[u64], max_gap: u64, label_config: LabelConfig) -> Self {
+        let mut candlestick_lens = candlestick_lens.to_vec();
+        candlestick_lens.sort();
+        candlestick_lens.dedup();
+
+        let base_len = *candlestick_lens.first().expect("No candle length");
+        assert!(
+            candlestick_lens.iter().all(|x| x % base_len == 0),
+            "Candle lengths must be multiples of the shortest one"
+        );
+
         Self {
-            candlestick_len: candlestick_len,
-            agr_token_ticks: HashMap::new(),
-            candlesticks: Vec::new(),
+            candlestick_lens,
+            max_gap,
+            label_config,
+            pools: Pools::new(),
+            candlesticks: BTreeMap::new(),
         }
     }
 
-    pub fn handle_swap(&mut self, swap: SwapTick) {
-        let volume =
-            swap.token0_usd_price * swap.amount0_in + swap.token1_usd_price * swap.amount1_in;
-
-        self.update(
-            swap.block_number as u64,
-            &swap.token0_symbol,
-            swap.token0_address.parse().unwrap(),
-            swap.token0_usd_price,
-            swap.amount0_in,
-            swap.amount0_out,
-            volume,
-        );
-
-        self.update(
-            swap.block_number as u64,
-            &swap.token1_symbol,
-            swap.token1_address.parse().unwrap(),
-            swap.token1_usd_price,
-            swap.amount1_in,
-            swap.amount1_out,
-            volume,
-        );
+    // Syncs only give the pools their reserves, they must all be handled before building.
+    pub fn handle_sync(&mut self, sync: SyncTick) {
+        self.pools.handle_sync(sync);
     }
 
-    fn update(
-        &mut self,
-        block_number: u64,
-        token_symbol: &str,
-        token_address: Address,
-        price: f64,
-        amount_in: f64,
-        amount_out: f64,
-        volume: f64,
-    ) {
-        if !self.agr_token_ticks.contains_key(&token_address) {
-            self.agr_token_ticks.insert(token_address, BTreeMap::new());
-        }
-
-        let agr_blocks = self.agr_token_ticks.get_mut(&token_address).unwrap();
-        if !agr_blocks.contains_key(&block_number) {
-            agr_blocks.insert(
-                block_number,
-                TokenTick {
-                    block_number: block_number,
-                    token_symbol: token_symbol.to_owned(),
-                    token_address: token_address,
-                    price: price,
-                    next_candle_close_price: 0.0,
-                    volume: 0.0,
-                    buys_count: 0,
-                    sells_count: 0,
-                    buys_usd: 0.0,
-                    sells_usd: 0.0,
-                },
-            );
-        }
-
-        let token_tick = agr_blocks.get_mut(&block_number).unwrap();
-        token_tick.price = price;
-        token_tick.volume += volume;
-
-        if amount_in != 0.0 {
-            token_tick.buys_count += 1;
-        }
-
-        if amount_out != 0.0 {
-            token_tick.sells_count += 1;
-        }
+    pub fn handle_swap(&mut self, swap: SwapTick) {
+        self.pools.handle_swap(swap);
+    }
 
-        token_tick.buys_usd += amount_in * price;
-        token_tick.sells_usd += amount_out * price;
+    pub fn pools(&self) -> &Pools {
+        &self.pools
     }
 
+    // Builds the pool candles, then the token candles of every length: the shortest rolled up
+    // from the pools the token trades in, longer ones by rolling the shortest up, so they agree on
+    // OHLC, volume and counts.
+    // Rolling windows, targets and labels are computed at each length from its own candles.
+    // A target is only valid when the next candle is the adjacent one.
     pub fn build_candlesticks(&mut self) {
-        for (_, ticks) in &self.agr_token_ticks {
-            let first_tick = match ticks.first_key_value() {
-                Some(r) => r.1,
-                None => continue,
-            };
+        self.pools.build_candlesticks(&self.candlestick_lens);
 
-            let mut current_interval_start =
-                first_tick.block_number - (first_tick.block_number % self.candlestick_len);
+        let base_len = self.candlestick_lens[0];
+        let token_candlesticks = token_candlesticks(self.pools.candlesticks(base_len));
 
-            let mut bucket: Vec<TokenTick> = Vec::new();
-            let start_idx = self.candlesticks.len();
+        for candlesticks in token_candlesticks.values() {
+            let base = self.fill_gaps(candlesticks);
 
-            let blocks_in_hour = 300u64;
+            for &len in &self.candlestick_lens {
+                let mut candlesticks = if len == base_len {
+                    base.clone()
+                } else {
+                    roll_up(&base, len)
+                };
 
-            let mut window_6h = Window::new(blocks_in_hour * 6);
-            let mut window_1d = Window::new(blocks_in_hour * 24);
-            let mut window_3d = Window::new(blocks_in_hour * 24 * 3);
-            let mut big_window = BigWindow::new(blocks_in_hour * 24 * 7);
+                let mut windows = Windows::new();
+                for candlestick in &mut candlesticks {
+                    windows.add(candlestick);
+                }
+
+                for i in 1..candlesticks.len() {
+                    if candlesticks[i].open_block_number
+                        == candlesticks[i - 1].open_block_number + len
+                    {
+                        candlesticks[i - 1].target_price = candlesticks[i].close_price;
+                        candlesticks[i - 1].target_valid = true;
+                    }
+                }
 
-            for (block_number, tick) in ticks {
-                let interval_start = block_number - (block_number % self.candlestick_len);
+                labels::label(&mut candlesticks, len, &self.label_config);
 
-                if interval_start != current_interval_start {
-                    if !bucket.is_empty() {
-                        let mut candlestick = self.build_candlestick(bucket.clone());
+                self.candlesticks
+                    .entry(len)
+                    .or_default()
+                    .extend(candlesticks);
+            }
+        }
+    }
 
-                        window_6h.add(candlestick.clone());
-                        window_6h.fill_6h(&mut candlestick);
+    // Intervals without swaps are skipped, unless the gap they make is at most `max_gap` blocks:
+    // then they get empty candles at the last close.
+    fn fill_gaps(&self, candlesticks: &BTreeMap<u64, Candlestick>) -> Vec<Candlestick> {
+        let len = self.candlestick_lens[0];
+        let mut filled: Vec<Candlestick> = Vec::new();
+
+        for candlestick in candlesticks.values() {
+            if let Some(last) = filled.last() {
+                let gap = candlestick.open_block_number - (last.open_block_number + len);
+
+                if gap > 0 && gap <= self.max_gap {
+                    let mut open_block_number = last.open_block_number;
+                    let close_price = last.close_price;
+
+                    for _ in 0..gap / len {
+                        open_block_number += len;
+                        filled.push(self.empty_candlestick(
+                            candlestick,
+                            open_block_number,
+                            close_price,
+                        ));
+                    }
+                }
+            }
 
-                        window_1d.add(candlestick.clone());
-                        window_1d.fill_1d(&mut candlestick);
+            filled.push(candlestick.clone());
+        }
 
-                        window_3d.add(candlestick.clone());
-                        window_3d.fill_3d(&mut candlestick);
+        filled
+    }
 
-                        big_window.add(candlestick.clone());
-                        big_window.fill(&mut candlestick);
+    // An interval without swaps: no volume, the price carried from the last close.
+    fn empty_candlestick(
+        &self,
+        candlestick: &Candlestick,
+        open_block_number: u64,
+        price: f64,
+    ) -> Candlestick {
+        Candlestick {
+            open_block_number,
+            close_block_number: open_block_number + self.candlestick_lens[0] - 1,
+            token_symbol: candlestick.token_symbol.clone(),
+            token_address: candlestick.token_address,
+            open_price: price,
+            close_price: price,
+            high_price: price,
+            low_price: price,
+            vwap: price,
+            ..Default::default()
+        }
+    }
 
-                        self.candlesticks.push(candlestick);
-                        bucket.clear();
-                    }
+    // Holder stats are the state at the close of their interval, so a candle gets the latest
+    // ones closed by its own close and never balances from after it.
+    pub fn fill_holders(&mut self, holders: Vec<HolderStats>) {
+        let mut token_holders: HashMap<Address, BTreeMap<u64, HolderStats>> = HashMap::new();
+        for stats in holders {
+            token_holders
+                .entry(stats.token_address.parse().unwrap())
+                .or_default()
+                .insert(stats.close_block_number, stats);
+        }
 
-                    current_interval_start = interval_start;
-                }
+        for candlestick in self.candlesticks.values_mut().flatten() {
+            let stats = match token_holders.get(&candlestick.token_address) {
+                Some(r) => r,
+                None => continue,
+            };
 
-                bucket.push(tick.clone());
+            if let Some((_, stats)) = stats.range(..=candlestick.close_block_number).next_back() {
+                candlestick.holders_count = stats.holders_count;
+                candlestick.top_holders_share = stats.top_holders_share;
             }
+        }
+    }
 
-            if !bucket.is_empty() {
-                let mut candlestick = self.build_candlestick(bucket.clone());
-                window_6h.add(candlestick.clone());
-                window_6h.fill_6h(&mut candlestick);
+    pub fn fill_features(&mut self, config: &FeatureConfig) {
+        for candlesticks in self.candlesticks.values_mut() {
+            features::fill(candlesticks, config);
+        }
+    }
 
-                window_1d.add(candlestick.clone());
-                window_1d.fill_1d(&mut candlestick);
+    // Breadth is taken over the candles of the same length.
+    pub fn fill_context(&mut self, anchors: &[Anchor], std_floor: f64) {
+        for candlesticks in self.candlesticks.values_mut() {
+            context::fill(candlesticks, anchors, std_floor);
+        }
+    }
 
-                window_3d.add(candlestick.clone());
-                window_3d.fill_3d(&mut candlestick);
+    // Candles of every length, shortest first, each sorted by open block.
+    pub fn to_vec(&self) -> Vec<(u64, Vec<Candlestick>)> {
+        self.candlesticks
+            .iter()
+            .map(|(len, candlesticks)| {
+                let mut candlesticks = candlesticks.clone();
+                candlesticks.sort_by_key(|x| x.open_block_number);
+                (*len, candlesticks)
+            })
+            .collect()
+    }
+}
 
-                big_window.add(candlestick.clone());
-                big_window.fill(&mut candlestick);
+// A token candle being merged from the pools, with the swap positions its open and close came from.
+struct TokenCandlestick {
+    candlestick: Candlestick,
+    first_swap: u64,
+    last_swap: u64,
+    trades: Trades,
+}
 
-                self.candlesticks.push(candlestick);
+// Every token's candles from the candles of the pools it trades in, at the same length.
+// Open and close are the token's trade prices in the pool with the first and the last swap of the
+// interval, volume, counts and squared returns are summed, a swap being a buy of the token when
+// the trader received it.
+fn token_candlesticks(
+    pool_candlesticks: &[PoolCandlestick],
+) -> HashMap<Address, BTreeMap<u64, Candlestick>> {
+    let mut tokens: HashMap<Address, BTreeMap<u64, TokenCandlestick>> = HashMap::new();
+
+    for pool in pool_candlesticks {
+        let sides = [
+            (
+                &pool.base_symbol,
+                pool.base_address,
+                &pool.base,
+                (pool.buys_count, pool.buys_usd),
+                (pool.sells_count, pool.sells_usd),
+            ),
+            (
+                &pool.quote_symbol,
+                pool.quote_address,
+                &pool.quote,
+                (pool.sells_count, pool.sells_usd),
+                (pool.buys_count, pool.buys_usd),
+            ),
+        ];
+
+        for (symbol, address, trades, buys, sells) in sides {
+            let token = tokens
+                .entry(address)
+                .or_default()
+                .entry(pool.open_block_number)
+                .or_insert_with(|| TokenCandlestick {
+                    candlestick: Candlestick {
+                        open_block_number: pool.open_block_number,
+                        close_block_number: pool.close_block_number,
+                        token_symbol: symbol.clone(),
+                        token_address: address,
+                        open_price: trades.usd.open,
+                        close_price: trades.usd.close,
+                        high_price: trades.usd.high,
+                        low_price: trades.usd.low,
+                        ..Default::default()
+                    },
+                    first_swap: pool.first_swap,
+                    last_swap: pool.last_swap,
+                    trades: Trades::default(),
+                });
+
+            let candlestick = &mut token.candlestick;
+            if pool.first_swap < token.first_swap {
+                candlestick.open_price = trades.usd.open;
+                token.first_swap = pool.first_swap;
+            }
+            if pool.last_swap > token.last_swap {
+                candlestick.close_price = trades.usd.close;
+                token.last_swap = pool.last_swap;
             }
+            candlestick.high_price = f64::max(candlestick.high_price, trades.usd.high);
+            candlestick.low_price = f64::min(candlestick.low_price, trades.usd.low);
+            candlestick.volume += pool.volume;
+            candlestick.buys_count += buys.0;
+            candlestick.buys_usd += buys.1;
+            candlestick.sells_count += sells.0;
+            candlestick.sells_usd += sells.1;
+
+            token.trades.amount += trades.amount;
+            token.trades.value_usd += trades.value_usd;
+            // Returns stay within their pool, a switch between pools is no return.
+            token.trades.squared_returns += trades.squared_returns;
+        }
+    }
+
+    tokens
+        .into_iter()
+        .map(|(address, candlesticks)| {
+            let candlesticks = candlesticks
+                .into_iter()
+                .map(|(open_block_number, token)| {
+                    let (mut candlestick, trades) = (token.candlestick, token.trades);
+                    candlestick.vwap =
+                        ratio(trades.value_usd, trades.amount).unwrap_or(candlestick.close_price);
+                    candlestick.buy_volume_share =
+                        ratio(candlestick.buys_usd, candlestick.volume).unwrap_or(0.0);
+                    candlestick.realized_volatility = trades.squared_returns.sqrt();
+                    candlestick.amount = trades.amount;
+                    (open_block_number, candlestick)
+                })
+                .collect();
+            (address, candlesticks)
+        })
+        .collect()
+}
 
-            for i in start_idx + 1..self.candlesticks.len() {
-                self.candlesticks[i - 1].target_price = self.candlesticks[i].close_price;
+// Candles of `len` blocks from the shorter, sorted candles of one token.
+fn roll_up(candlesticks: &[Candlestick], len: u64) -> Vec<Candlestick> {
+    let mut rolled_up: Vec<Candlestick> = Vec::new();
+
+    for candlestick in candlesticks {
+        let open_block_number = candlestick.open_block_number - candlestick.open_block_number % len;
+
+        match rolled_up.last_mut() {
+            Some(last) if last.open_block_number == open_block_number => {
+                last.close_price = candlestick.close_price;
+                last.high_price = f64::max(last.high_price, candlestick.high_price);
+                last.low_price = f64::min(last.low_price, candlestick.low_price);
+                last.volume += candlestick.volume;
+                last.buys_count += candlestick.buys_count;
+                last.sells_count += candlestick.sells_count;
+                last.buys_usd += candlestick.buys_usd;
+                last.sells_usd += candlestick.sells_usd;
+
+                let amount = last.amount + candlestick.amount;
+                if amount > 0.0 {
+                    last.vwap =
+                        (last.vwap * last.amount + candlestick.vwap * candlestick.amount) / amount;
+                }
+                last.amount = amount;
+                last.buy_volume_share = ratio(last.buys_usd, last.volume).unwrap_or(0.0);
+                last.realized_volatility =
+                    f64::hypot(last.realized_volatility, candlestick.realized_volatility);
             }
+            _ => rolled_up.push(Candlestick {
+                open_block_number,
+                close_block_number: open_block_number + len - 1,
+                token_symbol: candlestick.token_symbol.clone(),
+                token_address: candlestick.token_address,
+                open_price: candlestick.open_price,
+                close_price: candlestick.close_price,
+                high_price: candlestick.high_price,
+                low_price: candlestick.low_price,
+                vwap: candlestick.vwap,
+                volume: candlestick.volume,
+                buys_count: candlestick.buys_count,
+                sells_count: candlestick.sells_count,
+                buys_usd: candlestick.buys_usd,
+                sells_usd: candlestick.sells_usd,
+                buy_volume_share: candlestick.buy_volume_share,
+                realized_volatility: candlestick.realized_volatility,
+                amount: candlestick.amount,
+                ..Default::default()
+            }),
         }
     }
 
-    fn build_candlestick(&self, bucket: Vec<TokenTick>) -> Candlestick {
-        let mut candlestick = Candlestick {
-            open_block_number: bucket[0].block_number
-                - bucket[0].block_number % self.candlestick_len,
-            close_block_number: bucket[0].block_number
-                - bucket[0].block_number % self.candlestick_len
-                + self.candlestick_len
-                - 1,
-            token_symbol: bucket[0].token_symbol.clone(),
-            token_address: bucket[0].token_address,
-            open_price: bucket[0].price,
-            close_price: bucket.last().unwrap().price,
-            ..Default::default()
-        };
+    rolled_up
+}
 
-        candlestick.high_price = i32::MIN as f64;
-        candlestick.low_price = i32::MAX as f64;
+// The rolling windows of one token's candles.
+struct Windows {
+    window_6h: Window,
+    window_1d: Window,
+    window_3d: Window,
+    big_window: BigWindow,
+}
 
-        for tick in bucket {
-            candlestick.volume += tick.volume;
-            candlestick.buys_count += tick.buys_count;
-            candlestick.sells_count += tick.sells_count;
-            candlestick.buys_usd += tick.buys_usd;
-            candlestick.sells_usd += tick.sells_usd;
+impl Windows {
+    fn new() -> Self {
+        let blocks_in_hour = 300u64;
 
-            candlestick.high_price = f64::max(candlestick.high_price, tick.price);
-            candlestick.low_price = f64::min(candlestick.low_price, tick.price);
+        Self {
+            window_6h: Window::new(blocks_in_hour * 6),
+            window_1d: Window::new(blocks_in_hour * 24),
+            window_3d: Window::new(blocks_in_hour * 24 * 3),
+            big_window: BigWindow::new(blocks_in_hour * 24 * 7),
         }
-
-        return candlestick;
     }
 
-    pub fn to_vec(&self) -> Vec<Candlestick> {
-        let mut candlesticks = self.candlesticks.clone();
-        candlesticks.sort_by_key(|x| x.open_block_number);
+    // Adds the candle to every window and fills its rolling features from them.
+    fn add(&mut self, candlestick: &mut Candlestick) {
+        self.window_6h.add(candlestick.clone());
+        self.window_6h.fill_6h(candlestick);
+
+        self.window_1d.add(candlestick.clone());
+        self.window_1d.fill_1d(candlestick);
 
-        return candlesticks;
+        self.window_3d.add(candlestick.clone());
+        self.window_3d.fill_3d(candlestick);
+
+        self.big_window.add(candlestick.clone());
+        self.big_window.fill(candlestick);
     }
 }
 
@@ -451,3 +635,149 @@ fn std_deviation(data: &[f64]) -> Option<f64> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::labels::StdWindow;
+    use super::*;
+    use crate::logs_processor::BUY;
+    use bigdecimal::BigDecimal;
+
+    const TOKEN: &str = "0x00000000000000000000000000000000000000aa";
+    const WETH: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+    const POOL: &str = "0x00000000000000000000000000000000000a0001";
+
+    fn tokens(candlestick_lens: &[u64], max_gap: u64) -> Tokens {
+        let label_config = LabelConfig {
+            horizons: Vec::new(),
+            std_window: StdWindow::SixHours,
+            take_profit: None,
+            stop_loss: None,
+        };
+
+        Tokens::new(candlestick_lens, max_gap, label_config)
+    }
+
+    // A swap of TKA for WETH at `price` WETH per TKA, with WETH at 2000 USD.
+    fn swap(block_number: i64, price: f64, base_amount: f64, bought: bool) -> SwapTick {
+        SwapTick {
+            token0_symbol: "TKA".to_owned(),
+            token1_symbol: "WETH".to_owned(),
+            token0_address: TOKEN.to_owned(),
+            token1_address: WETH.to_owned(),
+            block_number,
+            address: POOL.to_owned(),
+            sender: POOL.to_owned(),
+            amount0_in: BigDecimal::from(0),
+            amount0_out: BigDecimal::from(0),
+            amount1_in: BigDecimal::from(0),
+            amount1_out: BigDecimal::from(0),
+            token0_usd_price: price * 2000.0,
+            token1_usd_price: 2000.0,
+            transaction_hash: None,
+            origin: None,
+            log_index: None,
+            base_address: TOKEN.to_owned(),
+            side: if bought { BUY } else { "sell" }.to_owned(),
+            base_amount: BigDecimal::from(base_amount),
+            quote_amount: BigDecimal::from(base_amount * price),
+            execution_price: price,
+            notional_usd: base_amount * price * 2000.0,
+            price_impact: 0.0,
+        }
+    }
+
+    fn token_candlesticks(tokens: &Tokens, len: u64) -> Vec<Candlestick> {
+        let address: Address = TOKEN.parse().unwrap();
+        let (_, candlesticks) = tokens.to_vec().into_iter().find(|x| x.0 == len).unwrap();
+
+        candlesticks
+            .into_iter()
+            .filter(|x| x.token_address == address)
+            .collect()
+    }
+
+    fn holder_stats(open_block_number: u64, close_block_number: u64, count: u64) -> HolderStats {
+        HolderStats {
+            open_block_number,
+            close_block_number,
+            token_symbol: "TKA".to_owned(),
+            token_address: TOKEN.to_owned(),
+            holders_count: count,
+            top_holders_share: 0.5,
+            transfers_count: 0,
+        }
+    }
+
+    #[test]
+    fn holders_come_from_stats_closed_by_the_candle_close() {
+        let mut tokens = tokens(&[300], 0);
+        tokens.handle_swap(swap(1000, 0.001, 100.0, true));
+        tokens.build_candlesticks();
+
+        // The day's stats close after the candle, the ones before it closed in time.
+        tokens.fill_holders(vec![holder_stats(0, 899, 10), holder_stats(900, 8099, 20)]);
+
+        let candlesticks = token_candlesticks(&tokens, 300);
+        assert_eq!(candlesticks.len(), 1);
+        assert_eq!(candlesticks[0].holders_count, 10);
+    }
+
+    #[test]
+    fn only_gaps_up_to_max_gap_are_filled() {
+        let mut tokens = tokens(&[300], 600);
+        tokens.handle_swap(swap(1000, 0.001, 100.0, true));
+        // Two intervals without swaps after the first candle, then five.
+        tokens.handle_swap(swap(1900, 0.002, 100.0, true));
+        tokens.handle_swap(swap(3700, 0.003, 100.0, false));
+        tokens.build_candlesticks();
+
+        let candlesticks = token_candlesticks(&tokens, 300);
+        let opens: Vec<_> = candlesticks.iter().map(|x| x.open_block_number).collect();
+        assert_eq!(opens, vec![900, 1200, 1500, 1800, 3600]);
+
+        for empty in &candlesticks[1..3] {
+            assert_eq!(empty.volume, 0.0);
+            assert_eq!(empty.close_price, candlesticks[0].close_price);
+        }
+
+        // Targets are valid up to the unfilled gap, not across it.
+        let valid: Vec<_> = candlesticks.iter().map(|x| x.target_valid).collect();
+        assert_eq!(valid, vec![true, true, true, false, false]);
+        assert_eq!(candlesticks[2].target_price, candlesticks[3].close_price);
+        assert_eq!(candlesticks[3].target_price, 0.0);
+    }
+
+    #[test]
+    fn rolled_up_candles_match_the_ones_built_directly() {
+        let mut rolled_up = tokens(&[300, 1800], 0);
+        let mut direct = tokens(&[1800], 0);
+        for i in 0..40 {
+            let price = 0.001 * (1.0 + ((i * 7) % 11) as f64 / 10.0);
+            let base_amount = 10.0 + (i % 5) as f64 * 3.0;
+            rolled_up.handle_swap(swap(100 + i * 90, price, base_amount, i % 3 != 0));
+            direct.handle_swap(swap(100 + i * 90, price, base_amount, i % 3 != 0));
+        }
+        rolled_up.build_candlesticks();
+        direct.build_candlesticks();
+
+        let rolled_up = token_candlesticks(&rolled_up, 1800);
+        let direct = token_candlesticks(&direct, 1800);
+        assert_eq!(rolled_up.len(), 3);
+        assert_eq!(rolled_up.len(), direct.len());
+
+        for (x, y) in rolled_up.iter().zip(&direct) {
+            assert_eq!(x.open_block_number, y.open_block_number);
+            assert_eq!(x.close_block_number, y.close_block_number);
+            assert_eq!(
+                (x.open_price, x.high_price, x.low_price, x.close_price),
+                (y.open_price, y.high_price, y.low_price, y.close_price)
+            );
+            assert_eq!((x.buys_count, x.sells_count), (y.buys_count, y.sells_count));
+            // Sums are taken in a different order.
+            assert!((x.volume - y.volume).abs() < 1e-9);
+            assert!((x.buys_usd - y.buys_usd).abs() < 1e-9);
+            assert!((x.sells_usd - y.sells_usd).abs() < 1e-9);
+        }
+    }
+}
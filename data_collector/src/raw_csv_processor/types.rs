@@ -1,18 +1,141 @@
 use serde::Serialize;
 use web3::types::Address;
 
+// The swaps of one block in one pool.
+#[derive(Clone, Default)]
+pub struct PoolTick {
+    /// Positions of the first and last swap among all the pools' swaps
+    pub first_swap: u64,
+    pub last_swap: u64,
+    /// Quote per base the traders paid
+    pub execution: Ohlc,
+    /// Of log returns of the execution price from the pool's previous swap
+    pub squared_returns: f64,
+    pub base: Trades,
+    pub quote: Trades,
+    /// Oracle prices as of the last swap
+    pub base_usd_price: f64,
+    pub quote_usd_price: f64,
+    pub volume: f64,
+    pub buys_count: u64,
+    pub sells_count: u64,
+    pub buys_usd: f64,
+    pub sells_usd: f64,
+}
+
+// Trades of one side of a pool, at the execution price converted to USD.
+#[derive(Clone, Default)]
+pub struct Trades {
+    pub usd: Ohlc,
+    pub amount: f64,
+    pub value_usd: f64,
+    /// Of log returns of the USD price from the pool's previous swap
+    pub squared_returns: f64,
+}
+
+impl Trades {
+    pub fn new(price: f64) -> Self {
+        Trades {
+            usd: Ohlc::new(price),
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, price: f64, amount: f64, log_return: f64) {
+        self.usd.push(price);
+        self.amount += amount;
+        self.value_usd += price * amount;
+        self.squared_returns += log_return * log_return;
+    }
+
+    // Extends the trades with later ones.
+    pub fn merge(&mut self, other: &Trades) {
+        self.usd.merge(&other.usd);
+        self.amount += other.amount;
+        self.value_usd += other.value_usd;
+        self.squared_returns += other.squared_returns;
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Ohlc {
+    pub open: f64,
+    pub close: f64,
+    pub high: f64,
+    pub low: f64,
+}
+
+impl Ohlc {
+    pub fn new(price: f64) -> Self {
+        Ohlc {
+            open: price,
+            close: price,
+            high: price,
+            low: price,
+        }
+    }
+
+    pub fn push(&mut self, price: f64) {
+        self.close = price;
+        self.high = f64::max(self.high, price);
+        self.low = f64::min(self.low, price);
+    }
+
+    // Extends the candle with a later one.
+    pub fn merge(&mut self, other: &Ohlc) {
+        self.close = other.close;
+        self.high = f64::max(self.high, other.high);
+        self.low = f64::min(self.low, other.low);
+    }
+}
+
 #[derive(Serialize, Clone, Default)]
-pub struct TokenTick {
-    pub block_number: u64,
-    pub token_symbol: String,
-    pub token_address: Address,
-    pub price: f64,
-    pub next_candle_close_price: f64,
+pub struct PoolCandlestick {
+    pub open_block_number: u64,
+    pub close_block_number: u64,
+    pub pool_address: Address,
+    pub base_symbol: String,
+    pub base_address: Address,
+    pub quote_symbol: String,
+    pub quote_address: Address,
+
+    /// Quote per base from the pool's reserves, the execution price where they aren't known
+    pub open_price: f64,
+    pub close_price: f64,
+    pub high_price: f64,
+    pub low_price: f64,
+    /// Of the swaps' execution prices, quote per base
+    pub vwap: f64,
+    pub trade_high_price: f64,
+    pub trade_low_price: f64,
+    /// In USD
     pub volume: f64,
+    pub base_volume: f64,
+    pub quote_volume: f64,
+    /// Of the base token: a buy is a swap the trader received it in
     pub buys_count: u64,
     pub sells_count: u64,
     pub buys_usd: f64,
     pub sells_usd: f64,
+    pub buy_volume_share: f64,
+    /// Square root of the summed squared log returns of the execution price, each swap's return
+    /// taken from the pool's previous swap
+    pub realized_volatility: f64,
+    /// After the candle's last swap, 0 when the pool had no sync by then
+    pub reserve_base: f64,
+    pub reserve_quote: f64,
+    pub liquidity_usd: f64,
+
+    /// Positions of the first and last swap among all the pools' swaps, token candles take their
+    /// open and close from the pools by them
+    #[serde(skip)]
+    pub first_swap: u64,
+    #[serde(skip)]
+    pub last_swap: u64,
+    #[serde(skip)]
+    pub base: Trades,
+    #[serde(skip)]
+    pub quote: Trades,
 }
 
 #[derive(Serialize, Clone, Default)]
@@ -22,16 +145,30 @@ pub struct Candlestick {
     pub token_symbol: String,
     pub token_address: Address,
 
+    /// USD prices of the first, last, highest and lowest trade, at the swaps' execution prices
     pub open_price: f64,
     pub close_price: f64,
     pub high_price: f64,
     pub low_price: f64,
+    /// Volume-weighted, in USD
+    pub vwap: f64,
+    /// Close of the next candle, 0 when that isn't the adjacent interval
     pub target_price: f64,
+    pub target_valid: bool,
     pub volume: f64,
     pub buys_count: u64,
     pub sells_count: u64,
     pub buys_usd: f64,
     pub sells_usd: f64,
+    /// Share of the USD volume bought
+    pub buy_volume_share: f64,
+    /// Square root of the squared log returns of the USD trade price summed over all the token's
+    /// pools, each trade's return taken from the previous trade in its own pool. Price gaps between
+    /// pools never count as returns, this isn't the volatility of the merged trade sequence
+    pub realized_volatility: f64,
+    /// Traded amount of the token, weighs the VWAP when candles are rolled up
+    #[serde(skip)]
+    pub amount: f64,
 
     pub open_price_6h: f64,
     pub volume_6h: f64,
@@ -71,4 +208,17 @@ pub struct Candlestick {
     pub buys_usd_week: f64,
     pub sells_usd_week: f64,
     pub volume_week: f64,
+
+    pub holders_count: u64,
+    pub top_holders_share: f64,
+
+    /// Values of `FeatureConfig::columns`, written after the other columns
+    #[serde(skip)]
+    pub features: Vec<Option<f64>>,
+    /// Values of `context::columns`, written after the features
+    #[serde(skip)]
+    pub context: Vec<Option<f64>>,
+    /// Values of `LabelConfig::columns`, written after the context
+    #[serde(skip)]
+    pub labels: Vec<Option<f64>>,
 }
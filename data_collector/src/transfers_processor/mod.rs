@@ -0,0 +1,208 @@
+use crate::db::db::{load_logs, logs_block_range};
+use crate::db::models::{BalanceTick, HolderStats, Token, TransferEvent};
+use crate::logs_processor::{build_token_registry, normalize, read_cex_data_db, u256_to_f64};
+use crate::{utils, TransfersProcessorArgs};
+use diesel::PgConnection;
+use std::collections::{HashMap, HashSet};
+use web3::types::{Address, U256};
+
+const TRANSFER_LOG_TYPE: i32 = 5;
+const BLOCKS_PER_PAGE: i64 = 10000;
+
+struct TokenLedger {
+    token: Token,
+    balances: HashMap<Address, U256>,
+    interval_start: u64,
+    transfers_count: u64,
+}
+
+impl TokenLedger {
+    fn new(token: Token) -> Self {
+        Self {
+            token,
+            balances: HashMap::new(),
+            interval_start: 0,
+            transfers_count: 0,
+        }
+    }
+
+    fn apply(&mut self, event: &TransferEvent) {
+        if event.from != Address::zero() {
+            let balance = self.balances.entry(event.from).or_default();
+            // Balances are only exact when transfers are collected from the token deployment.
+            *balance = balance.saturating_sub(event.value);
+            if balance.is_zero() {
+                self.balances.remove(&event.from);
+            }
+        }
+
+        if event.to != Address::zero() && !event.value.is_zero() {
+            let balance = self.balances.entry(event.to).or_default();
+            *balance = balance.saturating_add(event.value);
+        }
+
+        self.transfers_count += 1;
+    }
+
+    fn balance(&self, holder: &Address) -> U256 {
+        self.balances.get(holder).cloned().unwrap_or_default()
+    }
+
+    fn stats(&self, candlestick_len: u64, top_n: usize) -> HolderStats {
+        let mut balances: Vec<f64> = self.balances.values().map(|x| u256_to_f64(*x)).collect();
+        let total: f64 = balances.iter().sum();
+
+        let top = if balances.len() > top_n && top_n > 0 {
+            balances.select_nth_unstable_by(top_n - 1, |a, b| b.partial_cmp(a).unwrap());
+            balances[..top_n].iter().sum()
+        } else if top_n > 0 {
+            total
+        } else {
+            0.0
+        };
+
+        HolderStats {
+            open_block_number: self.interval_start,
+            close_block_number: self.interval_start + candlestick_len - 1,
+            token_symbol: self.token.symbol.clone(),
+            token_address: format!("{:?}", self.token.address),
+            holders_count: self.balances.len() as u64,
+            top_holders_share: if total > 0.0 { top / total } else { 0.0 },
+            transfers_count: self.transfers_count,
+        }
+    }
+}
+
+pub struct TransfersProcessor {
+    args: TransfersProcessorArgs,
+    watched: HashSet<Address>,
+}
+
+impl TransfersProcessor {
+    pub fn new(args: TransfersProcessorArgs) -> Self {
+        let watched = args
+            .watched
+            .iter()
+            .map(|x| x.parse().expect("Invalid watched address"))
+            .collect();
+
+        TransfersProcessor {
+            args,
+            watched,
+        }
+    }
+
+    pub async fn save(&self, conn: &PgConnection) {
+        let tokens = build_token_registry(&self.args.rpc, &read_cex_data_db(conn)).await;
+
+        println!("[CEX data handled]");
+
+        let (from_block, to_block) = match logs_block_range(conn, TRANSFER_LOG_TYPE) {
+            Some(r) => r,
+            None => {
+                println!("No transfers in the database");
+                return;
+            }
+        };
+
+        let mut ledgers: HashMap<Address, TokenLedger> = HashMap::new();
+        let mut balance_ticks = Vec::new();
+        let mut holder_stats = Vec::new();
+
+        let mut page_start = from_block;
+        while page_start <= to_block {
+            let page_end = i64::min(page_start + BLOCKS_PER_PAGE - 1, to_block);
+
+            let mut changed: HashMap<(Address, Address), u64> = HashMap::new();
+            let mut current_block = None;
+
+            for record in load_logs(conn, TRANSFER_LOG_TYPE, page_start, page_end) {
+                let event = TransferEvent::from_record(&record)
+                    .unwrap_or_else(|x| panic!("{}", record.malformed(&x)));
+
+                if current_block != Some(event.block_number) {
+                    self.flush_balances(&ledgers, &mut changed, &mut balance_ticks);
+                    current_block = Some(event.block_number);
+                }
+
+                let token = match tokens.get(&format!("{:?}", event.address)) {
+                    Some(r) => r,
+                    None => continue,
+                };
+
+                let ledger = ledgers
+                    .entry(event.address)
+                    .or_insert_with(|| TokenLedger::new(token.clone()));
+
+                let interval_start =
+                    event.block_number - (event.block_number % self.args.candlestick_len);
+                if interval_start != ledger.interval_start {
+                    if ledger.transfers_count > 0 {
+                        holder_stats.push(ledger.stats(self.args.candlestick_len, self.args.top_n));
+                    }
+
+                    ledger.interval_start = interval_start;
+                    ledger.transfers_count = 0;
+                }
+
+                ledger.apply(&event);
+
+                for holder in [event.from, event.to] {
+                    if self.watched.contains(&holder) {
+                        changed.insert((event.address, holder), event.block_number);
+                    }
+                }
+            }
+
+            self.flush_balances(&ledgers, &mut changed, &mut balance_ticks);
+
+            println!(
+                "{} / {}",
+                page_end - from_block + 1,
+                to_block - from_block + 1
+            );
+
+            page_start = page_end + 1;
+        }
+
+        for ledger in ledgers.values() {
+            if ledger.transfers_count > 0 {
+                holder_stats.push(ledger.stats(self.args.candlestick_len, self.args.top_n));
+            }
+        }
+
+        holder_stats.sort_by_key(|x| x.open_block_number);
+
+        println!("[Transfers handled]");
+
+        utils::write(
+            &format!("{}/balances.csv", self.args.output_dir),
+            balance_ticks,
+        );
+        utils::write(
+            &format!("{}/holders.csv", self.args.output_dir),
+            holder_stats,
+        );
+    }
+
+    fn flush_balances(
+        &self,
+        ledgers: &HashMap<Address, TokenLedger>,
+        changed: &mut HashMap<(Address, Address), u64>,
+        balance_ticks: &mut Vec<BalanceTick>,
+    ) {
+        let mut changed: Vec<_> = changed.drain().collect();
+        changed.sort();
+
+        for ((token_address, holder), block_number) in changed {
+            let ledger = &ledgers[&token_address];
+            balance_ticks.push(BalanceTick {
+                block_number: block_number as i64,
+                token_symbol: ledger.token.symbol.clone(),
+                token_address: format!("{:?}", token_address),
+                holder: format!("{:?}", holder),
+                balance: normalize(ledger.balance(&holder), ledger.token.decimals),
+            });
+        }
+    }
+}
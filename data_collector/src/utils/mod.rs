@@ -1,4 +1,4 @@
-use csv::Writer;
+use csv::{Writer, WriterBuilder};
 use serde::Serialize;
 use std::fs::File;
 
@@ -15,3 +15,33 @@ where
 
     wtr.flush().unwrap();
 }
+
+// Like `write`, with `columns` appended to every record. `T` must serialize as a flat struct.
+pub fn write_with_columns<T, F>(path: &str, records: Vec<T>, columns: &[String], values: F)
+where
+    T: Serialize + Default,
+    F: Fn(&T) -> Vec<Option<f64>>,
+{
+    // The header of `T` is the one csv writes for it, the extra columns can't go through serde.
+    let mut header_wtr = Writer::from_writer(vec![]);
+    header_wtr.serialize(T::default()).unwrap();
+    let data = header_wtr.into_inner().unwrap();
+    let mut header = csv::Reader::from_reader(data.as_slice())
+        .headers()
+        .unwrap()
+        .clone();
+    for column in columns {
+        header.push_field(column);
+    }
+
+    let file = File::create(path).unwrap();
+    let mut wtr = WriterBuilder::new().has_headers(false).from_writer(file);
+    wtr.write_record(&header).unwrap();
+
+    for record in records {
+        let values = values(&record);
+        wtr.serialize((record, values)).unwrap();
+    }
+
+    wtr.flush().unwrap();
+}